@@ -0,0 +1,207 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{hashes::Hash, BlockHash};
+use miningpool_observer_shared::config::{ZmqConfig, ZmqTopic};
+
+use crate::metrics;
+
+pub const LOG_TARGET_ZMQ: &str = "zmq";
+
+const WAIT_TIME_AFTER_ZMQ_ERROR: time::Duration = time::Duration::from_secs(5);
+
+// Labels used in the body of a `sequence` ZMQ message.
+const SEQUENCE_LABEL_BLOCK_CONNECTED: u8 = b'C';
+const SEQUENCE_LABEL_BLOCK_DISCONNECTED: u8 = b'D';
+
+/// A block event published by Bitcoin Core via ZMQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockNotification {
+    Connected(BlockHash),
+    Disconnected(BlockHash),
+}
+
+/// Parses the frames of a multipart ZMQ message published by Bitcoin Core.
+/// Returns None for messages that aren't block notifications, for example,
+/// mempool events on the `sequence` topic.
+pub fn parse_message(topic: ZmqTopic, frames: &[Vec<u8>]) -> Option<BlockNotification> {
+    let (message_topic, body) = match frames {
+        [message_topic, body, ..] => (message_topic, body),
+        _ => return None,
+    };
+
+    if message_topic.as_slice() != topic.as_str().as_bytes() || body.len() < 32 {
+        return None;
+    }
+
+    // Bitcoin Core publishes the block hash in the same (reversed) byte order
+    // as it's displayed in the RPC interface.
+    let mut hash_bytes = [0u8; 32];
+    hash_bytes.copy_from_slice(&body[..32]);
+    hash_bytes.reverse();
+    let hash = BlockHash::from_byte_array(hash_bytes);
+
+    match topic {
+        ZmqTopic::HashBlock => Some(BlockNotification::Connected(hash)),
+        ZmqTopic::Sequence => match body.get(32) {
+            Some(&SEQUENCE_LABEL_BLOCK_CONNECTED) => Some(BlockNotification::Connected(hash)),
+            Some(&SEQUENCE_LABEL_BLOCK_DISCONNECTED) => Some(BlockNotification::Disconnected(hash)),
+            _ => None,
+        },
+    }
+}
+
+/// Subscribes to the configured Bitcoin Core ZMQ publisher and forwards the
+/// block notifications to the sender. The ZMQ socket reconnects on its own
+/// if the publisher goes away.
+pub fn start_zmq_listener_thread(
+    config: ZmqConfig,
+    sender: mpsc::Sender<BlockNotification>,
+) -> Result<(), zmq::Error> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::SUB)?;
+    socket.connect(&config.address)?;
+    socket.set_subscribe(config.topic.as_str().as_bytes())?;
+
+    log::info!(
+        target: LOG_TARGET_ZMQ,
+        "Subscribed to the '{}' ZMQ topic on {}.",
+        config.topic.as_str(),
+        config.address
+    );
+
+    thread::spawn(move || loop {
+        match socket.recv_multipart(0) {
+            Ok(frames) => {
+                if let Some(notification) = parse_message(config.topic, &frames) {
+                    log::debug!(
+                        target: LOG_TARGET_ZMQ,
+                        "Received a block notification: {:?}",
+                        notification
+                    );
+                    metrics::RUNTIME_ZMQ_BLOCK_NOTIFICATIONS.inc();
+                    if sender.send(notification).is_err() {
+                        log::warn!(
+                            target: LOG_TARGET_ZMQ,
+                            "The receiver of the block notifications is gone. Stopping the ZMQ listener.",
+                        );
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET_ZMQ,
+                    "Could not receive a message from the ZMQ publisher on {}. Retrying in {:?}. Error: {}",
+                    config.address,
+                    WAIT_TIME_AFTER_ZMQ_ERROR,
+                    e
+                );
+                metrics::ERROR_ZMQ.inc();
+                thread::sleep(WAIT_TIME_AFTER_ZMQ_ERROR);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const BLOCK_HASH: &str = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+
+    fn rpc_order_bytes(hash: &BlockHash) -> Vec<u8> {
+        let mut bytes = hash.to_byte_array().to_vec();
+        bytes.reverse();
+        bytes
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let hash = BlockHash::from_str(BLOCK_HASH).unwrap();
+
+        let hashblock = vec![
+            b"hashblock".to_vec(),
+            rpc_order_bytes(&hash),
+            1u32.to_le_bytes().to_vec(),
+        ];
+        assert_eq!(
+            parse_message(ZmqTopic::HashBlock, &hashblock),
+            Some(BlockNotification::Connected(hash))
+        );
+        // a hashblock message on a sequence subscription is ignored
+        assert_eq!(parse_message(ZmqTopic::Sequence, &hashblock), None);
+
+        let mut disconnected_body = rpc_order_bytes(&hash);
+        disconnected_body.push(SEQUENCE_LABEL_BLOCK_DISCONNECTED);
+        let disconnected = vec![
+            b"sequence".to_vec(),
+            disconnected_body,
+            2u32.to_le_bytes().to_vec(),
+        ];
+        assert_eq!(
+            parse_message(ZmqTopic::Sequence, &disconnected),
+            Some(BlockNotification::Disconnected(hash))
+        );
+
+        // mempool events on the sequence topic are ignored
+        let mut mempool_body = rpc_order_bytes(&hash);
+        mempool_body.push(b'A');
+        mempool_body.extend_from_slice(&7u64.to_le_bytes());
+        let mempool = vec![
+            b"sequence".to_vec(),
+            mempool_body,
+            3u32.to_le_bytes().to_vec(),
+        ];
+        assert_eq!(parse_message(ZmqTopic::Sequence, &mempool), None);
+
+        assert_eq!(parse_message(ZmqTopic::HashBlock, &[]), None);
+    }
+
+    #[test]
+    fn test_zmq_listener_with_local_publisher() {
+        // A local ZMQ publisher standing in for Bitcoin Core.
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let address = publisher.get_last_endpoint().unwrap().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        start_zmq_listener_thread(
+            ZmqConfig {
+                address,
+                topic: ZmqTopic::Sequence,
+            },
+            sender,
+        )
+        .unwrap();
+
+        let hash = BlockHash::from_str(BLOCK_HASH).unwrap();
+        let mut body = rpc_order_bytes(&hash);
+        body.push(SEQUENCE_LABEL_BLOCK_CONNECTED);
+
+        // The subscriber might not be connected yet when we publish the first
+        // messages. Keep publishing until the notification arrives.
+        for sequence in 0u32..50 {
+            publisher
+                .send_multipart(
+                    vec![
+                        b"sequence".to_vec(),
+                        body.clone(),
+                        sequence.to_le_bytes().to_vec(),
+                    ],
+                    0,
+                )
+                .unwrap();
+            if let Ok(notification) = receiver.recv_timeout(time::Duration::from_millis(100)) {
+                assert_eq!(notification, BlockNotification::Connected(hash));
+                return;
+            }
+        }
+        panic!("Did not receive a block notification from the local ZMQ publisher.");
+    }
+}
@@ -0,0 +1,413 @@
+use std::fmt;
+
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{Block, BlockHash};
+use miningpool_observer_shared::bitcoincore_rpc::json::{
+    GetBlockTemplateResult, GetBlockTxFeesResult,
+};
+use miningpool_observer_shared::bitcoincore_rpc::Error;
+
+use crate::metrics;
+use crate::node::Node;
+use crate::processing;
+
+/// Reorgs deeper than this are not followed back to the fork point.
+const MAX_REORG_DEPTH: u64 = 100;
+
+/// How the chain tip changed between the previous and the current template.
+pub enum ChainTipChange {
+    /// Both templates build on the same block. No block has been mined.
+    Unchanged,
+    /// A new block building on the previous template has been mined.
+    NewBlock {
+        block: Block,
+        block_tx_fees: GetBlockTxFeesResult,
+    },
+    /// Multiple blocks were mined in rapid succession. The block is the one
+    /// building on the previous template. The current template builds on a
+    /// later block. The skipped blocks between them can't be compared to a
+    /// template.
+    MissedBlock {
+        block: Block,
+        block_tx_fees: GetBlockTxFeesResult,
+        skipped_blocks: Vec<BlockHash>,
+    },
+    /// The block the previous template builds on isn't part of the active
+    /// chain anymore. The previous template can't be compared to a block.
+    Reorg(Reorg),
+}
+
+/// A chain reorganization found by walking both chains back to the block
+/// they have in common.
+#[derive(Debug, PartialEq)]
+pub struct Reorg {
+    /// The last block both chains have in common.
+    pub fork_point: BlockHash,
+    /// The blocks that dropped out of the active chain, lowest height first.
+    pub stale_blocks: Vec<BlockHash>,
+    /// The blocks of the new active chain above the fork point, lowest
+    /// height first.
+    pub connected_blocks: Vec<BlockHash>,
+}
+
+impl Reorg {
+    /// The number of blocks that dropped out of the active chain.
+    pub fn depth(&self) -> usize {
+        self.stale_blocks.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum ChainTipError {
+    Block(BlockHash, Error),
+    BlockHeader(BlockHash, Error),
+    BlockTxFees(BlockHash, Error),
+    ReorgTooDeep(BlockHash, BlockHash),
+}
+
+impl fmt::Display for ChainTipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainTipError::Block(hash, e) => write!(
+                f,
+                "Could not get the block with the hash {} from the Bitcoin Core RPC server: {}",
+                hash, e
+            ),
+            ChainTipError::BlockHeader(hash, e) => write!(
+                f,
+                "Could not get the block header with the hash {} from the Bitcoin Core RPC server: {}",
+                hash, e
+            ),
+            ChainTipError::BlockTxFees(hash, e) => write!(
+                f,
+                "Could not get the txids and fees for block with the hash {} from the Bitcoin Core RPC server: {}",
+                hash, e
+            ),
+            ChainTipError::ReorgTooDeep(old_tip, new_tip) => write!(
+                f,
+                "Could not find a common block of the chains with the tips {} and {} in the last {} blocks",
+                old_tip, new_tip, MAX_REORG_DEPTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChainTipError {}
+
+fn get_block(node: &impl Node, hash: &BlockHash) -> Result<Block, ChainTipError> {
+    let block = node
+        .get_block(hash)
+        .map_err(|e| ChainTipError::Block(*hash, e))?;
+    metrics::RUNTIME_REQUESTED_BLOCKS.inc();
+    Ok(block)
+}
+
+fn get_prev_blockhash(node: &impl Node, hash: &BlockHash) -> Result<BlockHash, ChainTipError> {
+    node.get_block_header(hash)
+        .map(|header| header.prev_blockhash)
+        .map_err(|e| ChainTipError::BlockHeader(*hash, e))
+}
+
+// TODO: Once Bitcoin Core v22 with getblock verbosity level 2 is released
+// and rust-bitcoincore-rpc has can get the block and fees in on RPC call,
+// then the getblock call and this can be merged.
+fn get_block_txid_fee(
+    node: &impl Node,
+    hash: &BlockHash,
+) -> Result<GetBlockTxFeesResult, ChainTipError> {
+    node.get_block_txid_fee(hash)
+        .map_err(|e| ChainTipError::BlockTxFees(*hash, e))
+}
+
+/// Requests the block and the txids and fees of its transactions.
+pub fn get_block_with_fees(
+    node: &impl Node,
+    hash: &BlockHash,
+) -> Result<(Block, GetBlockTxFeesResult), ChainTipError> {
+    Ok((get_block(node, hash)?, get_block_txid_fee(node, hash)?))
+}
+
+/// Walks the chains ending in old_tip and new_tip back via the previous
+/// block hashes until both reach the same block.
+fn find_fork(
+    node: &impl Node,
+    (old_tip, old_height): (BlockHash, u64),
+    (new_tip, new_height): (BlockHash, u64),
+) -> Result<Reorg, ChainTipError> {
+    let (mut old, mut new) = ((old_tip, old_height), (new_tip, new_height));
+    let mut stale_blocks: Vec<BlockHash> = vec![];
+    let mut connected_blocks: Vec<BlockHash> = vec![];
+    while old.0 != new.0 {
+        if stale_blocks.len() as u64 >= MAX_REORG_DEPTH
+            || connected_blocks.len() as u64 >= MAX_REORG_DEPTH
+        {
+            return Err(ChainTipError::ReorgTooDeep(old_tip, new_tip));
+        }
+        if old.1 >= new.1 {
+            stale_blocks.push(old.0);
+            old = (get_prev_blockhash(node, &old.0)?, old.1 - 1);
+        }
+        if new.1 > old.1 {
+            connected_blocks.push(new.0);
+            new = (get_prev_blockhash(node, &new.0)?, new.1 - 1);
+        }
+    }
+    stale_blocks.reverse();
+    connected_blocks.reverse();
+    Ok(Reorg {
+        fork_point: old.0,
+        stale_blocks,
+        connected_blocks,
+    })
+}
+
+/// Detects if and how the chain tip changed between the previous_template
+/// and the current_template and requests the block building on the
+/// previous_template from the node.
+pub fn detect_chain_tip_change(
+    node: &impl Node,
+    previous_template: &GetBlockTemplateResult,
+    current_template: &GetBlockTemplateResult,
+) -> Result<ChainTipChange, ChainTipError> {
+    // To detect if a block has been mined, we check the if the previous block hashes
+    // of the previous_template and the current_template are different. If they are
+    // the same, no block has been mined.
+    if previous_template.previous_block_hash == current_template.previous_block_hash {
+        return Ok(ChainTipChange::Unchanged);
+    }
+
+    // The previous block hash of the previous_template and the current_template are
+    // different. This means the chain tip changed. We request the block with the
+    // current_templates previous block hash. In most cases, this should be the block
+    // that was just mined.
+    let block = get_block(node, &current_template.previous_block_hash)?;
+
+    // When something changed, we can't be sure if the templates in last_templates
+    // are actually templates for the block we just requested. For example, two block
+    // might have arrived at our node in rapid succession and we didn't query for a
+    // template in between.
+    // To check that we can compare the block with one of our last_templates, we
+    // compare the previous block hashes of block and the previous_template.
+    // Only if the hashes are equal, we can compare the template and block.
+    if block.header.prev_blockhash == previous_template.previous_block_hash {
+        let block_tx_fees = get_block_txid_fee(node, &current_template.previous_block_hash)?;
+        return Ok(ChainTipChange::NewBlock {
+            block,
+            block_tx_fees,
+        });
+    }
+
+    // Otherwise, we either skipped one or more blocks or the block the
+    // previous_template builds on was reorged out. To find out, both chains
+    // are walked back until they reach a common block.
+    let reorg = find_fork(
+        node,
+        (
+            previous_template.previous_block_hash,
+            previous_template.height - 1,
+        ),
+        (
+            current_template.previous_block_hash,
+            current_template.height - 1,
+        ),
+    )?;
+
+    // If no block dropped out of the active chain, the chain was extended by
+    // multiple blocks. We can however still compare the previous_template to
+    // it's respective block.
+    if reorg.stale_blocks.is_empty() {
+        log::warn!(
+            target: processing::LOG_TARGET_PROCESSING,
+            "Missed {} blocks found in rapid succession. Only the first can be compared to the previous_template.",
+            reorg.connected_blocks.len() - 1
+        );
+        metrics::RUNTIME_SKIPPED_BLOCK_EVENTS.inc();
+        let (block, block_tx_fees) = get_block_with_fees(node, &reorg.connected_blocks[0])?;
+        return Ok(ChainTipChange::MissedBlock {
+            block,
+            block_tx_fees,
+            skipped_blocks: reorg.connected_blocks[1..].to_vec(),
+        });
+    }
+
+    log::warn!(
+        target: processing::LOG_TARGET_PROCESSING,
+        "Reorg of depth {}: {} blocks were replaced by {} blocks building on {}.",
+        reorg.depth(),
+        reorg.stale_blocks.len(),
+        reorg.connected_blocks.len(),
+        reorg.fork_point
+    );
+    metrics::RUNTIME_REORGS.inc();
+    Ok(ChainTipChange::Reorg(reorg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::mock::{test_block, MockNode};
+    use crate::template_history::tests::{template, txid};
+    use miningpool_observer_shared::bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    const HEIGHT: u64 = 900000;
+
+    fn template_on(block: &Block, current_time: u64) -> GetBlockTemplateResult {
+        let mut t = template(
+            &block.block_hash().to_string(),
+            current_time,
+            &[txid(1), txid(2)],
+        );
+        t.height = HEIGHT;
+        t
+    }
+
+    fn next_template_on(block: &Block, current_time: u64) -> GetBlockTemplateResult {
+        let mut t = template_on(block, current_time);
+        t.height += 1;
+        t
+    }
+
+    #[test]
+    fn test_detect_chain_tip_change() {
+        let mut node = MockNode::default();
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        node.connect_block(HEIGHT - 1, a.clone());
+
+        let previous_template = template_on(&a, 1700000010);
+        let current_template = template_on(&a, 1700000020);
+        assert!(matches!(
+            detect_chain_tip_change(&node, &previous_template, &current_template),
+            Ok(ChainTipChange::Unchanged)
+        ));
+
+        // The block b builds on the previous template.
+        let b = test_block(a.block_hash(), 1700000030);
+        node.connect_block(HEIGHT, b.clone());
+        let current_template = next_template_on(&b, 1700000040);
+        match detect_chain_tip_change(&node, &previous_template, &current_template) {
+            Ok(ChainTipChange::NewBlock {
+                block,
+                block_tx_fees,
+            }) => {
+                assert_eq!(block, b);
+                assert_eq!(block_tx_fees.hash, b.block_hash());
+            }
+            _ => panic!("expected a new block"),
+        }
+    }
+
+    #[test]
+    fn test_detect_chain_tip_change_missed_block() {
+        let mut node = MockNode::default();
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        node.connect_block(HEIGHT - 1, a.clone());
+        let previous_template = template_on(&a, 1700000010);
+
+        // b and c were found in rapid succession. There is no template
+        // building on b.
+        let b = test_block(a.block_hash(), 1700000030);
+        let c = test_block(b.block_hash(), 1700000031);
+        node.connect_block(HEIGHT, b.clone());
+        node.connect_block(HEIGHT + 1, c.clone());
+
+        let mut current_template = template_on(&c, 1700000040);
+        current_template.height = HEIGHT + 2;
+        match detect_chain_tip_change(&node, &previous_template, &current_template) {
+            Ok(ChainTipChange::MissedBlock {
+                block,
+                skipped_blocks,
+                ..
+            }) => {
+                assert_eq!(block, b);
+                assert_eq!(skipped_blocks, vec![c.block_hash()]);
+            }
+            _ => panic!("expected a missed block"),
+        }
+    }
+
+    #[test]
+    fn test_detect_chain_tip_change_reorg() {
+        let mut node = MockNode::default();
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        node.connect_block(HEIGHT - 1, a.clone());
+        let previous_template = template_on(&a, 1700000010);
+
+        // a is replaced by the competing block a2 at the same height.
+        let a2 = test_block(a.header.prev_blockhash, 1700000001);
+        node.connect_block(HEIGHT - 1, a2.clone());
+        let current_template = template_on(&a2, 1700000020);
+        match detect_chain_tip_change(&node, &previous_template, &current_template) {
+            Ok(ChainTipChange::Reorg(reorg)) => assert_eq!(
+                reorg,
+                Reorg {
+                    fork_point: BlockHash::all_zeros(),
+                    stale_blocks: vec![a.block_hash()],
+                    connected_blocks: vec![a2.block_hash()],
+                }
+            ),
+            _ => panic!("expected a reorg"),
+        }
+
+        // a2 is extended by b2 before a new template is requested.
+        let b2 = test_block(a2.block_hash(), 1700000030);
+        node.connect_block(HEIGHT, b2.clone());
+        let current_template = next_template_on(&b2, 1700000040);
+        match detect_chain_tip_change(&node, &previous_template, &current_template) {
+            Ok(ChainTipChange::Reorg(reorg)) => {
+                assert_eq!(reorg.depth(), 1);
+                assert_eq!(reorg.fork_point, BlockHash::all_zeros());
+                assert_eq!(
+                    reorg.connected_blocks,
+                    vec![a2.block_hash(), b2.block_hash()]
+                );
+            }
+            _ => panic!("expected a reorg"),
+        }
+    }
+
+    #[test]
+    fn test_detect_chain_tip_change_deep_reorg() {
+        let mut node = MockNode::default();
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        let b = test_block(a.block_hash(), 1700000010);
+        let c = test_block(b.block_hash(), 1700000020);
+        node.connect_block(HEIGHT - 3, a.clone());
+        node.connect_block(HEIGHT - 2, b.clone());
+        node.connect_block(HEIGHT - 1, c.clone());
+        let previous_template = template_on(&c, 1700000030);
+
+        // b and c are replaced by b2, c2 and d2.
+        let b2 = test_block(a.block_hash(), 1700000011);
+        let c2 = test_block(b2.block_hash(), 1700000021);
+        let d2 = test_block(c2.block_hash(), 1700000031);
+        node.connect_block(HEIGHT - 2, b2.clone());
+        node.connect_block(HEIGHT - 1, c2.clone());
+        node.connect_block(HEIGHT, d2.clone());
+        let current_template = next_template_on(&d2, 1700000040);
+        match detect_chain_tip_change(&node, &previous_template, &current_template) {
+            Ok(ChainTipChange::Reorg(reorg)) => assert_eq!(
+                reorg,
+                Reorg {
+                    fork_point: a.block_hash(),
+                    stale_blocks: vec![b.block_hash(), c.block_hash()],
+                    connected_blocks: vec![b2.block_hash(), c2.block_hash(), d2.block_hash()],
+                }
+            ),
+            _ => panic!("expected a reorg"),
+        }
+    }
+
+    #[test]
+    fn test_detect_chain_tip_change_unknown_block() {
+        let mut node = MockNode::default();
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        node.connect_block(HEIGHT - 1, a.clone());
+        let previous_template = template_on(&a, 1700000010);
+
+        let b = test_block(a.block_hash(), 1700000030);
+        let current_template = next_template_on(&b, 1700000040);
+        assert!(matches!(
+            detect_chain_tip_change(&node, &previous_template, &current_template),
+            Err(ChainTipError::Block(hash, _)) if hash == b.block_hash()
+        ));
+    }
+}
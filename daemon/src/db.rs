@@ -2,14 +2,17 @@ use std::collections::HashSet;
 use std::error::Error;
 use std::iter::FromIterator;
 
+use miningpool_observer_shared::chrono::NaiveDateTime;
 use miningpool_observer_shared::model::{
-    Block, ConflictingTransaction, DebugTemplateSelectionInfo, NewBlock, SanctionedAddress,
-    SanctionedTransactionInfo, SanctionedUtxo, SanctionedUtxoScanInfo, Transaction,
-    TransactionOnlyInBlock, TransactionOnlyInTemplate,
+    ArchivedTemplate, Block, CoinbaseInfo, ConflictingTransaction, DebugTemplateSelectionInfo,
+    NewBlock, SanctionedAddress, SanctionedTransactionInfo, SanctionedUtxo, SanctionedUtxoScanInfo,
+    Transaction, TransactionOnlyInBlock, TransactionOnlyInTemplate, UncomparedBlock,
 };
 use miningpool_observer_shared::schema;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+use crate::model::ProcessedBlock;
 use miningpool_observer_shared::diesel;
 use miningpool_observer_shared::diesel::pg::PgConnection;
 use miningpool_observer_shared::diesel::prelude::*;
@@ -33,12 +36,12 @@ pub fn insert_block(b: &NewBlock, conn: &mut PgConnection) -> Result<i64, diesel
 }
 
 pub fn insert_transactions(
-    txns: Vec<Transaction>,
+    txns: &[Transaction],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::transaction::dsl::*;
     let inserted_txids = diesel::insert_into(transaction)
-        .values(&txns)
+        .values(txns)
         .on_conflict_do_nothing()
         .returning(txid)
         .get_results::<Vec<u8>>(conn)?;
@@ -80,7 +83,7 @@ pub fn insert_transactions(
 }
 
 pub fn insert_transactions_only_in_block(
-    txns: Vec<TransactionOnlyInBlock>,
+    txns: &[TransactionOnlyInBlock],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::transaction_only_in_block::dsl::*;
@@ -91,7 +94,7 @@ pub fn insert_transactions_only_in_block(
 }
 
 pub fn insert_transactions_only_in_template(
-    txns: Vec<TransactionOnlyInTemplate>,
+    txns: &[TransactionOnlyInTemplate],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::transaction_only_in_template::dsl::*;
@@ -102,7 +105,7 @@ pub fn insert_transactions_only_in_template(
 }
 
 pub fn insert_sanctioned_transaction_infos(
-    sanctioned_infos: Vec<SanctionedTransactionInfo>,
+    sanctioned_infos: &[SanctionedTransactionInfo],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::sanctioned_transaction_info::dsl::*;
@@ -113,7 +116,7 @@ pub fn insert_sanctioned_transaction_infos(
 }
 
 pub fn insert_conflicting_transactions(
-    ctxns: Vec<ConflictingTransaction>,
+    ctxns: &[ConflictingTransaction],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::conflicting_transactions::dsl::*;
@@ -123,6 +126,139 @@ pub fn insert_conflicting_transactions(
     Ok(())
 }
 
+pub fn insert_coinbase_info(
+    info: &CoinbaseInfo,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use schema::coinbase_info::dsl::*;
+    diesel::insert_into(coinbase_info)
+        .values(info)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Inserts the processed block and all rows referencing it in a single
+/// database transaction. Either everything or nothing is written. Returns
+/// the id of the inserted block.
+pub fn insert_processed_block(
+    processed_block: &mut ProcessedBlock,
+    conn: &mut PgConnection,
+) -> Result<i64, diesel::result::Error> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let block_id = insert_block(&processed_block.block, conn)?;
+        processed_block.set_block_id(block_id);
+        insert_transactions(&processed_block.transactions, conn)?;
+        insert_transactions_only_in_block(&processed_block.transactions_only_in_block, conn)?;
+        insert_transactions_only_in_template(&processed_block.transactions_only_in_template, conn)?;
+        insert_sanctioned_transaction_infos(&processed_block.sanctioned_transaction_infos, conn)?;
+        insert_conflicting_transactions(&processed_block.conflicting_transactions, conn)?;
+        if !processed_block.newly_sanctioned_utxos.is_empty() {
+            log::info!(target: "sanctioned_utxos", "Inserting {} new sanctioned UTXOs into the database.", processed_block.newly_sanctioned_utxos.len());
+            insert_sanctioned_utxos(&processed_block.newly_sanctioned_utxos, conn)?;
+        }
+        insert_debug_template_selection_infos(
+            &processed_block.debug_template_selection_infos,
+            conn,
+        )?;
+        if let Some(info) = &processed_block.coinbase_info {
+            insert_coinbase_info(info, conn)?;
+        }
+        Ok(block_id)
+    })
+}
+
+/// Marks the blocks that dropped out of the active chain in a reorg as
+/// stale and the blocks that are (again) part of the active chain as not
+/// stale. The hashes are in the byte order they are displayed in. Returns
+/// the number of updated blocks.
+pub fn update_stale_blocks(
+    stale_hashes: &[Vec<u8>],
+    connected_hashes: &[Vec<u8>],
+    depth: i32,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        use schema::block::dsl::*;
+        let stale_count = diesel::update(block.filter(hash.eq_any(stale_hashes)))
+            .set((stale.eq(true), reorg_depth.eq(depth)))
+            .execute(conn)?;
+        let connected_count = diesel::update(block.filter(hash.eq_any(connected_hashes)))
+            .set((stale.eq(false), reorg_depth.eq(None::<i32>)))
+            .execute(conn)?;
+        Ok(stale_count + connected_count)
+    })
+}
+
+/// Inserts the blocks that couldn't be compared to a template. Blocks that
+/// are already present are ignored. Returns the number of inserted blocks.
+pub fn insert_uncompared_blocks(
+    blocks: &[UncomparedBlock],
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use schema::uncompared_block::dsl::*;
+    diesel::insert_into(uncompared_block)
+        .values(blocks)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+/// Returns true if the block with the hash is present in either the block
+/// or the uncompared_block table. The hash is in the byte order it is
+/// displayed in.
+pub fn is_block_observed(
+    block_hash: &[u8],
+    conn: &mut PgConnection,
+) -> Result<bool, diesel::result::Error> {
+    let in_block = diesel::select(diesel::dsl::exists(
+        schema::block::table.filter(schema::block::hash.eq(block_hash)),
+    ))
+    .get_result::<bool>(conn)?;
+    if in_block {
+        return Ok(true);
+    }
+    diesel::select(diesel::dsl::exists(
+        schema::uncompared_block::table.filter(schema::uncompared_block::hash.eq(block_hash)),
+    ))
+    .get_result(conn)
+}
+
+/// Returns the height of the highest block observed before. None if no
+/// block was observed before.
+pub fn max_observed_height(conn: &mut PgConnection) -> Result<Option<i32>, diesel::result::Error> {
+    let max_block_height = schema::block::table
+        .select(diesel::dsl::max(schema::block::height))
+        .get_result::<Option<i32>>(conn)?;
+    let max_uncompared_height = schema::uncompared_block::table
+        .select(diesel::dsl::max(schema::uncompared_block::height))
+        .get_result::<Option<i32>>(conn)?;
+    Ok(max_block_height.max(max_uncompared_height))
+}
+
+/// Records the start of a daemon run. Returns the id of the run.
+pub fn insert_daemon_run(
+    time: &NaiveDateTime,
+    conn: &mut PgConnection,
+) -> Result<i64, diesel::result::Error> {
+    use schema::daemon_run::dsl::*;
+    diesel::insert_into(daemon_run)
+        .values((start_time.eq(time), last_seen_time.eq(time)))
+        .returning(id)
+        .get_result(conn)
+}
+
+/// Updates the time the daemon run was last seen alive.
+pub fn update_daemon_run_last_seen_time(
+    run_id: i64,
+    time: &NaiveDateTime,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use schema::daemon_run::dsl::*;
+    diesel::update(daemon_run.filter(id.eq(run_id)))
+        .set(last_seen_time.eq(time))
+        .execute(conn)?;
+    Ok(())
+}
+
 /// Deletes all Sanctioned UTXOs and inserts the passed Sanctioned UTXOs.
 pub fn clean_and_insert_sanctioned_utxos(
     utxos: &[SanctionedUtxo],
@@ -172,7 +308,7 @@ pub fn get_sanctioned_utxos(
 }
 
 pub fn insert_debug_template_selection_infos(
-    infos: Vec<DebugTemplateSelectionInfo>,
+    infos: &[DebugTemplateSelectionInfo],
     conn: &mut PgConnection,
 ) -> Result<(), diesel::result::Error> {
     use schema::debug_template_selection::dsl::*;
@@ -183,6 +319,30 @@ pub fn insert_debug_template_selection_infos(
     Ok(())
 }
 
+/// Inserts the template into the template archive. Returns the number of
+/// inserted templates: 0 if a template with the same transactions was
+/// already archived for the same height and second.
+pub fn insert_archived_template(
+    archived_template: &ArchivedTemplate,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use schema::template_archive::dsl::*;
+    diesel::insert_into(template_archive)
+        .values(archived_template)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+/// Deletes the archived templates older than the passed time. Returns the
+/// number of deleted templates.
+pub fn delete_archived_templates_before(
+    time: &NaiveDateTime,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use schema::template_archive::dsl::*;
+    diesel::delete(template_archive.filter(template_time.lt(time))).execute(conn)
+}
+
 pub fn replace_sanctioned_addresses(
     addr: Vec<SanctionedAddress>,
     conn: &mut PgConnection,
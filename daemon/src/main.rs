@@ -1,10 +1,10 @@
 #![cfg_attr(feature = "strict", deny(warnings))]
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::fs::File;
 use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time;
 use std::time::Instant;
@@ -18,12 +18,11 @@ use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
     Amount, Block,
 };
 use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
-use miningpool_observer_shared::config::PoolIdentificationConfig;
+use miningpool_observer_shared::config::{Deployment, PoolIdentificationConfig};
 use simple_logger::SimpleLogger;
 
 use miningpool_observer_shared::bitcoincore_rpc::json::{
-    GetBlockTemplateModes, GetBlockTemplateResult, GetBlockTemplateRules, GetBlockTxFeesResult,
-    ScanTxOutRequest,
+    GetBlockTemplateResult, GetBlockTxFeesResult, ScanTxOutRequest,
 };
 use miningpool_observer_shared::bitcoincore_rpc::jsonrpc;
 use miningpool_observer_shared::bitcoincore_rpc::{Auth, Client, Error, RpcApi};
@@ -33,12 +32,29 @@ use miningpool_observer_shared::{
     config, db_pool, model as shared_model, prometheus_metric_server,
 };
 
+use crate::blocknotify::BlockNotification;
+use crate::chaintip::ChainTipChange;
 use crate::model::TxInfo;
-
+use crate::node::Node;
+use crate::retry_queue::RetryQueue;
+use crate::seen_txids::SeenTxids;
+use crate::template_history::TemplateHistory;
+use crate::template_store::TemplateStore;
+
+mod blocknotify;
+mod chaintip;
 mod db;
 mod metrics;
 mod model;
+mod node;
+mod policy;
 mod processing;
+mod replay;
+mod retry_queue;
+mod seen_txids;
+mod template_archive;
+mod template_history;
+mod template_store;
 
 const WAIT_TIME_BETWEEN_TEMPLATE_QUERIES: time::Duration = time::Duration::from_secs(10);
 const WAIT_TIME_BETWEEN_CONNPOOL_GETCONNECTION: time::Duration = time::Duration::from_secs(1);
@@ -49,7 +65,14 @@ const WAIT_TIME_BETWEEN_SANCTIONED_ADDRESS_UPDATES: time::Duration =
 const WAIT_TIME_BETWEEN_POOL_IDENTIFICATOIN_DATASET_UPDATES: time::Duration =
     time::Duration::from_secs(60 * 60 * 24); // 1 day
 const TIMEOUT_UTXO_SET_SCANS: time::Duration = time::Duration::from_secs(60 * 8); // 8 minutes
-const MAX_OLD_TEMPLATES: usize = 15;
+const TIMEOUT_TEMPLATE_LONGPOLL: time::Duration = time::Duration::from_secs(60 * 30); // 30 minutes
+const MAX_TEMPLATE_HISTORY_AGE: time::Duration = time::Duration::from_secs(60 * 60 * 3); // 3 hours
+const MAX_TEMPLATES_IN_HISTORY: usize = 2000;
+const WAIT_TIME_BETWEEN_HEARTBEATS: time::Duration = time::Duration::from_secs(60); // 1 minute
+const WAIT_TIME_BETWEEN_MEMPOOL_SNAPSHOTS: time::Duration = time::Duration::from_secs(30);
+const MAX_SEEN_TXID_AGE: time::Duration = time::Duration::from_secs(60 * 60 * 24); // 1 day
+const MAX_DOWNTIME_BLOCKS: usize = 1008; // about a week of blocks
+const BLOCK_EVENT_QUEUE_SIZE: usize = 16;
 const TIMEOUT_HTTP_GET_REQUEST: u64 = 10; // seconds
 
 const LOG_TARGET_RPC: &str = "rpc";
@@ -84,6 +107,18 @@ fn main() {
         Err(e) => panic!("Could not setup logger: {}", e),
     }
 
+    // In replay mode, recorded templates and blocks are processed instead of
+    // the ones from Bitcoin Core.
+    if let Some(replay_config) = config.replay {
+        replay::run(
+            replay_config,
+            &config.database_url,
+            config.pool_identification.network,
+            &config.deployments,
+        );
+        return;
+    }
+
     let rpc_client = match Client::new(&config.rpc_url.clone(), config.rpc_auth.clone()) {
         Ok(config) => config,
         Err(e) => panic!("Could not setup the Bitcoin Core RPC client: {}", e),
@@ -167,6 +202,29 @@ fn main() {
         config.pool_identification.clone().dataset_url,
     );
 
+    // A getblocktemplate long-poll only returns once Bitcoin Core has a new
+    // template. This needs a RPC client with a long timeout.
+    let longpoll_rpc_client = if config.template_longpolling {
+        let longpoll_transport = jsonrpc::simple_http::Builder::new()
+            .url(&config.rpc_url.clone())
+            .expect("invalid rpc url")
+            .auth(
+                user_pass.0.clone().expect("rpc user is empty"),
+                user_pass.1.clone(),
+            )
+            .timeout(TIMEOUT_TEMPLATE_LONGPOLL)
+            .build();
+        log::info!(
+            target: LOG_TARGET_STARTUP,
+            "Using getblocktemplate long-polling to request new templates."
+        );
+        Some(Client::from_jsonrpc(
+            jsonrpc::client::Client::with_transport(longpoll_transport),
+        ))
+    } else {
+        None
+    };
+
     // Build a custom transport here to be able to configure the timeout.
     let custom_timeout_transport = jsonrpc::simple_http::Builder::new()
         .url(&config.rpc_url.clone())
@@ -255,14 +313,190 @@ fn main() {
         retag_transactions(retag_rpc_client, retag_conn_pool);
     }
 
-    main_loop(
+    let template_store = match config.template_history_path.clone() {
+        Some(path) => match TemplateStore::new(path.clone()) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET_STARTUP,
+                    "Could not open the template history directory {}: {}",
+                    path.display(),
+                    e
+                );
+                panic!("During startup: Could not open the template history directory.");
+            }
+        },
+        None => None,
+    };
+
+    let template_archive = if config.template_archive.enable {
+        let template_archive_conn_pool = match db_pool::new(&config.database_url) {
+            Ok(pool) => pool,
+            Err(e) => panic!(
+                "During startup: Could not create a Postgres connection pool: {}",
+                e
+            ),
+        };
+        log::info!(
+            target: LOG_TARGET_STARTUP,
+            "Archiving new block templates in the database."
+        );
+        Some(template_archive::start_template_archive_thread(
+            config.template_archive.clone(),
+            template_archive_conn_pool,
+        ))
+    } else {
+        None
+    };
+
+    let retry_queue = match RetryQueue::new(config.retry_queue_path.clone()) {
+        Ok(queue) => queue,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_STARTUP,
+                "Could not load the retry queue: {}",
+                e
+            );
+            panic!("During startup: Could not load the retry queue.");
+        }
+    };
+    if !retry_queue.is_empty() {
+        log::info!(
+            target: LOG_TARGET_STARTUP,
+            "Loaded {} blocks from the retry queue.",
+            retry_queue.len()
+        );
+    }
+    let retry_queue_conn_pool = match db_pool::new(&config.database_url) {
+        Ok(pool) => pool,
+        Err(e) => panic!(
+            "During startup: Could not create a Postgres connection pool: {}",
+            e
+        ),
+    };
+    let retry_queue = retry_queue::start_retry_queue_thread(retry_queue, retry_queue_conn_pool);
+
+    // Block notifications via ZMQ. If ZMQ isn't configured, the sender is
+    // dropped here and the main loop falls back to polling only.
+    let (block_notification_sender, block_notifications) = mpsc::channel();
+    match config.zmq.clone() {
+        Some(zmq_config) => {
+            if let Err(e) =
+                blocknotify::start_zmq_listener_thread(zmq_config, block_notification_sender)
+            {
+                log::error!(
+                    target: LOG_TARGET_STARTUP,
+                    "Could not subscribe to the Bitcoin Core ZMQ publisher: {}",
+                    e
+                );
+                panic!("During startup: Could not subscribe to the Bitcoin Core ZMQ publisher.");
+            }
+        }
+        None => log::info!(
+            target: LOG_TARGET_STARTUP,
+            "No ZMQ publisher configured. Polling for new templates every {} seconds.",
+            WAIT_TIME_BETWEEN_TEMPLATE_QUERIES.as_secs()
+        ),
+    }
+
+    // The detected blocks are processed in a separate thread so that the
+    // main loop can keep requesting templates in the meantime.
+    let processing_rpc_client = match Client::new(&config.rpc_url.clone(), config.rpc_auth.clone())
+    {
+        Ok(config) => config,
+        Err(e) => panic!(
+            "During startup: Could not setup the Bitcoin Core RPC client: {}",
+            e
+        ),
+    };
+    start_daemon_run_heartbeat_thread(conn_pool.clone());
+    record_downtime_blocks(
         &rpc_client,
         &conn_pool,
+        miningpool_identification_data.clone(),
+        config.pool_identification.network,
+    );
+
+    // Mempool snapshots to know which transactions in a block our node has seen before.
+    let seen_txids_rpc_client = match Client::new(&config.rpc_url.clone(), config.rpc_auth.clone())
+    {
+        Ok(config) => config,
+        Err(e) => panic!(
+            "During startup: Could not setup the Bitcoin Core RPC client: {}",
+            e
+        ),
+    };
+    let seen_txids: model::SharedSeenTxids =
+        Arc::new(Mutex::new(SeenTxids::new(MAX_SEEN_TXID_AGE.as_secs())));
+    start_mempool_snapshot_thread(seen_txids_rpc_client, seen_txids.clone());
+
+    let block_events = start_block_processing_thread(
+        processing_rpc_client,
+        BlockDatabase {
+            pool: conn_pool,
+            retry_queue,
+        },
+        miningpool_identification_data.clone(),
+        config.pool_identification.clone(),
+        seen_txids,
+        config.deployments.clone(),
+    );
+
+    main_loop(
+        &rpc_client,
+        longpoll_rpc_client.as_ref(),
+        &mut TemplatePersistence {
+            store: template_store,
+            archive: template_archive,
+        },
+        &block_events,
         config.pool_identification.clone(),
         miningpool_identification_data,
+        &block_notifications,
     );
 }
 
+/// Where the requested templates are persisted besides the in-memory
+/// template history.
+struct TemplatePersistence {
+    /// Persists the template history to survive daemon restarts.
+    store: Option<TemplateStore>,
+    /// Archives every new template in the database.
+    archive: Option<mpsc::Sender<GetBlockTemplateResult>>,
+}
+
+/// Where the processed blocks are written to.
+struct BlockDatabase {
+    pool: db_pool::PgPool,
+    /// Blocks that can't be written are sent to the retry queue.
+    retry_queue: mpsc::Sender<model::ProcessedBlock>,
+}
+
+/// A change of the chain tip handled by the processing thread.
+enum BlockEvent {
+    /// A block to compare to its candidate templates.
+    Block(DetectedBlock),
+    /// Blocks dropped out of the active chain and are marked as stale.
+    Reorg(chaintip::Reorg),
+    /// Blocks that can't be compared to a template and are only recorded.
+    Uncompared {
+        hashes: Vec<bitcoin::BlockHash>,
+        reason: shared_model::UncomparedReason,
+    },
+}
+
+/// A detected block together with the templates that were candidates for it.
+struct DetectedBlock {
+    block: Block,
+    block_tx_fees: GetBlockTxFeesResult,
+    /// A snapshot of the template history at the time the block was detected.
+    templates: Arc<TemplateHistory>,
+    detected: Instant,
+    /// The wall-clock time the block was detected. Stored as the time the
+    /// block was seen.
+    seen_time: chrono::DateTime<chrono::Utc>,
+}
+
 fn startup_db_mirgation(conn_pool: &db_pool::PgPool) {
     match conn_pool.get() {
         Ok(mut conn) => {
@@ -293,27 +527,41 @@ fn startup_db_mirgation(conn_pool: &db_pool::PgPool) {
 }
 
 fn main_loop(
-    rpc: &Client,
-    db_pool: &db_pool::PgPool,
+    rpc: &impl Node,
+    longpoll_rpc: Option<&impl Node>,
+    persistence: &mut TemplatePersistence,
+    block_events: &mpsc::SyncSender<BlockEvent>,
     pool_identification_config: config::PoolIdentificationConfig,
     pools: model::SharedPoolIDData,
+    block_notifications: &mpsc::Receiver<BlockNotification>,
 ) {
-    // stores up to the last MAX_OLD_TEMPLATES GetBlockTemplateResults to lookup older templates
-    // based on miner block timestamps.
-    let mut last_templates: VecDeque<GetBlockTemplateResult> =
-        VecDeque::with_capacity(MAX_OLD_TEMPLATES);
+    // stores the templates of up to the last MAX_TEMPLATE_HISTORY_AGE to lookup older
+    // templates based on miner block timestamps.
+    let mut last_templates =
+        TemplateHistory::new(MAX_TEMPLATE_HISTORY_AGE.as_secs(), MAX_TEMPLATES_IN_HISTORY);
+    if let Some(store) = persistence.store.as_mut() {
+        load_template_history(rpc, store, &mut last_templates);
+    }
+
+    // The templates the last processed block was compared to. After a reorg,
+    // a competing block at the same height can be compared to them as well.
+    let mut last_block_templates: Option<Arc<TemplateHistory>> = None;
+
+    // The longpollid of the last template when using getblocktemplate long-polling.
+    let mut longpollid: Option<String> = None;
+    // Number of template updates since the last block.
+    let mut template_updates: i64 = 0;
 
     loop {
         metrics::RUNTIME_TEMPLATES_IN_MEMORY.set(last_templates.len() as i64);
+        metrics::RUNTIME_TEMPLATE_HISTORY_TRANSACTIONS
+            .set(last_templates.transaction_count() as i64);
 
-        let current_template = match rpc.get_block_template(
-            GetBlockTemplateModes::Template,
-            &[
-                GetBlockTemplateRules::SegWit,
-                GetBlockTemplateRules::Taproot,
-            ],
-            &[],
-        ) {
+        let template_result = match longpoll_rpc {
+            Some(longpoll_rpc) => longpoll_rpc.get_block_template_longpoll(longpollid.as_deref()),
+            None => rpc.get_block_template(),
+        };
+        let current_template = match template_result {
             Ok(t) => t,
             Err(e) => {
                 log::error!(
@@ -322,293 +570,874 @@ fn main_loop(
                     e
                 );
                 metrics::ERROR_RPC.inc();
-                thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
+                wait_for_next_template_query(block_notifications);
                 continue;
             }
         };
         metrics::RUNTIME_REQUESTED_TEMPLATES.inc();
+        if longpoll_rpc.is_some() {
+            longpollid = Some(current_template.longpollid.clone());
+        }
+
+        if let Some(archive) = persistence.archive.as_ref() {
+            let is_new_template = match last_templates.latest() {
+                Some(previous_template) => {
+                    previous_template.previous_block_hash != current_template.previous_block_hash
+                        || template_transactions_changed(previous_template, &current_template)
+                }
+                None => true,
+            };
+            // Unchanged templates aren't archived again.
+            if is_new_template && archive.send(current_template.clone()).is_err() {
+                log::error!(
+                    target: template_archive::LOG_TARGET_TEMPLATE_ARCHIVE,
+                    "The template archive thread stopped. Not archiving templates anymore."
+                );
+                metrics::ERROR_TEMPLATE_ARCHIVE.inc();
+                persistence.archive = None;
+            }
+        }
 
         log_template_infos(&current_template);
 
         // if we don't have any templates yet, just add the current_template
         // and finish the loop early.
         if last_templates.is_empty() {
-            last_templates.push_back(current_template);
-            thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
+            push_template(
+                &mut last_templates,
+                persistence.store.as_mut(),
+                current_template,
+            );
+            template_updates += 1;
+            if longpoll_rpc.is_none() {
+                wait_for_next_template_query(block_notifications);
+            }
             continue;
         }
-
-        let previous_template = match last_templates.make_contiguous().last() {
-            Some(t) => t,
-            None => {
+
+        let previous_template = match last_templates.latest() {
+            Some(t) => t,
+            None => {
+                processing::log_processing_error(&format!(
+                    "Could not get the previous_template from last_templates (size: {}).",
+                    last_templates.len()
+                ));
+                metrics::ERROR_PROCESSING.inc(); // TODO: this can be remove as log_processing_error already increases it
+                panic!("last_templates should not be empty.");
+            }
+        };
+
+        // A new chain tip is detected with the template building on it.
+        let seen_time = chrono::Utc::now();
+        let (bitcoin_block, block_tx_fees, skipped_blocks) = match chaintip::detect_chain_tip_change(
+            rpc,
+            previous_template,
+            &current_template,
+        ) {
+            Ok(ChainTipChange::Unchanged) => {
+                if template_transactions_changed(previous_template, &current_template) {
+                    template_updates += 1;
+                }
+                push_template(
+                    &mut last_templates,
+                    persistence.store.as_mut(),
+                    current_template,
+                );
+                // When long-polling, the next getblocktemplate call blocks until
+                // there is a new template. No need to wait here.
+                if longpoll_rpc.is_none() {
+                    wait_for_next_template_query(block_notifications);
+                }
+                continue;
+            }
+            Ok(ChainTipChange::Reorg(reorg)) => {
+                log::warn!(
+                    target: processing::LOG_TARGET_PROCESSING,
+                    "The block the last {} templates build on was reorged out. Dropping these templates.",
+                    last_templates.len()
+                );
+                let competing_block_reason = send_competing_block(
+                    rpc,
+                    &reorg,
+                    last_block_templates.as_ref(),
+                    seen_time,
+                    block_events,
+                );
+                if let (Some(reason), Some(competing_block)) =
+                    (competing_block_reason, reorg.connected_blocks.first())
+                {
+                    send_uncompared_blocks(block_events, vec![*competing_block], reason);
+                }
+                // No template built on the parents of the later blocks of the
+                // new active chain.
+                send_uncompared_blocks(
+                    block_events,
+                    reorg.connected_blocks.iter().skip(1).copied().collect(),
+                    shared_model::UncomparedReason::NoTemplate,
+                );
+                send_block_event(block_events, BlockEvent::Reorg(reorg));
+                last_templates.clear();
+                clear_template_store(persistence.store.as_mut());
+                push_template(
+                    &mut last_templates,
+                    persistence.store.as_mut(),
+                    current_template,
+                );
+                template_updates = 1;
+                continue;
+            }
+            Ok(ChainTipChange::NewBlock {
+                block,
+                block_tx_fees,
+            }) => (block, block_tx_fees, vec![]),
+            Ok(ChainTipChange::MissedBlock {
+                block,
+                block_tx_fees,
+                skipped_blocks,
+            }) => (block, block_tx_fees, skipped_blocks),
+            Err(e) => {
+                log::error!(target: LOG_TARGET_RPC, "{}", e);
+                log::error!(
+                    target: processing::LOG_TARGET_PROCESSING,
+                    "Skipping the processing of block {}.",
+                    current_template.previous_block_hash
+                );
+                metrics::ERROR_RPC.inc();
+                wait_for_next_template_query(block_notifications);
+                continue;
+            }
+        };
+
+        log::info!(
+            target: LOG_TARGET_STATS,
+            "Received {} template updates since the last block.",
+            template_updates
+        );
+        metrics::STAT_TEMPLATE_UPDATES_PER_BLOCK_INTERVAL.set(template_updates);
+        template_updates = 0;
+
+        log::info!(
+            target: LOG_TARGET_STATS,
+            "{} {} mined by {}",
+            if !skipped_blocks.is_empty() {
+                "Processing missed block"
+            } else {
+                "New block detected"
+            },
+            bitcoin_block.block_hash(),
+            match bitcoin_block.identify_pool(pool_identification_config.network, &pools.lock().unwrap()) {
+                Some(result) => result.pool.name,
+                None => "UNKNOWN".to_string(),
+            }
+        );
+
+        // The templates are handed over to the processing thread. The
+        // current_template is the first one building on the new chain tip.
+        let templates = Arc::new(last_templates.take());
+        last_block_templates = Some(templates.clone());
+        send_block_event(
+            block_events,
+            BlockEvent::Block(DetectedBlock {
+                block: bitcoin_block,
+                block_tx_fees,
+                templates,
+                detected: Instant::now(),
+                seen_time,
+            }),
+        );
+        send_uncompared_blocks(
+            block_events,
+            skipped_blocks,
+            shared_model::UncomparedReason::NoTemplate,
+        );
+        clear_template_store(persistence.store.as_mut());
+        push_template(
+            &mut last_templates,
+            persistence.store.as_mut(),
+            current_template,
+        );
+        template_updates += 1;
+        if longpoll_rpc.is_none() {
+            wait_for_next_template_query(block_notifications);
+        }
+    }
+}
+
+/// After a reorg, the first block of the new active chain competes with the
+/// first stale block at the same height. If the templates of the last
+/// processed block build on the fork point, the competing block is compared
+/// to them too. Returns the reason why the competing block couldn't be
+/// compared, if it wasn't sent for processing.
+fn send_competing_block(
+    rpc: &impl Node,
+    reorg: &chaintip::Reorg,
+    last_block_templates: Option<&Arc<TemplateHistory>>,
+    seen_time: chrono::DateTime<chrono::Utc>,
+    block_events: &mpsc::SyncSender<BlockEvent>,
+) -> Option<shared_model::UncomparedReason> {
+    let templates = match last_block_templates {
+        Some(templates)
+            if templates
+                .latest()
+                .is_some_and(|t| t.previous_block_hash == reorg.fork_point) =>
+        {
+            templates.clone()
+        }
+        _ => {
+            log::info!(
+                target: processing::LOG_TARGET_PROCESSING,
+                "No templates building on the fork point {}. Not processing the competing block.",
+                reorg.fork_point
+            );
+            return Some(shared_model::UncomparedReason::NoTemplate);
+        }
+    };
+    let hash = reorg.connected_blocks.first()?;
+
+    match chaintip::get_block_with_fees(rpc, hash) {
+        Ok((block, block_tx_fees)) => {
+            log::info!(
+                target: LOG_TARGET_STATS,
+                "Processing competing block {} at height {}",
+                hash,
+                block.bip34_block_height().unwrap_or_default()
+            );
+            metrics::RUNTIME_COMPETING_BLOCKS.inc();
+            send_block_event(
+                block_events,
+                BlockEvent::Block(DetectedBlock {
+                    block,
+                    block_tx_fees,
+                    templates,
+                    detected: Instant::now(),
+                    seen_time,
+                }),
+            );
+            None
+        }
+        Err(e) => {
+            log::error!(target: LOG_TARGET_RPC, "{}", e);
+            log::error!(
+                target: processing::LOG_TARGET_PROCESSING,
+                "Skipping the processing of the competing block {}.",
+                hash
+            );
+            metrics::ERROR_RPC.inc();
+            Some(shared_model::UncomparedReason::RpcFailure)
+        }
+    }
+}
+
+/// Queues blocks that can't be compared to a template to be recorded by the
+/// processing thread.
+fn send_uncompared_blocks(
+    block_events: &mpsc::SyncSender<BlockEvent>,
+    hashes: Vec<bitcoin::BlockHash>,
+    reason: shared_model::UncomparedReason,
+) {
+    if hashes.is_empty() {
+        return;
+    }
+    log::info!(
+        target: LOG_TARGET_STATS,
+        "Recording {} blocks that can't be compared to a template ({}): {:?}",
+        hashes.len(),
+        reason.name(),
+        hashes
+    );
+    send_block_event(block_events, BlockEvent::Uncompared { hashes, reason });
+}
+
+/// Queues the block event for the processing thread. Blocks if the queue is
+/// full.
+fn send_block_event(block_events: &mpsc::SyncSender<BlockEvent>, event: BlockEvent) {
+    metrics::RUNTIME_BLOCK_EVENT_QUEUE_DEPTH.inc();
+    let result = match block_events.try_send(event) {
+        Ok(()) => Ok(()),
+        Err(mpsc::TrySendError::Full(event)) => {
+            log::warn!(
+                target: processing::LOG_TARGET_PROCESSING,
+                "The queue of blocks waiting to be processed is full ({} blocks). Waiting for the processing to catch up.",
+                BLOCK_EVENT_QUEUE_SIZE
+            );
+            block_events.send(event).map_err(|_| ())
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => Err(()),
+    };
+    if result.is_err() {
+        processing::log_processing_error("The block processing thread stopped.");
+        panic!("The block processing thread stopped.");
+    }
+}
+
+/// Starts a thread processing the block events sent to the returned sender.
+fn start_block_processing_thread(
+    rpc: impl Node + Send + 'static,
+    database: BlockDatabase,
+    pools: model::SharedPoolIDData,
+    pool_identification_config: PoolIdentificationConfig,
+    seen_txids: model::SharedSeenTxids,
+    deployments: Vec<Deployment>,
+) -> mpsc::SyncSender<BlockEvent> {
+    let (sender, receiver) = mpsc::sync_channel::<BlockEvent>(BLOCK_EVENT_QUEUE_SIZE);
+
+    thread::spawn(move || {
+        for event in receiver.iter() {
+            metrics::RUNTIME_BLOCK_EVENT_QUEUE_DEPTH.dec();
+            match event {
+                BlockEvent::Block(detected_block) => {
+                    process(
+                        &rpc,
+                        &database,
+                        &detected_block,
+                        pools.clone(),
+                        pool_identification_config.clone(),
+                        &seen_txids,
+                        &deployments,
+                    );
+                    metrics::RUNTIME_BLOCK_PROCESSING_SECONDS
+                        .observe(detected_block.detected.elapsed().as_secs_f64());
+                }
+                BlockEvent::Reorg(reorg) => update_stale_blocks(&database, &reorg),
+                BlockEvent::Uncompared { hashes, reason } => record_uncompared_blocks(
+                    &rpc,
+                    &database.pool,
+                    &hashes,
+                    reason,
+                    pools.clone(),
+                    pool_identification_config.network,
+                ),
+            }
+        }
+    });
+
+    sender
+}
+
+/// Loads the persisted template history. Only templates building on the
+/// current chain tip are kept as the others can't be compared to the next
+/// block anymore. Afterwards, the store is rewritten to match the loaded
+/// history.
+fn load_template_history(
+    rpc: &impl Node,
+    store: &mut TemplateStore,
+    last_templates: &mut TemplateHistory,
+) {
+    let records = match store.load() {
+        Ok(records) => records,
+        Err(e) => {
+            log::error!(
+                target: template_store::LOG_TARGET_TEMPLATE_STORE,
+                "Could not load the template history: {}",
+                e
+            );
+            vec![]
+        }
+    };
+
+    let tip = match rpc.get_best_block_hash() {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_RPC,
+                "Could not get the best block hash from the Bitcoin Core RPC server. Not using the template history: {}",
+                e
+            );
+            metrics::ERROR_RPC.inc();
+            None
+        }
+    };
+
+    // The records are replayed into an unbounded history as they were
+    // written by a history that might have dropped older templates.
+    let mut stored_templates = TemplateHistory::new(u64::MAX, usize::MAX);
+    if let Some(tip) = tip {
+        for record in records {
+            if let Err(e) = stored_templates.append(record) {
+                log::warn!(
+                    target: template_store::LOG_TARGET_TEMPLATE_STORE,
+                    "Could not load a stored template record. Ignoring it and all later records: {}",
+                    e
+                );
+                break;
+            }
+        }
+        let stored = stored_templates.len();
+        stored_templates.drop_templates_not_building_on(&tip);
+        log::info!(
+            target: template_store::LOG_TARGET_TEMPLATE_STORE,
+            "Loaded {} of {} stored templates. The others don't build on the chain tip {}.",
+            stored_templates.len(),
+            stored,
+            tip
+        );
+    }
+
+    clear_template_store(Some(&mut *store));
+    for template in stored_templates.iter() {
+        push_template(last_templates, Some(&mut *store), template);
+    }
+}
+
+/// Adds the template to the template history and, if configured, persists
+/// it in the template store.
+fn push_template(
+    last_templates: &mut TemplateHistory,
+    template_store: Option<&mut TemplateStore>,
+    template: GetBlockTemplateResult,
+) {
+    let record = last_templates.push(template);
+    if let Some(store) = template_store {
+        if let Err(e) = store.append(&record) {
+            log::error!(
+                target: template_store::LOG_TARGET_TEMPLATE_STORE,
+                "Could not persist the template: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Removes the persisted templates once the template history was cleared.
+fn clear_template_store(template_store: Option<&mut TemplateStore>) {
+    if let Some(store) = template_store {
+        if let Err(e) = store.clear() {
+            log::error!(
+                target: template_store::LOG_TARGET_TEMPLATE_STORE,
+                "Could not clear the persisted template history: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Returns true if the transactions in the two templates differ.
+fn template_transactions_changed(a: &GetBlockTemplateResult, b: &GetBlockTemplateResult) -> bool {
+    a.transactions.len() != b.transactions.len()
+        || a.transactions
+            .iter()
+            .zip(b.transactions.iter())
+            .any(|(tx_a, tx_b)| tx_a.txid != tx_b.txid)
+}
+
+/// Waits WAIT_TIME_BETWEEN_TEMPLATE_QUERIES before the next template is
+/// requested. Returns early if a block notification arrives via ZMQ so that
+/// a new block is processed as soon as it's connected.
+fn wait_for_next_template_query(block_notifications: &mpsc::Receiver<BlockNotification>) {
+    match block_notifications.recv_timeout(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES) {
+        Ok(notification) => log::debug!(
+            target: blocknotify::LOG_TARGET_ZMQ,
+            "Requesting a new template after a block notification: {:?}",
+            notification
+        ),
+        Err(mpsc::RecvTimeoutError::Timeout) => (),
+        // Without a ZMQ listener, we fall back to polling.
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES)
+        }
+    }
+}
+
+fn process(
+    rpc: &impl Node,
+    database: &BlockDatabase,
+    event: &DetectedBlock,
+    pools: model::SharedPoolIDData,
+    pool_identification_config: PoolIdentificationConfig,
+    seen_txids: &model::SharedSeenTxids,
+    deployments: &[Deployment],
+) {
+    let connection = &mut match database.pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_DBPOOL,
+                "Could not get a connection from the connection pool. Retrying in {:?}. Error: {}",
+                WAIT_TIME_BETWEEN_CONNPOOL_GETCONNECTION,
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            thread::sleep(WAIT_TIME_BETWEEN_CONNPOOL_GETCONNECTION);
+            match database.pool.get() {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!(target: LOG_TARGET_DBPOOL, "Could not get a connection from the connection pool. Skipping processing of block {}: {}", event.block.block_hash(), e);
+                    metrics::ERROR_DBPOOL.inc();
+                    return;
+                }
+            }
+        }
+    };
+
+    let sanctioned = load_sanctioned_data(connection);
+
+    let mut processed_block = processing::build_processed_block(
+        &event.block,
+        &event.block_tx_fees,
+        &event.templates,
+        event.seen_time,
+        &sanctioned,
+        |txids| mempool_entries(rpc, txids),
+        pools,
+        pool_identification_config.network,
+    );
+    let extra_tx_context = extra_tx_context(
+        event,
+        seen_txids,
+        &processed_block.transactions_only_in_block,
+    );
+    processing::label_transactions_only_in_block(
+        &mut processed_block.transactions_only_in_block,
+        &processed_block.conflicting_transactions,
+        processed_block.block.template_time.and_utc().timestamp() as u64,
+        processed_block
+            .block
+            .template_pkg_feerates
+            .iter()
+            .copied()
+            .reduce(f32::min),
+        &extra_tx_context,
+    );
+    processed_block.block.prev_block_interval =
+        match rpc.get_block_header(&event.block.header.prev_blockhash) {
+            Ok(prev_header) => Some(processing::prev_block_interval(&event.block, &prev_header)),
+            Err(e) => {
+                log::warn!(
+                    target: LOG_TARGET_RPC,
+                    "Could not get the parent header of block {}: {}",
+                    event.block.block_hash(),
+                    e
+                );
+                metrics::ERROR_RPC.inc();
+                None
+            }
+        };
+    let prev_median_time_past =
+        processing::median_time_past(&event.block.header.prev_blockhash, |hash| {
+            match rpc.get_block_header(hash) {
+                Ok(header) => Some(header),
+                Err(e) => {
+                    log::warn!(
+                        target: LOG_TARGET_RPC,
+                        "Could not get header {} to calculate the median time past: {}",
+                        hash,
+                        e
+                    );
+                    metrics::ERROR_RPC.inc();
+                    None
+                }
+            }
+        });
+    processing::tag_deployment_signaling(&mut processed_block.block, deployments);
+    processing::tag_timestamp_anomalies(&mut processed_block.block, prev_median_time_past);
+    log_processed_block_stats(&processed_block);
+
+    // The block is either written or queued to be written later.
+    write_processed_block(processed_block, connection, &database.retry_queue);
+}
+
+/// Marks the blocks that dropped out of the active chain as stale.
+fn update_stale_blocks(database: &BlockDatabase, reorg: &chaintip::Reorg) {
+    let mut connection = match database.pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_DBPOOL,
+                "Could not get a connection from the connection pool. Not marking {} blocks as stale: {}",
+                reorg.stale_blocks.len(),
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+
+    let to_db_hashes = |hashes: &[bitcoin::BlockHash]| -> Vec<Vec<u8>> {
+        hashes.iter().map(display_order_bytes).collect()
+    };
+
+    match db::update_stale_blocks(
+        &to_db_hashes(&reorg.stale_blocks),
+        &to_db_hashes(&reorg.connected_blocks),
+        reorg.depth() as i32,
+        &mut connection,
+    ) {
+        Ok(updated) => log::info!(
+            target: processing::LOG_TARGET_PROCESSING,
+            "Updated the stale status of {} blocks after a reorg of depth {}.",
+            updated,
+            reorg.depth()
+        ),
+        Err(e) => processing::log_processing_error(&format!(
+            "Could not mark the blocks {:?} as stale: {}",
+            reorg.stale_blocks, e
+        )),
+    }
+}
+
+/// Returns the block hash in the byte order it is displayed in, which is the
+/// order the hashes are stored in the database.
+fn display_order_bytes(hash: &bitcoin::BlockHash) -> Vec<u8> {
+    let mut bytes = hash.to_byte_array().to_vec();
+    bytes.reverse();
+    bytes
+}
+
+/// Records blocks that couldn't be compared to a template together with
+/// the reason why. Blocks mined during a downtime weren't seen by the
+/// daemon when they were found. Their header time is used as seen time.
+fn record_uncompared_blocks(
+    rpc: &impl Node,
+    db_pool: &db_pool::PgPool,
+    hashes: &[bitcoin::BlockHash],
+    reason: shared_model::UncomparedReason,
+    pools: model::SharedPoolIDData,
+    network: bitcoin::Network,
+) {
+    let seen_time = if reason == shared_model::UncomparedReason::Downtime {
+        None
+    } else {
+        Some(chrono::Utc::now().naive_utc())
+    };
+    let mut uncompared_blocks = vec![];
+    for hash in hashes {
+        let block = match rpc.get_block(hash) {
+            Ok(block) => block,
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET_RPC,
+                    "Could not get the uncompared block {}: {}",
+                    hash,
+                    e
+                );
+                metrics::ERROR_RPC.inc();
+                continue;
+            }
+        };
+        match processing::build_uncompared_block(&block, reason, seen_time, pools.clone(), network)
+        {
+            Some(uncompared_block) => uncompared_blocks.push(uncompared_block),
+            None => processing::log_processing_error(&format!(
+                "Could not read the height of the uncompared block {}.",
+                hash
+            )),
+        }
+    }
+    if uncompared_blocks.is_empty() {
+        return;
+    }
+
+    let mut connection = match db_pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_DBPOOL,
+                "Could not get a connection from the connection pool. Not recording {} uncompared blocks: {}",
+                uncompared_blocks.len(),
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+    match db::insert_uncompared_blocks(&uncompared_blocks, &mut connection) {
+        Ok(inserted) => log::info!(
+            target: processing::LOG_TARGET_PROCESSING,
+            "Recorded {} uncompared blocks ({}).",
+            inserted,
+            reason.name()
+        ),
+        Err(e) => processing::log_processing_error(&format!(
+            "Could not record the uncompared blocks {:?}: {}",
+            hashes, e
+        )),
+    }
+}
+
+/// Records the blocks mined while the daemon wasn't running. Walks back from
+/// the chain tip until a block that was already observed is found or the
+/// height of the highest observed block is reached. The latter stops the walk
+/// when the last observed block was reorged out. Nothing is recorded on the
+/// first start with an empty database.
+fn record_downtime_blocks(
+    rpc: &impl Node,
+    db_pool: &db_pool::PgPool,
+    pools: model::SharedPoolIDData,
+    network: bitcoin::Network,
+) {
+    let mut connection = match db_pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_DBPOOL,
+                "Could not get a connection from the connection pool. Not recording the blocks mined during the downtime: {}",
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+    let max_observed_height = match db::max_observed_height(&mut connection) {
+        Ok(Some(height)) => height as u64,
+        Ok(None) => return,
+        Err(e) => {
+            processing::log_processing_error(&format!(
+                "Could not get the height of the highest observed block: {}",
+                e
+            ));
+            return;
+        }
+    };
+
+    let mut hash = match rpc.get_best_block_hash() {
+        Ok(hash) => hash,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_RPC,
+                "Could not get the best block hash. Not recording the blocks mined during the downtime: {}",
+                e
+            );
+            metrics::ERROR_RPC.inc();
+            return;
+        }
+    };
+    let tip_height = match rpc.get_block(&hash) {
+        Ok(block) => match block.bip34_block_height() {
+            Ok(height) => height,
+            Err(e) => {
                 processing::log_processing_error(&format!(
-                    "Could not get the previous_template from last_templates (size: {}).",
-                    last_templates.len()
+                    "Could not read the height of the best block {}: {}",
+                    hash, e
                 ));
-                metrics::ERROR_PROCESSING.inc(); // TODO: this can be remove as log_processing_error already increases it
-                panic!("last_templates should not be empty.");
-            }
-        };
-
-        // To detect if a block has been mined, we check the if the previous block hashes
-        // of the previous_template and the current_template are different. If they are
-        // the same, no block has been mined and we wait before getting a new template.
-        if previous_template.previous_block_hash == current_template.previous_block_hash {
-            last_templates.push_back(current_template);
-            if last_templates.len() > MAX_OLD_TEMPLATES {
-                last_templates.pop_front();
+                return;
             }
-            thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-            continue;
+        },
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_RPC,
+                "Could not get the best block {}. Not recording the blocks mined during the downtime: {}",
+                hash,
+                e
+            );
+            metrics::ERROR_RPC.inc();
+            return;
         }
-
-        // The previous block hash of the previous_template and the current_template are
-        // different. This means the chain tip changed. We request the block with the
-        // current_templates previous block hash. In most cases, this should be the block
-        // that was just mined.
-        let bitcoin_block = match rpc.get_block(&current_template.previous_block_hash) {
-            Ok(b) => {
-                metrics::RUNTIME_REQUESTED_BLOCKS.inc();
-                b
-            }
+    };
+    // every step of the walk goes one block lower
+    let blocks_above_observed = tip_height.saturating_sub(max_observed_height) as usize;
+    let mut downtime_blocks = vec![];
+    loop {
+        if downtime_blocks.len() >= blocks_above_observed {
+            break;
+        }
+        match db::is_block_observed(&display_order_bytes(&hash), &mut connection) {
+            Ok(true) => break,
+            Ok(false) => (),
             Err(e) => {
-                log::error!(
-                    target: LOG_TARGET_RPC,
-                    "Could not get the block with the hash {} from the Bitcoin Core RPC server: {}",
-                    current_template.previous_block_hash,
-                    e
-                );
-                log::error!(
-                    target: LOG_TARGET_RPC,
-                    "Skipping the processing of block {}.",
-                    current_template.previous_block_hash
-                );
-                metrics::ERROR_RPC.inc();
-                thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-                continue;
+                processing::log_processing_error(&format!(
+                    "Could not check if the block {} was observed: {}",
+                    hash, e
+                ));
+                return;
             }
-        };
-
-        // When something changed, we can't be sure if the templates in last_templates
-        // are actually templates for the block we just requested. For example, two block
-        // might have arrived at our node in rapid succession and we didn't query for a
-        // template in between.
-        // To check that we can compare the block with one of our last_templates, we
-        // compare the previous block hashes of block and the previous_template.
-        // Only if the hashes are equal, we can compare the template and block.
-        // If they are not equal, we might have skipped one or more blocks.
-        if bitcoin_block.header.prev_blockhash != previous_template.previous_block_hash {
+        }
+        if downtime_blocks.len() >= MAX_DOWNTIME_BLOCKS {
             log::warn!(
-                target: processing::LOG_TARGET_PROCESSING,
-                "Can't compare the previous_template to the new block. Was there a reorg or multiple blocks found in rapid succession?",
-            );
-            metrics::RUNTIME_SKIPPED_BLOCK_EVENTS.inc();
-
-            // We can however still compare the previous_template to it's respective block.
-            let hash_of_missed_block = match rpc.get_block_hash(previous_template.height) {
-                Ok(hash) => hash,
-                Err(e) => {
-                    log::error!(
-                        target: LOG_TARGET_RPC,
-                        "Could not get the block hash of block {} from the Bitcoin Core RPC server: {}",
-                        previous_template.height,
-                        e
-                    );
-                    thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-                    continue;
-                }
-            };
-
-            let bitcoin_block = match rpc.get_block(&hash_of_missed_block) {
-                Ok(b) => {
-                    metrics::RUNTIME_REQUESTED_BLOCKS.inc();
-                    b
-                }
-                Err(e) => {
-                    log::error!(
-                        target: LOG_TARGET_RPC,
-                        "Could not get the block with the hash {} from the Bitcoin Core RPC server: {}",
-                        current_template.previous_block_hash,
-                        e
-                    );
-                    log::error!(
-                        target: LOG_TARGET_RPC,
-                        "Skipping the processing of block missed block {}.",
-                        current_template.previous_block_hash
-                    );
-                    thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-                    continue;
-                }
-            };
-
-            // TODO: Once Bitcoin Core v22 with getblock verbosity level 2 is released
-            // and rust-bitcoincore-rpc has can get the block and fees in on RPC call,
-            // then the above getblock call and this can be merged.
-            let block_tx_fees = match rpc.get_block_txid_fee(&hash_of_missed_block) {
-                Ok(b) => b,
-                Err(e) => {
-                    log::error!(target: LOG_TARGET_RPC, "Could not get the txids and fees for block with the hash {} from the Bitcoin Core RPC server: {}", current_template.previous_block_hash, e);
-                    log::error!(
-                        target: processing::LOG_TARGET_PROCESSING,
-                        "Skipping the processing of block {}.",
-                        current_template.previous_block_hash
-                    );
-                    metrics::ERROR_RPC.inc();
-                    thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-                    continue;
-                }
-            };
-
-            log::info!(
-                target: LOG_TARGET_STATS,
-                "Processing missed block {} mined by {}",
-                bitcoin_block.block_hash(),
-                match bitcoin_block.identify_pool(pool_identification_config.clone().network, &pools.lock().unwrap()) {
-                    Some(result) => result.pool.name,
-                    None => "UNKNOWN".to_string(),
-                }
-            );
-
-            process(
-                rpc,
-                db_pool,
-                &bitcoin_block,
-                &block_tx_fees,
-                &mut last_templates,
-                pools.clone(),
-                pool_identification_config.clone(),
+                target: LOG_TARGET_STARTUP,
+                "Found more than {} blocks mined during the downtime. Only recording the last {} blocks.",
+                MAX_DOWNTIME_BLOCKS,
+                MAX_DOWNTIME_BLOCKS
             );
-
-            last_templates.push_back(current_template);
-            continue;
+            break;
         }
-
-        // TODO: Once Bitcoin Core v22 with getblock verbosity level 2 is released
-        // and rust-bitcoincore-rpc has can get the block and fees in on RPC call,
-        // then the above getblock call and this can be merged.
-        let block_tx_fees = match rpc.get_block_txid_fee(&current_template.previous_block_hash) {
-            Ok(b) => b,
+        downtime_blocks.push(hash);
+        hash = match rpc.get_block_header(&hash) {
+            Ok(header) => header.prev_blockhash,
             Err(e) => {
-                log::error!(target: LOG_TARGET_RPC, "Could not get the txids and fees for block with the hash {} from the Bitcoin Core RPC server: {}", current_template.previous_block_hash, e);
                 log::error!(
-                    target: processing::LOG_TARGET_PROCESSING,
-                    "Skipping the processing of block {}.",
-                    current_template.previous_block_hash
+                    target: LOG_TARGET_RPC,
+                    "Could not get the block header of {}: {}",
+                    hash,
+                    e
                 );
                 metrics::ERROR_RPC.inc();
-                thread::sleep(WAIT_TIME_BETWEEN_TEMPLATE_QUERIES);
-                continue;
+                break;
             }
         };
+    }
+    drop(connection);
 
+    if !downtime_blocks.is_empty() {
         log::info!(
-            target: LOG_TARGET_STATS,
-            "New block detected {} mined by {}",
-            bitcoin_block.block_hash(),
-            match bitcoin_block.identify_pool(pool_identification_config.network.clone(), &pools.lock().unwrap()) {
-                Some(result) => result.pool.name,
-                None => "UNKNOWN".to_string(),
-            }
+            target: LOG_TARGET_STARTUP,
+            "Recording {} blocks mined while the daemon wasn't running.",
+            downtime_blocks.len()
         );
-
-        process(
+        record_uncompared_blocks(
             rpc,
             db_pool,
-            &bitcoin_block,
-            &block_tx_fees,
-            &mut last_templates,
-            pools.clone(),
-            pool_identification_config.clone(),
+            &downtime_blocks,
+            shared_model::UncomparedReason::Downtime,
+            pools,
+            network,
         );
     }
 }
 
-fn process(
-    rpc: &Client,
-    db_pool: &db_pool::PgPool,
-    bitcoin_block: &Block,
-    block_tx_fees: &GetBlockTxFeesResult,
-    last_templates: &mut VecDeque<GetBlockTemplateResult>,
-    pools: model::SharedPoolIDData,
-    pool_identification_config: PoolIdentificationConfig,
-) {
-    let block_tx_data = processing::build_block_tx_data(bitcoin_block, block_tx_fees);
-
-    // For best possible comparison we want to compare a template and a block
-    // where our template was generated at the same time as the pool generated
-    // his template. As we can't know when exactly the pool generated his
-    // template, we assume that it must have been before or at the
-    // timestamp included in the block headder. During mining, the miners likely
-    // engages in nTime rolling, which increases the timestamp in the block header.
-    // Additionally, we don't know if the pools clock is accurate.
-    //
-    // Thus, to aid template selection, we pick the template sharing the most transactions
-    // with the block out of the templates generated before the timestamp in the block header.
-    let template = select_best_template_for_block(last_templates, block_tx_data.txids.clone());
-
-    let template_tx_data = processing::build_template_tx_data(template);
-
-    let template_tx_packages = processing::build_packages(&template_tx_data.txinfos);
-    let template_pkg_feerates: Vec<f32> =
-        template_tx_packages.iter().map(|p| p.feerate()).collect();
-    let template_pkg_weights: Vec<i64> = template_tx_packages
-        .iter()
-        .map(|p| p.weight() as i64)
-        .collect();
-
-    let block_tx_packages = processing::build_packages(&block_tx_data.txinfos);
-    let block_pkg_feerates: Vec<f32> = block_tx_packages.iter().map(|p| p.feerate()).collect();
-    let block_pkg_weights: Vec<i64> = block_tx_packages
-        .iter()
-        .map(|p| p.weight() as i64)
-        .collect();
-
-    // Set diffs to get the transactions that are in the block
-    // but not in the template and the other way around.
-    let txids_only_in_block: HashSet<&Txid> = block_tx_data
-        .txids
-        .difference(&template_tx_data.txids)
-        .collect();
-    let txids_only_in_template: HashSet<&Txid> = template_tx_data
-        .txids
-        .difference(&block_tx_data.txids)
-        .collect();
-    let shared_tx: usize = block_tx_data
-        .txids
-        .intersection(&template_tx_data.txids)
-        .count();
-
-    log::info!(
-        target: LOG_TARGET_STATS,
-        "Transactions in block: shared={}, missing={}, extra={}",
-        shared_tx,
-        txids_only_in_template.len(),
-        txids_only_in_block.len()
-    );
-
-    let mut connection = &mut match db_pool.get() {
-        Ok(c) => c,
+/// Records the start of this daemon run and periodically updates the time
+/// it was last seen alive. The gaps between runs are shown as downtime.
+fn start_daemon_run_heartbeat_thread(db_pool: db_pool::PgPool) {
+    let run_id = match db_pool.get() {
+        Ok(mut conn) => match db::insert_daemon_run(&chrono::Utc::now().naive_utc(), &mut conn) {
+            Ok(id) => id,
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET_STARTUP,
+                    "Could not record the start of the daemon run: {}",
+                    e
+                );
+                panic!("During startup: Could not record the start of the daemon run.");
+            }
+        },
         Err(e) => {
             log::error!(
-                target: LOG_TARGET_DBPOOL,
-                "Could not get a connection from the connection pool. Retrying in {:?}. Error: {}",
-                WAIT_TIME_BETWEEN_CONNPOOL_GETCONNECTION,
+                target: LOG_TARGET_STARTUP,
+                "Could not get a connection from the connection pool: {}",
                 e
             );
-            metrics::ERROR_DBPOOL.inc();
-            thread::sleep(WAIT_TIME_BETWEEN_CONNPOOL_GETCONNECTION);
-            match db_pool.get() {
-                Ok(c) => c,
-                Err(e) => {
-                    log::error!(target: LOG_TARGET_DBPOOL, "Could not get a connection from the connection pool. Skipping processing of block {}: {}", bitcoin_block.block_hash(), e);
-                    metrics::ERROR_DBPOOL.inc();
-                    return;
-                }
-            }
+            panic!("During startup: Could not get a database connection from the connection pool.");
         }
     };
 
-    let sanctioned_addresses: HashSet<String> = match db::sanctioned_addresses(&mut connection) {
+    thread::spawn(move || loop {
+        thread::sleep(WAIT_TIME_BETWEEN_HEARTBEATS);
+        let result = match db_pool.get() {
+            Ok(mut conn) => db::update_daemon_run_last_seen_time(
+                run_id,
+                &chrono::Utc::now().naive_utc(),
+                &mut conn,
+            )
+            .map_err(|e| e.to_string()),
+            Err(e) => {
+                metrics::ERROR_DBPOOL.inc();
+                Err(e.to_string())
+            }
+        };
+        if let Err(e) = result {
+            log::error!(
+                target: LOG_TARGET_DBPOOL,
+                "Could not update the last seen time of the daemon run {}: {}",
+                run_id,
+                e
+            );
+        }
+    });
+}
+
+/// Loads the sanctioned addresses and UTXOs from the database. Falls back
+/// to empty sets if they can't be loaded.
+fn load_sanctioned_data(connection: &mut diesel::PgConnection) -> model::SanctionedData {
+    let addresses: HashSet<String> = match db::sanctioned_addresses(connection) {
         Ok(addresses) => addresses.iter().map(|a| a.address.clone()).collect(),
         Err(e) => {
             processing::log_processing_error(&format!("Could not load the sanctioned addresses from the database. Using empty list of addresses: {}", e));
@@ -616,187 +1445,68 @@ fn process(
         }
     };
 
-    let sanctioned_utxos = match db::get_sanctioned_utxos(&mut connection) {
+    let utxos = match db::get_sanctioned_utxos(connection) {
         Ok(utxos) => utxos,
         Err(e) => {
             processing::log_processing_error(&format!("Could not load the sanctioned utxos from the database. Using empty UTXO set. Error: {}", e));
             vec![]
         }
     };
-    let outpoint_to_sanctioned_utxo_map =
-        processing::build_outpoint_to_sanctioned_utxo_map(&sanctioned_utxos);
-
-    let sanctioned_missing_tx = processing::get_sanctioned_missing_tx_count(
-        &txids_only_in_template,
-        &template_tx_data,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
-    );
-
-    let block_fees: Amount =
-        Amount::from_sat(block_tx_fees.tx.iter().map(|tx| tx.fee.to_sat()).sum());
-    let template_fees: Amount =
-        Amount::from_sat(template.transactions.iter().map(|tx| tx.fee.to_sat()).sum());
-    let missing_tx: i32 = txids_only_in_template.len() as i32;
-    let extra_tx: i32 = txids_only_in_block.len() as i32;
-    let block = processing::build_block(
-        bitcoin_block,
-        template,
-        &template_tx_data.txid_to_txinfo_map,
-        &template_pkg_weights,
-        &template_pkg_feerates,
-        &block_pkg_weights,
-        &block_pkg_feerates,
-        missing_tx,
-        sanctioned_missing_tx as i32,
-        extra_tx,
-        shared_tx as i32,
-        &block_fees,
-        &template_fees,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
-        pools,
-        pool_identification_config.network,
-    );
 
-    let block_id = match db::insert_block(&block, &mut connection) {
-        Ok(id) => id,
-        Err(e) => {
-            processing::log_processing_error(&format!("Could not insert the block into the database. Skipping the remaining processing. Error: {}", e));
-            return;
-        }
-    };
+    model::SanctionedData { addresses, utxos }
+}
 
-    let mut transactions: HashMap<Vec<u8>, shared_model::Transaction> = HashMap::new();
-
-    let conflicting_transactions = processing::build_conflicting_transactions(
-        block_id,
-        &txids_only_in_template,
-        &template_tx_data.txid_to_txinfo_map,
-        &txids_only_in_block,
-        &block_tx_data.txid_to_txinfo_map,
-        &mut transactions,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
+fn log_processed_block_stats(processed_block: &model::ProcessedBlock) {
+    log::info!(
+        target: LOG_TARGET_STATS,
+        "Transactions in block: shared={}, missing={}, extra={}",
+        processed_block.block.shared_tx,
+        processed_block.block.missing_tx,
+        processed_block.block.extra_tx
     );
 
-    if !conflicting_transactions.is_empty() {
+    let conflicting_transactions = processed_block.conflicting_transactions.len();
+    if conflicting_transactions > 0 {
         log::info!(
             target: LOG_TARGET_STATS,
             "Between the template and the block are {} sets of conflicting transactions.",
-            conflicting_transactions.len()
+            conflicting_transactions
         );
-        metrics::STAT_CONFLICTING_TRANSACTION_SETS.inc_by(conflicting_transactions.len() as u64);
+        metrics::STAT_CONFLICTING_TRANSACTION_SETS.inc_by(conflicting_transactions as u64);
     }
 
-    let template_txid_to_mempool_age = mempool_age_seconds(rpc, &txids_only_in_template);
-    let transactions_only_in_template = processing::build_transactions_only_in_template(
-        block_id,
-        &txids_only_in_template,
-        &template_tx_data.txid_to_txinfo_map,
-        &template_txid_to_mempool_age,
-        &mut transactions,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
-    );
-    let transactions_only_in_block = processing::build_transactions_only_in_block(
-        block_id,
-        &txids_only_in_block,
-        &block_tx_data.txid_to_txinfo_map,
-        &mut transactions,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
-    );
-    let sanctioned_transaction_infos = processing::build_sanctioned_transaction_infos(
-        block_id,
-        &block_tx_data,
-        &template_tx_data.txids,
-        &template_tx_data.txid_to_txinfo_map,
-        &txids_only_in_block,
-        &outpoint_to_sanctioned_utxo_map,
-        &sanctioned_addresses,
-        &mut transactions,
-    );
-    if !sanctioned_transaction_infos.is_empty() {
+    let sanctioned_transactions = processed_block.sanctioned_transaction_infos.len();
+    if sanctioned_transactions > 0 {
         log::info!(
             target: LOG_TARGET_STATS,
             "There are a total of {} sanctioned transactions in this template and block.",
-            sanctioned_transaction_infos.len()
+            sanctioned_transactions
         );
-        metrics::STAT_SANCTIONED_TRANSACTIONS.inc_by(sanctioned_transaction_infos.len() as u64);
-    }
-
-    let debug_template_selection_infos = processing::build_debug_template_selection_infos(
-        block_id,
-        last_templates,
-        block_tx_data.txids,
-        template.current_time,
-    );
-
-    if let Err(e) =
-        db::insert_transactions(transactions.values().cloned().collect(), &mut connection)
-    {
-        processing::log_processing_error(&format!("Could not insert the transactions into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-        return;
-    }
-    if let Err(e) =
-        db::insert_transactions_only_in_block(transactions_only_in_block, &mut connection)
-    {
-        processing::log_processing_error(&format!("Could not insert the transactions_only_in_block into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-        return;
-    }
-    if let Err(e) =
-        db::insert_transactions_only_in_template(transactions_only_in_template, &mut connection)
-    {
-        processing::log_processing_error(&format!("Could not insert the transactions_only_in_template into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-        return;
-    }
-    if let Err(e) =
-        db::insert_sanctioned_transaction_infos(sanctioned_transaction_infos, &mut connection)
-    {
-        processing::log_processing_error(&format!("Could not insert the sanctioned_transaction_infos into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-        return;
-    }
-    if let Err(e) = db::insert_conflicting_transactions(conflicting_transactions, &mut connection) {
-        processing::log_processing_error(&format!("Could not insert the conflicting_transactions into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-        return;
+        metrics::STAT_SANCTIONED_TRANSACTIONS.inc_by(sanctioned_transactions as u64);
     }
+}
 
-    let newly_sactioned_utxos =
-        processing::build_newly_created_sanctioned_utxos(bitcoin_block, &sanctioned_addresses);
-    if !newly_sactioned_utxos.is_empty() {
-        log::info!(target: "sanctioned_utxos", "Inserting {} new sanctioned UTXOs into the database.", newly_sactioned_utxos.len());
-        if let Err(e) = db::insert_sanctioned_utxos(&newly_sactioned_utxos, &mut connection) {
-            processing::log_processing_error(&format!("Could not insert the newly_sactioned_utxos into the database. Skipping the remaining processing. Unclean database state! Error: {}", e));
-            return;
+/// Writes the processed block into the database. If that fails, nothing is
+/// written and the block is queued to be retried later.
+fn write_processed_block(
+    mut processed_block: model::ProcessedBlock,
+    connection: &mut diesel::PgConnection,
+    retry_queue: &mpsc::Sender<model::ProcessedBlock>,
+) {
+    if let Err(e) = db::insert_processed_block(&mut processed_block, connection) {
+        processing::log_processing_error(&format!(
+            "Could not write the block {} into the database. Queuing it to be retried later. Error: {}",
+            hex::encode(&processed_block.block.hash),
+            e
+        ));
+        if retry_queue.send(processed_block).is_err() {
+            log::error!(
+                target: retry_queue::LOG_TARGET_RETRY_QUEUE,
+                "The retry queue thread stopped. Could not queue the block."
+            );
+            metrics::ERROR_RETRY_QUEUE.inc();
         }
     }
-
-    if let Err(e) =
-        db::insert_debug_template_selection_infos(debug_template_selection_infos, &mut connection)
-    {
-        log::warn!(target: processing::LOG_TARGET_PROCESSING, "Could not insert the debug_template_selection_infos into the database. Non-critical. Error: {}", e);
-        return;
-    }
-
-    last_templates.clear();
-}
-
-fn select_best_template_for_block(
-    last_templates: &VecDeque<GetBlockTemplateResult>,
-    block_txids: HashSet<Txid>,
-) -> &GetBlockTemplateResult {
-    assert!(!last_templates.is_empty());
-
-    last_templates
-        .iter()
-        .min_by_key(|t| {
-            let template_txids: HashSet<Txid> = t.transactions.iter().map(|t| t.txid).collect();
-            let extra = block_txids.difference(&template_txids).count();
-            let missing = template_txids.difference(&block_txids).count();
-            missing + extra
-        })
-        .unwrap() // we can unwrap the Option here as we WILL find a minimum if the last_templates is not empty (which is asserted).
 }
 
 fn log_template_infos(t: &GetBlockTemplateResult) {
@@ -822,7 +1532,7 @@ fn log_template_infos(t: &GetBlockTemplateResult) {
 
 fn scantxoutset_sanctioned_tx(
     addrs: Vec<String>,
-    rpc: &Client,
+    rpc: &impl Node,
 ) -> Result<
     (
         Vec<shared_model::SanctionedUtxo>,
@@ -890,7 +1600,10 @@ fn scantxoutset_sanctioned_tx(
     Ok((utxos, scan_info))
 }
 
-fn start_sanctioned_utxos_scan_thread(rpc_client: Client, db_pool: db_pool::PgPool) {
+fn start_sanctioned_utxos_scan_thread(
+    rpc_client: impl Node + Send + 'static,
+    db_pool: db_pool::PgPool,
+) {
     thread::spawn(move || loop {
         let mut conn = &mut match db_pool.get() {
             Ok(c) => c,
@@ -961,7 +1674,7 @@ fn start_sanctioned_utxos_scan_thread(rpc_client: Client, db_pool: db_pool::PgPo
     });
 }
 
-fn retag_transactions(rpc_client: Client, db_pool: db_pool::PgPool) {
+fn retag_transactions(rpc_client: impl Node + Send + 'static, db_pool: db_pool::PgPool) {
     thread::spawn(move || {
         log::info!(
             target: LOG_TARGET_RETAG_TX,
@@ -1012,7 +1725,7 @@ fn retag_transactions(rpc_client: Client, db_pool: db_pool::PgPool) {
             reversed_txid.reverse();
             let hash = bitcoin::hashes::sha256d::Hash::from_slice(&reversed_txid).unwrap();
             let txid: Txid = Txid::from_raw_hash(hash);
-            if let Ok(tx) = rpc_client.get_raw_transaction(&txid, None) {
+            if let Ok(tx) = rpc_client.get_raw_transaction(&txid) {
                 let tx_info = TxInfo {
                     txid,
                     tx: tx.clone(),
@@ -1283,7 +1996,7 @@ fn start_pool_identification_update_thread(pools: model::SharedPoolIDData, url:
 }
 
 fn start_retry_unknown_pool_identification_thread(
-    rpc_client: Client,
+    rpc_client: impl Node + Send + 'static,
     db_pool: db_pool::PgPool,
     pools: model::SharedPoolIDData,
     pool_identification_config: config::PoolIdentificationConfig,
@@ -1417,43 +2130,162 @@ fn get_user_pass(
     }
 }
 
-fn mempool_age_seconds(
-    rpc: &Client,
-    txids_only_in_template: &HashSet<&Txid>,
-) -> HashMap<Txid, i32> {
-    let mut txid_to_seconds_in_mempool: HashMap<Txid, i32> = HashMap::new();
-    log::info!(
-        target: processing::LOG_TARGET_PROCESSING,
-        "Getting the mempool entry times for {} only-in-template-transactions",
-        txids_only_in_template.len()
-    );
+/// Collects what our node knows about the transactions only in the block. A
+/// transaction counts as seen if it was in one of our mempool snapshots or in
+/// one of the templates the block was compared to. Transactions that arrived
+/// and were mined between two mempool snapshots appear as never seen. Never
+/// seen transactions are checked against the default policy of our node.
+fn extra_tx_context(
+    event: &DetectedBlock,
+    seen_txids: &model::SharedSeenTxids,
+    transactions_only_in_block: &[shared_model::TransactionOnlyInBlock],
+) -> model::ExtraTxContext {
+    let extra_txids: HashSet<&Vec<u8>> = transactions_only_in_block
+        .iter()
+        .filter(|t| t.position > 0)
+        .map(|t| &t.transaction_txid)
+        .collect();
 
-    // used the same time for all transactions
-    let now = chrono::Local::now().timestamp();
+    let mut context = model::ExtraTxContext {
+        first_seen: HashMap::new(),
+        pkg_feerates: HashMap::new(),
+        coinbase_script_txids: HashSet::new(),
+        reject_reasons: HashMap::new(),
+    };
+    let block_txid_to_pkg_feerate =
+        processing::block_txid_to_pkg_feerate(&event.block, &event.block_tx_fees);
+    let txid_to_fee: HashMap<Txid, bitcoin::Amount> = event
+        .block_tx_fees
+        .tx
+        .iter()
+        .map(|tx| (tx.txid, tx.fee))
+        .collect();
+    let extra_txs: Vec<(&bitcoin::Transaction, Txid, Vec<u8>)> = event
+        .block
+        .txdata
+        .iter()
+        // the coinbase transaction is never tested
+        .skip(1)
+        .filter_map(|tx| {
+            let txid = tx.compute_txid();
+            let mut txid_display_order = txid.to_byte_array().to_vec();
+            txid_display_order.reverse();
+            extra_txids
+                .contains(&txid_display_order)
+                .then_some((tx, txid, txid_display_order))
+        })
+        .collect();
+    let first_template_times = event
+        .templates
+        .first_template_times(&extra_txs.iter().map(|(_, txid, _)| *txid).collect());
+
+    for (tx, txid, txid_display_order) in extra_txs {
+        let first_seen = first_template_times
+            .get(&txid)
+            .copied()
+            .into_iter()
+            .chain(seen_txids.lock().unwrap().first_seen(&txid))
+            .min();
+        match first_seen {
+            Some(first_seen) => {
+                context
+                    .first_seen
+                    .insert(txid_display_order.clone(), first_seen);
+            }
+            // Our node accepted the transactions it has seen. The never seen
+            // ones are checked against its default policy.
+            None => {
+                let fee = txid_to_fee.get(&txid).copied().unwrap_or_default();
+                if let Some(reason) = policy::reject_reason(tx, fee) {
+                    context
+                        .reject_reasons
+                        .insert(txid_display_order.clone(), reason.to_string());
+                }
+            }
+        }
+        if let Some(feerate) = block_txid_to_pkg_feerate.get(&txid) {
+            context
+                .pkg_feerates
+                .insert(txid_display_order.clone(), *feerate);
+        }
+        if processing::uses_coinbase_output_script(&event.block.txdata[0], tx) {
+            context
+                .coinbase_script_txids
+                .insert(txid_display_order.clone());
+        }
+    }
+    context
+}
 
-    let mut failed_requests: usize = 0;
-    for txid in txids_only_in_template.iter() {
-        metrics::RUNTIME_REQUESTED_MEMPOOL_TRANSACTIONS.inc();
-        let seconds: i32 = match rpc.get_mempool_entry(txid) {
-            Ok(entry) => (now - entry.time as i64) as i32,
+/// Starts a thread periodically recording the txids in the mempool of our
+/// node in the history of seen txids.
+fn start_mempool_snapshot_thread(
+    rpc: impl Node + Send + 'static,
+    seen_txids: model::SharedSeenTxids,
+) {
+    thread::spawn(move || loop {
+        match rpc.get_raw_mempool() {
+            Ok(txids) => {
+                let mut seen_txids = seen_txids.lock().unwrap();
+                seen_txids.update(&txids, chrono::Utc::now().timestamp() as u64);
+                metrics::RUNTIME_SEEN_TXIDS.set(seen_txids.len() as i64);
+            }
             Err(e) => {
                 log::warn!(
                     target: LOG_TARGET_RPC,
-                    "Getting the mempool entry time for transaction {} failed: {}",
-                    txid.to_string(),
+                    "Could not get a mempool snapshot to update the seen txids: {}",
                     e
                 );
-                failed_requests += 1;
-                -1
+                metrics::ERROR_RPC.inc();
             }
-        };
-        txid_to_seconds_in_mempool.insert(**txid, seconds);
-    }
+        }
+        thread::sleep(WAIT_TIME_BETWEEN_MEMPOOL_SNAPSHOTS);
+    });
+}
+
+/// Looks up the transactions in a single snapshot of the mempool.
+/// Transactions not in the mempool, or all transactions if the snapshot
+/// can't be requested, are missing from the returned map.
+fn mempool_entries(
+    rpc: &impl Node,
+    txids_only_in_template: &HashSet<&Txid>,
+) -> HashMap<Txid, model::MempoolEntryInfo> {
+    log::info!(
+        target: processing::LOG_TARGET_PROCESSING,
+        "Getting the mempool entries for {} only-in-template-transactions",
+        txids_only_in_template.len()
+    );
+    metrics::RUNTIME_REQUESTED_MEMPOOL_TRANSACTIONS.inc_by(txids_only_in_template.len() as u64);
+
+    let mempool = match rpc.get_raw_mempool_verbose() {
+        Ok(mempool) => mempool,
+        Err(e) => {
+            log::warn!(
+                target: LOG_TARGET_RPC,
+                "Getting the mempool entries failed. The mempool information of the only-in-template-transactions is unknown: {}",
+                e
+            );
+            metrics::ERROR_RPC.inc();
+            return HashMap::new();
+        }
+    };
+
+    // used the same time for all transactions
+    let now = chrono::Local::now().timestamp();
+    let entries: HashMap<Txid, model::MempoolEntryInfo> = txids_only_in_template
+        .iter()
+        .filter_map(|txid| {
+            mempool
+                .get(*txid)
+                .map(|entry| (**txid, model::MempoolEntryInfo::new(entry, now)))
+        })
+        .collect();
     log::info!(
         target: processing::LOG_TARGET_PROCESSING,
-        "Completed requesting mempool entry times for {} transactions. {} requests failed.",
+        "Found {} of {} only-in-template-transactions in a mempool of {} transactions.",
+        entries.len(),
         txids_only_in_template.len(),
-        failed_requests
+        mempool.len()
     );
-    txid_to_seconds_in_mempool
+    entries
 }
@@ -1,6 +1,6 @@
 use lazy_static::lazy_static;
-use prometheus::{self, IntCounter, IntGauge};
-use prometheus::{register_int_counter, register_int_gauge};
+use prometheus::{self, Histogram, IntCounter, IntGauge};
+use prometheus::{register_histogram, register_int_counter, register_int_gauge};
 
 // Prometheus Metrics
 
@@ -30,10 +30,50 @@ lazy_static! {
     pub static ref RUNTIME_SKIPPED_BLOCK_EVENTS: IntCounter =
         register_int_counter!(format!("{}_runtime_skipped_block_events", PREFIX), "Number of block-skipped-events. Can happen if there are multiple rapid blocks.").unwrap();
 
+    /// Number of detected chain reorganizations.
+    pub static ref RUNTIME_REORGS: IntCounter =
+        register_int_counter!(format!("{}_runtime_reorgs", PREFIX), "Number of detected reorgs where blocks dropped out of the active chain.").unwrap();
+
+    /// Number of competing blocks processed after a reorg.
+    pub static ref RUNTIME_COMPETING_BLOCKS: IntCounter =
+        register_int_counter!(format!("{}_runtime_competing_blocks", PREFIX), "Number of blocks processed after a reorg that compete with a stale block at the same height.").unwrap();
+
+    /// Number of block notifications received via ZMQ.
+    pub static ref RUNTIME_ZMQ_BLOCK_NOTIFICATIONS: IntCounter =
+        register_int_counter!(format!("{}_runtime_zmq_block_notifications", PREFIX), "Number of block notifications received from the Bitcoin Core ZMQ interface.").unwrap();
+
     /// Number of templates currently in memory.
     pub static ref RUNTIME_TEMPLATES_IN_MEMORY: IntGauge =
         register_int_gauge!(format!("{}_runtime_templates_in_memory", PREFIX), "Number of templates kept in memory.").unwrap();
 
+    /// Number of deduplicated transactions in the template history.
+    pub static ref RUNTIME_TEMPLATE_HISTORY_TRANSACTIONS: IntGauge =
+        register_int_gauge!(format!("{}_runtime_template_history_transactions", PREFIX), "Number of deduplicated transactions kept in the template history.").unwrap();
+
+    /// Number of txids in the history of seen mempool transactions.
+    pub static ref RUNTIME_SEEN_TXIDS: IntGauge =
+        register_int_gauge!(format!("{}_runtime_seen_txids", PREFIX), "Number of txids kept in the history of seen mempool transactions.").unwrap();
+
+    /// Number of templates written to the template archive.
+    pub static ref RUNTIME_ARCHIVED_TEMPLATES: IntCounter =
+        register_int_counter!(format!("{}_runtime_archived_templates", PREFIX), "Number of templates written to the template archive.").unwrap();
+
+    /// Number of templates not written to the template archive as they were already archived.
+    pub static ref RUNTIME_DUPLICATE_ARCHIVED_TEMPLATES: IntCounter =
+        register_int_counter!(format!("{}_runtime_duplicate_archived_templates", PREFIX), "Number of templates not written to the template archive as a template with the same height, time and transactions was already archived.").unwrap();
+
+    /// Number of processed blocks waiting to be written to the database.
+    pub static ref RUNTIME_RETRY_QUEUE_BLOCKS: IntGauge =
+        register_int_gauge!(format!("{}_runtime_retry_queue_blocks", PREFIX), "Number of processed blocks in the retry queue waiting to be written to the database.").unwrap();
+
+    /// Number of detected blocks and reorgs waiting to be processed.
+    pub static ref RUNTIME_BLOCK_EVENT_QUEUE_DEPTH: IntGauge =
+        register_int_gauge!(format!("{}_runtime_block_event_queue_depth", PREFIX), "Number of detected blocks and reorgs waiting in the queue to be processed.").unwrap();
+
+    /// Latency between detecting a block and finishing its processing, including the time spent waiting in the queue.
+    pub static ref RUNTIME_BLOCK_PROCESSING_SECONDS: Histogram =
+        register_histogram!(format!("{}_runtime_block_processing_seconds", PREFIX), "Seconds between detecting a block and finishing its processing.", vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0]).unwrap();
+
     // -------------------- Template Statistics
 
     /// Number of transactions in the most recently queried block template.
@@ -48,6 +88,10 @@ lazy_static! {
     pub static ref STAT_CURRENT_TEMPLATE_SIGOPS_GAUGE: IntGauge =
         register_int_gauge!(format!("{}_stats_current_template_sigops", PREFIX), "Sigops of the transactions in the block template.").unwrap();
 
+    /// Number of template updates in the last block interval.
+    pub static ref STAT_TEMPLATE_UPDATES_PER_BLOCK_INTERVAL: IntGauge =
+        register_int_gauge!(format!("{}_stats_template_updates_per_block_interval", PREFIX), "Number of template updates received between the last two blocks.").unwrap();
+
     /// Number of conflicting transaction sets between template and block.
     pub static ref STAT_CONFLICTING_TRANSACTION_SETS: IntCounter =
         register_int_counter!(format!("{}_stats_conflicting_transaction_sets", PREFIX), "Total number of processed conflicting transaction sets.").unwrap();
@@ -67,7 +111,19 @@ lazy_static! {
     pub static ref ERROR_PROCESSING: IntCounter =
         register_int_counter!(format!("{}_error_processing", PREFIX), "Number of processing errors.").unwrap();
 
+    /// Number of ZMQ errors. Can be used for alerting.
+    pub static ref ERROR_ZMQ: IntCounter =
+        register_int_counter!(format!("{}_error_zmq", PREFIX), "Number of ZMQ errors.").unwrap();
+
     /// Number of database connection pool errors. Can be used for alerting.
     pub static ref ERROR_DBPOOL: IntCounter =
         register_int_counter!(format!("{}_error_db_pool", PREFIX), "Number of database connection pool errors.").unwrap();
+
+    /// Number of template archive errors.
+    pub static ref ERROR_TEMPLATE_ARCHIVE: IntCounter =
+        register_int_counter!(format!("{}_error_template_archive", PREFIX), "Number of errors while writing to or cleaning up the template archive.").unwrap();
+
+    /// Number of retry queue errors.
+    pub static ref ERROR_RETRY_QUEUE: IntCounter =
+        register_int_counter!(format!("{}_error_retry_queue", PREFIX), "Number of errors while persisting or retrying the processed blocks in the retry queue.").unwrap();
 }
@@ -3,10 +3,16 @@ use std::sync::{Arc, Mutex};
 
 use bitcoin_pool_identification::Pool;
 use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{hash_types::Txid, Amount, Transaction};
+use miningpool_observer_shared::bitcoincore_rpc::json::GetMempoolEntryResult;
+use miningpool_observer_shared::model as shared_model;
+use serde::{Deserialize, Serialize};
+
+use crate::seen_txids::SeenTxids;
 
 pub type SharedPoolIDData = Arc<Mutex<Vec<Pool>>>;
+pub type SharedSeenTxids = Arc<Mutex<SeenTxids>>;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TxInfo {
     pub txid: Txid,
     pub tx: Transaction,
@@ -59,3 +65,158 @@ pub struct TemplateTxData {
     pub txid_to_txinfo_map: HashMap<Txid, TxInfo>,
     pub txinfos: Vec<TxInfo>,
 }
+
+/// How similar the transactions in a template and a block are. Both values
+/// range from 0.0 (nothing shared) to 1.0 (the same transactions).
+#[derive(Debug, PartialEq)]
+pub struct TxSetSimilarity {
+    /// Jaccard index of the template and block transactions.
+    pub equality: f32,
+    /// Jaccard index with each transaction weighted by its fee.
+    pub fee_weighted_equality: f32,
+    /// Jaccard index with each transaction weighted by its weight.
+    pub weight_weighted_equality: f32,
+}
+
+/// The transactions only in the block that were likely accelerated, i.e.
+/// included by the pool for an out-of-band payment.
+#[derive(Debug, Default, PartialEq)]
+pub struct AccelerationEstimate {
+    pub txids: HashSet<Txid>,
+    /// Estimated fees the pool gave up by including the packages of the
+    /// accelerated transactions instead of packages paying the block's
+    /// cutoff feerate.
+    pub fee_forgone: Amount,
+}
+
+/// How well the packages in a block follow a descending feerate order.
+#[derive(Debug, PartialEq)]
+pub struct PackageOrdering {
+    /// Count of packages followed by a package paying a considerably higher
+    /// feerate.
+    pub out_of_order_pkgs: i32,
+    /// Share of the adjacent packages in feerate order. None if there are
+    /// less than two packages.
+    pub conformance: Option<f32>,
+}
+
+/// The fee difference between a block and its template split by where it
+/// comes from. The block fees minus the template fees equal
+/// `extra_tx_fees - missing_tx_fees + residual`.
+#[derive(Debug, PartialEq)]
+pub struct FeeDelta {
+    /// Fees of the template transactions missing from the block. Conflicting
+    /// transactions aren't counted.
+    pub missing_tx_fees: i64,
+    /// Fees of the extra transactions in the block. Conflicting transactions
+    /// aren't counted.
+    pub extra_tx_fees: i64,
+    /// The remaining difference. Caused by conflicting transactions and
+    /// replacements.
+    pub residual: i64,
+}
+
+/// The sanctioned addresses and UTXOs a block and template are checked against.
+pub struct SanctionedData {
+    pub addresses: HashSet<String>,
+    pub utxos: Vec<shared_model::SanctionedUtxo>,
+}
+
+/// The mempool information of a transaction only in the template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MempoolEntryInfo {
+    pub age_seconds: i32,
+    pub ancestor_count: i32,
+    pub ancestor_vsize: i32,
+    pub descendant_count: i32,
+    pub descendant_vsize: i32,
+    /// Difference between the modified and the base fee in sat.
+    pub fee_delta: i64,
+}
+
+impl MempoolEntryInfo {
+    pub fn new(entry: &GetMempoolEntryResult, now: i64) -> MempoolEntryInfo {
+        MempoolEntryInfo {
+            age_seconds: (now - entry.time as i64) as i32,
+            ancestor_count: entry.ancestor_count as i32,
+            ancestor_vsize: entry.ancestor_size as i32,
+            descendant_count: entry.descendant_count as i32,
+            descendant_vsize: entry.descendant_size as i32,
+            fee_delta: entry.fees.modified.to_sat() as i64 - entry.fees.base.to_sat() as i64,
+        }
+    }
+}
+
+/// Information about the template and block used to classify why a
+/// transaction only in the template might be missing from the block.
+pub struct MissingTxContext {
+    pub mempool_entries: HashMap<Txid, MempoolEntryInfo>,
+    /// Txids (in display order) of template transactions conflicting with
+    /// a block transaction.
+    pub conflicting_txids: HashSet<Vec<u8>>,
+    /// Feerate of the template package each transaction is part of.
+    pub txid_to_pkg_feerate: HashMap<Txid, f32>,
+    /// Feerate of the lowest package in the block. None if the block only
+    /// contains the coinbase transaction.
+    pub lowest_block_pkg_feerate: Option<f32>,
+}
+
+/// What our node knows about the transactions only in the block. The txids
+/// are in display order.
+pub struct ExtraTxContext {
+    /// The UNIX timestamp the transactions were first seen in our mempool or
+    /// in one of our templates before the block. Transactions never seen are
+    /// missing.
+    pub first_seen: HashMap<Vec<u8>, u64>,
+    /// The package feerates of the transactions in the block.
+    pub pkg_feerates: HashMap<Vec<u8>, f32>,
+    /// Transactions paying to or spending from an output script of the
+    /// coinbase transaction.
+    pub coinbase_script_txids: HashSet<Vec<u8>>,
+    /// The reasons our node's default policy rejects the never seen
+    /// transactions with, see `policy::reject_reason()`. Transactions seen
+    /// before the block were accepted by our node and are missing.
+    pub reject_reasons: HashMap<Vec<u8>, String>,
+}
+
+/// Everything that is written to the database for a processed block. As the
+/// id of a block is only known once it has been inserted into the database,
+/// the rows referencing the block are build with a block_id of 0 and updated
+/// with set_block_id() later. The rows are sorted to make the output of the
+/// processing deterministic.
+#[derive(Serialize, Deserialize)]
+pub struct ProcessedBlock {
+    pub block: shared_model::NewBlock,
+    pub transactions: Vec<shared_model::Transaction>,
+    pub transactions_only_in_block: Vec<shared_model::TransactionOnlyInBlock>,
+    pub transactions_only_in_template: Vec<shared_model::TransactionOnlyInTemplate>,
+    pub sanctioned_transaction_infos: Vec<shared_model::SanctionedTransactionInfo>,
+    pub conflicting_transactions: Vec<shared_model::ConflictingTransaction>,
+    pub newly_sanctioned_utxos: Vec<shared_model::SanctionedUtxo>,
+    pub debug_template_selection_infos: Vec<shared_model::DebugTemplateSelectionInfo>,
+    #[serde(default)]
+    pub coinbase_info: Option<shared_model::CoinbaseInfo>,
+}
+
+impl ProcessedBlock {
+    pub fn set_block_id(&mut self, block_id: i64) {
+        self.transactions_only_in_block
+            .iter_mut()
+            .for_each(|t| t.block_id = block_id);
+        self.transactions_only_in_template
+            .iter_mut()
+            .for_each(|t| t.block_id = block_id);
+        self.sanctioned_transaction_infos
+            .iter_mut()
+            .for_each(|t| t.block_id = block_id);
+        self.conflicting_transactions
+            .iter_mut()
+            .for_each(|t| t.block_id = block_id);
+        self.debug_template_selection_infos
+            .iter_mut()
+            .for_each(|t| t.block_id = block_id);
+        if let Some(coinbase_info) = self.coinbase_info.as_mut() {
+            coinbase_info.block_id = block_id;
+        }
+    }
+}
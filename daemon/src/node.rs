@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
+    block, Block, BlockHash, Transaction, Txid,
+};
+use miningpool_observer_shared::bitcoincore_rpc::json::{
+    GetBlockTemplateModes, GetBlockTemplateResult, GetBlockTemplateRules, GetBlockTxFeesResult,
+    GetMempoolEntryResult, ScanTxOutRequest, ScanTxOutResult,
+};
+use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+use miningpool_observer_shared::bitcoincore_rpc::{Client, Error, RpcApi};
+
+/// The Bitcoin Core calls used by the daemon. Implemented by the RPC client
+/// and, for tests, by the in-memory MockNode.
+pub trait Node {
+    /// Requests a new block template with the SegWit and Taproot rules.
+    fn get_block_template(&self) -> Result<GetBlockTemplateResult, Error>;
+
+    /// Requests a block template via BIP22 long-polling. With a longpollid,
+    /// the call only returns once Bitcoin Core has a new template, i.e. when
+    /// the chain tip changed or the transactions in the mempool changed.
+    /// Without a longpollid, the current template is returned immediately.
+    fn get_block_template_longpoll(
+        &self,
+        longpollid: Option<&str>,
+    ) -> Result<GetBlockTemplateResult, Error>;
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error>;
+
+    fn get_block_header(&self, hash: &BlockHash) -> Result<block::Header, Error>;
+
+    fn get_best_block_hash(&self) -> Result<BlockHash, Error>;
+
+    /// The txids and fees of the transactions in the block.
+    fn get_block_txid_fee(&self, hash: &BlockHash) -> Result<GetBlockTxFeesResult, Error>;
+
+    /// The entries of all transactions in the mempool.
+    fn get_raw_mempool_verbose(&self) -> Result<HashMap<Txid, GetMempoolEntryResult>, Error>;
+
+    /// The txids of all transactions in the mempool.
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error>;
+
+    fn scan_tx_out_set_blocking(
+        &self,
+        descriptors: &[ScanTxOutRequest],
+    ) -> Result<ScanTxOutResult, Error>;
+
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Transaction, Error>;
+}
+
+impl Node for Client {
+    fn get_block_template(&self) -> Result<GetBlockTemplateResult, Error> {
+        RpcApi::get_block_template(
+            self,
+            GetBlockTemplateModes::Template,
+            &[
+                GetBlockTemplateRules::SegWit,
+                GetBlockTemplateRules::Taproot,
+            ],
+            &[],
+        )
+    }
+
+    fn get_block_template_longpoll(
+        &self,
+        longpollid: Option<&str>,
+    ) -> Result<GetBlockTemplateResult, Error> {
+        let mut request = serde_json::json!({
+            "mode": "template",
+            "rules": ["segwit", "taproot"],
+            "capabilities": [],
+        });
+        if let Some(longpollid) = longpollid {
+            request["longpollid"] = serde_json::Value::String(longpollid.to_string());
+        }
+        self.call("getblocktemplate", &[request])
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        RpcApi::get_block(self, hash)
+    }
+
+    fn get_block_header(&self, hash: &BlockHash) -> Result<block::Header, Error> {
+        RpcApi::get_block_header(self, hash)
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
+        RpcApi::get_best_block_hash(self)
+    }
+
+    fn get_block_txid_fee(&self, hash: &BlockHash) -> Result<GetBlockTxFeesResult, Error> {
+        RpcApi::get_block_txid_fee(self, hash)
+    }
+
+    fn get_raw_mempool_verbose(&self) -> Result<HashMap<Txid, GetMempoolEntryResult>, Error> {
+        RpcApi::get_raw_mempool_verbose(self)
+    }
+
+    fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        RpcApi::get_raw_mempool(self)
+    }
+
+    fn scan_tx_out_set_blocking(
+        &self,
+        descriptors: &[ScanTxOutRequest],
+    ) -> Result<ScanTxOutResult, Error> {
+        RpcApi::scan_tx_out_set_blocking(self, descriptors)
+    }
+
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+        RpcApi::get_raw_transaction(self, txid, None)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
+        absolute::LockTime, hashes::Hash, transaction, Amount, CompactTarget, ScriptBuf, TxIn,
+        TxMerkleNode, TxOut,
+    };
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+
+    /// A block building on prev_blockhash with only a coinbase transaction.
+    pub fn test_block(prev_blockhash: BlockHash, time: u32) -> Block {
+        Block {
+            header: block::Header {
+                version: block::Version::from_consensus(0x20000000),
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time,
+                bits: CompactTarget::from_consensus(0x17034219),
+                nonce: 0,
+            },
+            txdata: vec![Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                // The time makes the coinbase transactions unique.
+                input: vec![TxIn {
+                    script_sig: ScriptBuf::from_bytes(time.to_le_bytes().to_vec()),
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(312500000),
+                    script_pubkey: ScriptBuf::new(),
+                }],
+            }],
+        }
+    }
+
+    /// A scriptable in-memory node. The templates are returned in the order
+    /// they were added. Blocks are known once they are connected. Connecting
+    /// a block at a height below the chain tip reorgs the active chain.
+    #[derive(Default)]
+    pub struct MockNode {
+        templates: RefCell<VecDeque<GetBlockTemplateResult>>,
+        blocks: HashMap<BlockHash, (Block, GetBlockTxFeesResult)>,
+        active_chain: BTreeMap<u64, BlockHash>,
+        mempool: HashMap<Txid, GetMempoolEntryResult>,
+    }
+
+    fn not_found(what: &str) -> Error {
+        Error::ReturnedError(format!("{} not found", what))
+    }
+
+    impl MockNode {
+        pub fn add_template(&self, template: GetBlockTemplateResult) {
+            self.templates.borrow_mut().push_back(template);
+        }
+
+        /// Connects the block at the height. All blocks at and above the
+        /// height are disconnected from the active chain first.
+        pub fn connect_block(&mut self, height: u64, block: Block) {
+            let hash = block.block_hash();
+            let fees = serde_json::from_value(serde_json::json!({
+                "hash": hash.to_string(),
+                "tx": block
+                    .txdata
+                    .iter()
+                    .map(|tx| serde_json::json!({ "txid": tx.compute_txid().to_string() }))
+                    .collect::<Vec<serde_json::Value>>(),
+            }))
+            .expect("the block fees should be deserializable");
+            self.active_chain.split_off(&height);
+            self.active_chain.insert(height, hash);
+            self.blocks.insert(hash, (block, fees));
+        }
+
+        pub fn add_mempool_entry(&mut self, txid: Txid, entry: GetMempoolEntryResult) {
+            self.mempool.insert(txid, entry);
+        }
+    }
+
+    impl Node for MockNode {
+        fn get_block_template(&self) -> Result<GetBlockTemplateResult, Error> {
+            self.templates
+                .borrow_mut()
+                .pop_front()
+                .ok_or_else(|| not_found("template"))
+        }
+
+        fn get_block_template_longpoll(
+            &self,
+            _longpollid: Option<&str>,
+        ) -> Result<GetBlockTemplateResult, Error> {
+            self.get_block_template()
+        }
+
+        fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+            self.blocks
+                .get(hash)
+                .map(|(block, _)| block.clone())
+                .ok_or_else(|| not_found("block"))
+        }
+
+        fn get_block_header(&self, hash: &BlockHash) -> Result<block::Header, Error> {
+            self.get_block(hash).map(|block| block.header)
+        }
+
+        fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
+            self.active_chain
+                .values()
+                .next_back()
+                .copied()
+                .ok_or_else(|| not_found("chain tip"))
+        }
+
+        fn get_block_txid_fee(&self, hash: &BlockHash) -> Result<GetBlockTxFeesResult, Error> {
+            self.blocks
+                .get(hash)
+                .map(|(_, fees)| fees.clone())
+                .ok_or_else(|| not_found("block"))
+        }
+
+        fn get_raw_mempool_verbose(&self) -> Result<HashMap<Txid, GetMempoolEntryResult>, Error> {
+            Ok(self.mempool.clone())
+        }
+
+        fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+            Ok(self.mempool.keys().copied().collect())
+        }
+
+        fn scan_tx_out_set_blocking(
+            &self,
+            _descriptors: &[ScanTxOutRequest],
+        ) -> Result<ScanTxOutResult, Error> {
+            Err(Error::ReturnedError(
+                "scantxoutset is not supported by the mock node".to_string(),
+            ))
+        }
+
+        fn get_raw_transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+            self.active_chain
+                .values()
+                .filter_map(|hash| self.blocks.get(hash))
+                .flat_map(|(block, _)| block.txdata.iter())
+                .find(|tx| tx.compute_txid() == *txid)
+                .cloned()
+                .ok_or_else(|| not_found("transaction"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{test_block, MockNode};
+    use super::*;
+    use crate::template_history::tests::{template, txid};
+    use miningpool_observer_shared::bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_mock_node() {
+        let mut node = MockNode::default();
+        assert!(node.get_best_block_hash().is_err());
+        assert!(node.get_block_template().is_err());
+
+        let a = test_block(BlockHash::all_zeros(), 1700000000);
+        let b = test_block(a.block_hash(), 1700000010);
+        node.connect_block(10, a.clone());
+        node.connect_block(11, b.clone());
+        assert_eq!(node.get_best_block_hash().unwrap(), b.block_hash());
+        assert_eq!(
+            node.get_block_header(&b.block_hash())
+                .unwrap()
+                .prev_blockhash,
+            a.block_hash()
+        );
+        let coinbase = b.txdata[0].compute_txid();
+        assert_eq!(node.get_raw_transaction(&coinbase).unwrap(), b.txdata[0]);
+
+        // connecting b2 at height 11 disconnects b
+        let b2 = test_block(a.block_hash(), 1700000011);
+        node.connect_block(11, b2.clone());
+        assert_eq!(node.get_best_block_hash().unwrap(), b2.block_hash());
+        assert_eq!(node.get_block(&b.block_hash()).unwrap(), b);
+        assert!(node.get_raw_transaction(&coinbase).is_err());
+
+        let t1 = template(&b2.block_hash().to_string(), 1700000020, &[txid(1)]);
+        let t2 = template(&b2.block_hash().to_string(), 1700000030, &[txid(2)]);
+        node.add_template(t1.clone());
+        node.add_template(t2.clone());
+        assert_eq!(node.get_block_template().unwrap(), t1);
+        assert_eq!(node.get_block_template_longpoll(None).unwrap(), t2);
+
+        let mempool_txid: Txid = txid(1).parse().unwrap();
+        assert!(node.get_raw_mempool_verbose().unwrap().is_empty());
+        let entry: GetMempoolEntryResult = serde_json::from_value(serde_json::json!({
+            "vsize": 141,
+            "weight": 561,
+            "time": 1700000015,
+            "height": 11,
+            "descendantcount": 1,
+            "descendantsize": 141,
+            "ancestorcount": 1,
+            "ancestorsize": 141,
+            "wtxid": txid(1),
+            "fees": { "base": 0.00001, "modified": 0.00001, "ancestor": 0.00001, "descendant": 0.00001 },
+            "depends": [],
+            "spentby": [],
+            "bip125-replaceable": false,
+        }))
+        .unwrap();
+        node.add_mempool_entry(mempool_txid, entry.clone());
+        assert_eq!(
+            node.get_raw_mempool_verbose().unwrap().get(&mempool_txid),
+            Some(&entry)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
+    Amount, Script, Transaction, WitnessVersion,
+};
+
+/// Bitcoin Core's MAX_STANDARD_TX_WEIGHT.
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// Bitcoin Core's MIN_STANDARD_TX_NONWITNESS_SIZE.
+const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+/// Bitcoin Core's MAX_STANDARD_SCRIPTSIG_SIZE.
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+/// Bitcoin Core's TX_MAX_STANDARD_VERSION.
+const MAX_STANDARD_TX_VERSION: i32 = 3;
+/// Bitcoin Core's default -datacarriersize. The limit applies to the sum of
+/// all OP_RETURN output scripts.
+const MAX_OP_RETURN_RELAY: usize = 100_000;
+/// Bitcoin Core's MAX_DUST_OUTPUTS_PER_TX.
+const MAX_DUST_OUTPUTS_PER_TX: usize = 1;
+/// Bare multisig scripts with more keys are non-standard.
+const MAX_STANDARD_BARE_MULTISIG_KEYS: usize = 3;
+
+/// If the output script is of a standard type: P2PK, P2PKH, P2SH, bare
+/// multisig with up to three keys, OP_RETURN, P2WPKH, P2WSH or a witness
+/// program of a later version like P2TR and P2A.
+fn is_standard_output_script(script: &Script) -> bool {
+    match script.witness_version() {
+        Some(WitnessVersion::V0) => script.is_p2wpkh() || script.is_p2wsh(),
+        Some(_) => true,
+        None => {
+            script.is_p2pk()
+                || script.is_p2pkh()
+                || script.is_p2sh()
+                || script.is_op_return()
+                || (script.is_multisig()
+                    && script.instructions().count() - 3 <= MAX_STANDARD_BARE_MULTISIG_KEYS)
+        }
+    }
+}
+
+/// The reason Bitcoin Core's default policy rejects the transaction for.
+/// Only the rules checked without the outputs the transaction spends are
+/// applied, like IsStandardTx() and the ephemeral dust check do. The reasons
+/// are the ones testmempoolaccept reports. Returns None if the transaction
+/// passes these checks.
+///
+/// A confirmed transaction can't be tested with testmempoolaccept anymore
+/// as it is already known to the node. Checks that need the spent outputs,
+/// like the standardness of the inputs, aren't done.
+pub fn reject_reason(tx: &Transaction, fee: Amount) -> Option<&'static str> {
+    if tx.version.0 < 1 || tx.version.0 > MAX_STANDARD_TX_VERSION {
+        return Some("version");
+    }
+    if tx.weight().to_wu() > MAX_STANDARD_TX_WEIGHT {
+        return Some("tx-size");
+    }
+    for input in tx.input.iter() {
+        if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            return Some("scriptsig-size");
+        }
+        if !input.script_sig.is_push_only() {
+            return Some("scriptsig-not-pushonly");
+        }
+    }
+
+    let mut op_return_size: usize = 0;
+    let mut dust_outputs: usize = 0;
+    for output in tx.output.iter() {
+        if !is_standard_output_script(&output.script_pubkey) {
+            return Some("scriptpubkey");
+        }
+        if output.script_pubkey.is_op_return() {
+            op_return_size += output.script_pubkey.len();
+        } else if output.value < output.script_pubkey.minimal_non_dust() {
+            dust_outputs += 1;
+        }
+    }
+    if op_return_size > MAX_OP_RETURN_RELAY {
+        return Some("datacarrier");
+    }
+    if dust_outputs > MAX_DUST_OUTPUTS_PER_TX {
+        return Some("dust");
+    }
+
+    if tx.base_size() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        return Some("tx-size-small");
+    }
+    // a single dust output is only allowed in a transaction without fees
+    if dust_outputs > 0 && fee > Amount::ZERO {
+        return Some("dust");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
+        absolute::LockTime, hashes::Hash, script::PushBytes, transaction::Version, OutPoint,
+        ScriptBuf, Sequence, TxIn, TxOut, Txid, WPubkeyHash, Witness,
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_reject_reason() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let tx =
+            |version: i32, script_sig: ScriptBuf, outputs: Vec<(u64, ScriptBuf)>| Transaction {
+                version: Version(version),
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                    script_sig,
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                }],
+                output: outputs
+                    .into_iter()
+                    .map(|(value, script_pubkey)| TxOut {
+                        value: Amount::from_sat(value),
+                        script_pubkey,
+                    })
+                    .collect(),
+            };
+        let fee = Amount::from_sat(1000);
+
+        println!("A transaction that isn't confirmed yet and pays to P2WPKH is standard");
+        assert_eq!(
+            reject_reason(
+                &tx(2, ScriptBuf::new(), vec![(10_000, p2wpkh.clone())]),
+                fee
+            ),
+            None
+        );
+        println!("Outputs to an unknown script are rejected");
+        assert_eq!(
+            reject_reason(
+                &tx(
+                    2,
+                    ScriptBuf::new(),
+                    vec![(10_000, ScriptBuf::from_bytes(vec![0x51, 0x87]))]
+                ),
+                fee
+            ),
+            Some("scriptpubkey")
+        );
+        assert_eq!(
+            reject_reason(
+                &tx(4, ScriptBuf::new(), vec![(10_000, p2wpkh.clone())]),
+                fee
+            ),
+            Some("version")
+        );
+        assert_eq!(
+            reject_reason(
+                &tx(
+                    2,
+                    ScriptBuf::from_bytes(vec![0x51, 0x87]),
+                    vec![(10_000, p2wpkh.clone())]
+                ),
+                fee
+            ),
+            Some("scriptsig-not-pushonly")
+        );
+        println!("A single dust output is only allowed without fees");
+        assert_eq!(
+            reject_reason(&tx(3, ScriptBuf::new(), vec![(0, p2wpkh.clone())]), fee),
+            Some("dust")
+        );
+        assert_eq!(
+            reject_reason(
+                &tx(3, ScriptBuf::new(), vec![(0, p2wpkh.clone())]),
+                Amount::ZERO
+            ),
+            None
+        );
+        assert_eq!(
+            reject_reason(
+                &tx(
+                    3,
+                    ScriptBuf::new(),
+                    vec![(0, p2wpkh.clone()), (0, p2wpkh.clone())]
+                ),
+                Amount::ZERO
+            ),
+            Some("dust")
+        );
+        println!("OP_RETURN outputs aren't dust");
+        let op_return =
+            ScriptBuf::new_op_return(<&PushBytes>::try_from([0u8; 80].as_slice()).unwrap());
+        assert_eq!(
+            reject_reason(
+                &tx(2, ScriptBuf::new(), vec![(10_000, p2wpkh), (0, op_return)]),
+                fee
+            ),
+            None
+        );
+    }
+}
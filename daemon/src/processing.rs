@@ -1,13 +1,19 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 
 use crate::metrics;
-use crate::model::{BlockTxData, SharedPoolIDData, TemplateTxData, TxInfo, TxPackage};
+use crate::model::{
+    AccelerationEstimate, BlockTxData, ExtraTxContext, FeeDelta, MempoolEntryInfo,
+    MissingTxContext, PackageOrdering, ProcessedBlock, SanctionedData, SharedPoolIDData,
+    TemplateTxData, TxInfo, TxPackage, TxSetSimilarity,
+};
+use crate::template_history::TemplateHistory;
 
 use miningpool_observer_shared::bitcoincore_rpc::json::{
     GetBlockTemplateResult, GetBlockTxFeesResult,
 };
 use miningpool_observer_shared::chrono;
+use miningpool_observer_shared::config::Deployment;
 use miningpool_observer_shared::{model as shared_model, tags};
 
 use bitcoin_pool_identification::{IdentificationMethod, PoolIdentification};
@@ -21,12 +27,21 @@ use rawtx_rs::{input::InputInscriptionDetection, input::InputType, output::Outpu
 use miningpool_observer_shared::bitcoincore_rpc::bitcoin;
 use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
     hash_types::Txid, hashes::Hash, locktime::absolute::LockTime, network::Network, Address,
-    Amount, Transaction,
+    Amount, BlockHash, Transaction,
 };
 
 pub const LOG_TARGET_PROCESSING: &str = "processing";
 
 const VERSION_BIT_TAPROOT: u8 = 2;
+const MAX_PACKAGE_SELECTION_CLUSTER_SIZE: usize = 500;
+/// OP_RETURN, push of 36 bytes and the BIP141 witness commitment header.
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+const RSK_COMMITMENT_PREFIX: &[u8] = b"RSKBLOCK:";
+const AUXPOW_COMMITMENT_PREFIX: [u8; 4] = [0xfa, 0xbe, 0x6d, 0x6d];
+const MERGED_MINING_COMMITMENT_LEN: usize = 32;
+/// Shorter runs of printable characters in the coinbase scriptSig are
+/// likely part of the height or the extranonce and not of the tag.
+const MIN_COINBASE_TAG_RUN_LEN: usize = 4;
 
 fn in_and_outputs_to_strings(raw_tx_info: &RawTxInfo) -> (Vec<String>, Vec<String>) {
     let mut output_type_counts: HashMap<OutputType, u32> = HashMap::new();
@@ -332,57 +347,348 @@ fn get_pool_info_or_default(
     }
 }
 
-/// Builds a vector of transaction packages. These Packages are similar to what
-/// Bitcoin Core (currently) puts into blocks.
-pub fn build_packages(txns: &[TxInfo]) -> Vec<TxPackage> {
-    let txids_in_txns: HashSet<Txid> = txns.iter().map(|ti| ti.tx.compute_txid()).collect();
-    let mut packages: Vec<TxPackage> = vec![];
-
-    // Bitcoin Core places transactions that belong to a package next to each other.
-    // We consider locality when building packages.
-    // Bitcoin Core currently uses the best ancestor set as a package. See
-    // https://gist.github.com/murchandamus/5cb413fe9f26dbce57abfd344ebbfaf2#file-asb-vs-csb-png
-
-    let mut package_txns: Vec<TxInfo> = vec![];
-    // by iterating in reverse order, we know about the children first
-    // This is helpful, as children reference their parents and we can directly link them
-    for tx_info in txns.iter().rev() {
-        if package_txns.is_empty() {
-            package_txns.push(tx_info.clone());
-        } else {
-            let parent_txids: HashSet<Txid> =
-                package_txns.iter().fold(HashSet::new(), |mut txids, tx| {
-                    txids.extend(&tx.prev_output_txids());
-                    txids
-                });
-            if parent_txids.contains(&tx_info.tx.compute_txid()) {
-                // belongs to the same package if the tx is a parent of a tx in the package
-                package_txns.push(tx_info.clone());
-            } else if tx_info
+/// Builds the row for a block that couldn't be compared to a template.
+/// Without a seen_time, the block header time is used. Returns None if the
+/// height can't be read from the coinbase.
+pub fn build_uncompared_block(
+    block: &bitcoin::Block,
+    reason: shared_model::UncomparedReason,
+    seen_time: Option<chrono::NaiveDateTime>,
+    pools: SharedPoolIDData,
+    network: Network,
+) -> Option<shared_model::UncomparedBlock> {
+    let height = block.bip34_block_height().ok()?;
+    let (pool_name, _, _) =
+        get_pool_info_or_default(block.identify_pool(network, &pools.lock().unwrap()));
+    let mut hash = block.block_hash().to_byte_array().to_vec();
+    hash.reverse();
+    let mut prev_hash = block.header.prev_blockhash.to_byte_array().to_vec();
+    prev_hash.reverse();
+    let block_time = chrono::DateTime::from_timestamp(block.header.time as i64, 0)
+        .expect("block timestamp out of range")
+        .naive_utc();
+    Some(shared_model::UncomparedBlock {
+        hash,
+        prev_hash,
+        height: height as i32,
+        block_time,
+        seen_time: seen_time.unwrap_or(block_time),
+        pool_name,
+        reason: reason as i32,
+    })
+}
+
+/// Returns the 32 bytes following the first occurrence of the prefix.
+fn find_commitment(haystack: &[u8], prefix: &[u8]) -> Option<Vec<u8>> {
+    let start = haystack.windows(prefix.len()).position(|w| w == prefix)? + prefix.len();
+    haystack
+        .get(start..start + MERGED_MINING_COMMITMENT_LEN)
+        .map(|c| c.to_vec())
+}
+
+/// Joins the runs of printable ASCII characters in the pushes of the
+/// coinbase scriptSig. The push opcodes are skipped as their length byte
+/// might be a printable character too.
+fn coinbase_tag(script_sig: &bitcoin::Script) -> String {
+    script_sig
+        .instructions()
+        .map_while(Result::ok)
+        .filter_map(|instruction| match instruction {
+            bitcoin::script::Instruction::PushBytes(push) => Some(push.as_bytes().to_vec()),
+            _ => None,
+        })
+        .flat_map(|data| {
+            data.split(|b| !(0x20..=0x7e).contains(b))
+                .map(|run| run.to_vec())
+                .collect::<Vec<Vec<u8>>>()
+        })
+        .filter(|run| run.len() >= MIN_COINBASE_TAG_RUN_LEN)
+        .map(|run| String::from_utf8_lossy(&run).trim().to_string())
+        .filter(|run| !run.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Estimates the extranonce length as the length of the last push in the
+/// coinbase scriptSig after the BIP34 height that isn't printable text or
+/// an AuxPoW commitment. Pools usually push the extranonce as a single
+/// random looking blob.
+fn estimate_extranonce_len(script_sig: &bitcoin::Script) -> Option<i32> {
+    script_sig
+        .instructions()
+        .map_while(Result::ok)
+        .skip(1)
+        .filter_map(|instruction| match instruction {
+            bitcoin::script::Instruction::PushBytes(push) => Some(push.as_bytes().to_vec()),
+            _ => None,
+        })
+        .filter(|data| {
+            !data.is_empty()
+                && !data.iter().all(|b| (0x20..=0x7e).contains(b))
+                && find_commitment(data, &AUXPOW_COMMITMENT_PREFIX).is_none()
+        })
+        .last()
+        .map(|data| data.len() as i32)
+}
+
+/// Parses the coinbase transaction of the block. Returns None if the block
+/// has no coinbase transaction.
+pub fn build_coinbase_info(
+    block: &bitcoin::Block,
+    network: Network,
+) -> Option<shared_model::CoinbaseInfo> {
+    let coinbase = block.coinbase()?;
+    let script_sig = &coinbase.input.first()?.script_sig;
+
+    let witness_commitment = coinbase
+        .output
+        .iter()
+        .rev()
+        .map(|output| output.script_pubkey.as_bytes())
+        .find(|script| {
+            script.len() >= WITNESS_COMMITMENT_PREFIX.len() + MERGED_MINING_COMMITMENT_LEN
+                && script.starts_with(&WITNESS_COMMITMENT_PREFIX)
+        })
+        .map(|script| {
+            script[WITNESS_COMMITMENT_PREFIX.len()
+                ..WITNESS_COMMITMENT_PREFIX.len() + MERGED_MINING_COMMITMENT_LEN]
+                .to_vec()
+        });
+
+    let mut merged_mining: Vec<(shared_model::MergedMiningChain, Vec<u8>)> = coinbase
+        .output
+        .iter()
+        .filter(|output| output.script_pubkey.is_op_return())
+        .filter_map(|output| {
+            find_commitment(output.script_pubkey.as_bytes(), RSK_COMMITMENT_PREFIX)
+                .map(|c| (shared_model::MergedMiningChain::Rsk, c))
+        })
+        .collect();
+    if let Some(c) = find_commitment(script_sig.as_bytes(), &AUXPOW_COMMITMENT_PREFIX) {
+        merged_mining.push((shared_model::MergedMiningChain::AuxPow, c));
+    }
+
+    let payouts: Vec<&bitcoin::TxOut> = coinbase
+        .output
+        .iter()
+        .filter(|output| !output.script_pubkey.is_op_return())
+        .collect();
+
+    Some(shared_model::CoinbaseInfo {
+        // The block_id is set once the block is inserted into the database.
+        block_id: 0,
+        script_sig: script_sig.to_bytes(),
+        script_sig_tag: coinbase_tag(script_sig),
+        bip34_height: block.bip34_block_height().ok().map(|h| h as i32),
+        extranonce_len: estimate_extranonce_len(script_sig),
+        witness_commitment,
+        merged_mining_chains: merged_mining
+            .iter()
+            .map(|(chain, _)| *chain as i32)
+            .collect(),
+        merged_mining_commitments: merged_mining.into_iter().map(|(_, c)| c).collect(),
+        payout_outputs: payouts.len() as i32,
+        payout_scripts: payouts.iter().map(|o| o.script_pubkey.to_bytes()).collect(),
+        payout_addresses: payouts
+            .iter()
+            .map(|o| {
+                Address::from_script(&o.script_pubkey, network)
+                    .map(|a| a.to_string())
+                    .unwrap_or_default()
+            })
+            .collect(),
+        payout_values: payouts.iter().map(|o| o.value.to_sat() as i64).collect(),
+    })
+}
+
+/// Maps each template transaction to its parents in the template. The
+/// parents are taken from the `depends` field of getblocktemplate, which
+/// lists the 1-based indices of the template transactions a transaction
+/// spends from.
+pub fn template_tx_parents(template: &GetBlockTemplateResult) -> HashMap<Txid, HashSet<Txid>> {
+    template
+        .transactions
+        .iter()
+        .map(|tx| {
+            let parents = tx
+                .depends
+                .iter()
+                .filter_map(|i| template.transactions.get((*i as usize).checked_sub(1)?))
+                .map(|parent| parent.txid)
+                .collect();
+            (tx.txid, parents)
+        })
+        .collect()
+}
+
+/// Maps each transaction to the transactions in `txns` it spends from.
+pub fn in_set_tx_parents(txns: &[TxInfo]) -> HashMap<Txid, HashSet<Txid>> {
+    let txids: HashSet<Txid> = txns.iter().map(|tx_info| tx_info.txid).collect();
+    txns.iter()
+        .map(|tx_info| {
+            let parents = tx_info
                 .prev_output_txids()
+                .into_iter()
+                .filter(|txid| txids.contains(txid))
+                .collect();
+            (tx_info.txid, parents)
+        })
+        .collect()
+}
+
+/// Maps the transactions in the packages to the feerate of their package.
+pub fn txid_to_pkg_feerate(packages: &[TxPackage]) -> HashMap<Txid, f32> {
+    packages
+        .iter()
+        .flat_map(|p| {
+            let feerate = p.feerate();
+            p.txns.iter().map(move |t| (t.txid, feerate))
+        })
+        .collect()
+}
+
+/// Maps the transactions in the block to the feerate of the package they
+/// are part of.
+pub fn block_txid_to_pkg_feerate(
+    block: &bitcoin::Block,
+    block_tx_fees: &GetBlockTxFeesResult,
+) -> HashMap<Txid, f32> {
+    let block_tx_data = build_block_tx_data(block, block_tx_fees);
+    txid_to_pkg_feerate(&build_packages(
+        &block_tx_data.txinfos,
+        &in_set_tx_parents(&block_tx_data.txinfos),
+    ))
+}
+
+/// Builds the transaction packages the way Bitcoin Core's block assembler
+/// selects them: the transaction with the highest ancestor set feerate is
+/// picked together with its not yet picked ancestors. This repeats until all
+/// transactions are part of a package. The packages are returned in the
+/// order of their first transaction in `txns`.
+///
+/// The parents map each transaction to its direct parents. Only parents in
+/// `txns` are considered. As only related transactions influence each
+/// others ancestor set feerate, the selection is done per cluster of related
+/// transactions.
+pub fn build_packages(txns: &[TxInfo], parents: &HashMap<Txid, HashSet<Txid>>) -> Vec<TxPackage> {
+    let index: HashMap<Txid, usize> = txns
+        .iter()
+        .enumerate()
+        .map(|(i, tx_info)| (tx_info.txid, i))
+        .collect();
+    let parent_indices: Vec<Vec<usize>> = txns
+        .iter()
+        .map(|tx_info| match parents.get(&tx_info.txid) {
+            Some(parents) => parents
                 .iter()
-                .filter(|txid| txids_in_txns.contains(*txid))
-                .any(|txid| parent_txids.contains(txid))
-            {
-                // belongs to the same package if the transaction shares a parent with a
-                // transaction already in the package (if this parent is in the `txns` set of
-                // transactions)
-                package_txns.push(tx_info.clone());
-            } else {
-                packages.push(TxPackage {
-                    txns: package_txns.clone(),
-                });
-                package_txns.clear();
-                package_txns.push(tx_info.clone());
-            }
+                .filter_map(|parent| index.get(parent).copied())
+                .collect(),
+            None => vec![],
+        })
+        .collect();
+    let mut child_indices: Vec<Vec<usize>> = vec![vec![]; txns.len()];
+    for (child, parents) in parent_indices.iter().enumerate() {
+        for parent in parents {
+            child_indices[*parent].push(child);
         }
     }
-    if !package_txns.is_empty() {
-        packages.push(TxPackage {
-            txns: package_txns.clone(),
-        });
+
+    let mut ancestors: Vec<Option<HashSet<usize>>> = vec![None; txns.len()];
+    for i in 0..txns.len() {
+        collect_ancestors(i, &parent_indices, &mut ancestors);
+    }
+    let ancestors: Vec<HashSet<usize>> = ancestors.into_iter().map(|a| a.unwrap()).collect();
+
+    let mut packages: Vec<Vec<usize>> = vec![];
+    let mut clustered = vec![false; txns.len()];
+    for start in 0..txns.len() {
+        if clustered[start] {
+            continue;
+        }
+        // collect the cluster of transactions related to the start transaction
+        let mut cluster: Vec<usize> = vec![];
+        let mut stack = vec![start];
+        clustered[start] = true;
+        while let Some(i) = stack.pop() {
+            cluster.push(i);
+            for related in parent_indices[i].iter().chain(child_indices[i].iter()) {
+                if !clustered[*related] {
+                    clustered[*related] = true;
+                    stack.push(*related);
+                }
+            }
+        }
+        if cluster.len() == 1 {
+            packages.push(cluster);
+            continue;
+        }
+        // Mempool policy keeps clusters small. Blocks can contain larger
+        // clusters of transactions that were never in a mempool. To bound
+        // the processing time, these are treated as a single package.
+        if cluster.len() > MAX_PACKAGE_SELECTION_CLUSTER_SIZE {
+            cluster.sort_unstable();
+            packages.push(cluster);
+            continue;
+        }
+
+        let mut remaining: HashSet<usize> = cluster.into_iter().collect();
+        while !remaining.is_empty() {
+            let ancestor_set = |i: usize| -> Vec<usize> {
+                let mut set: Vec<usize> = ancestors[i]
+                    .iter()
+                    .filter(|a| remaining.contains(*a))
+                    .copied()
+                    .chain(std::iter::once(i))
+                    .collect();
+                set.sort_unstable();
+                set
+            };
+            let fee_and_vsize = |set: &[usize]| -> (u128, u128) {
+                set.iter().fold((0, 0), |(fee, vsize), i| {
+                    (
+                        fee + txns[*i].fee.to_sat() as u128,
+                        vsize + txns[*i].tx.vsize() as u128,
+                    )
+                })
+            };
+
+            let mut best: Option<(Vec<usize>, (u128, u128))> = None;
+            let mut candidates: Vec<usize> = remaining.iter().copied().collect();
+            candidates.sort_unstable();
+            for i in candidates {
+                let set = ancestor_set(i);
+                let (fee, vsize) = fee_and_vsize(&set);
+                let is_better = match &best {
+                    None => true,
+                    Some((best_set, (best_fee, best_vsize))) => {
+                        // Compare the feerates without dividing. On equal
+                        // feerates, the larger set is picked to keep
+                        // transactions paying the same feerate together.
+                        let a = fee * best_vsize;
+                        let b = best_fee * vsize;
+                        a > b || (a == b && set.len() > best_set.len())
+                    }
+                };
+                if is_better {
+                    best = Some((set, (fee, vsize)));
+                }
+            }
+
+            let (package, _) = best.expect("remaining transactions can't be empty here");
+            for i in package.iter() {
+                remaining.remove(i);
+            }
+            packages.push(package);
+        }
     }
-    packages.reverse();
+
+    packages.sort_by_key(|package| package[0]);
+    let packages: Vec<TxPackage> = packages
+        .into_iter()
+        .map(|package| TxPackage {
+            txns: package.iter().map(|i| txns[*i].clone()).collect(),
+        })
+        .collect();
 
     let total_tx: usize = packages.iter().map(|p| p.txns.len()).sum();
     assert_eq!(txns.len(), total_tx);
@@ -390,6 +696,29 @@ pub fn build_packages(txns: &[TxInfo]) -> Vec<TxPackage> {
     packages
 }
 
+/// Collects the in-set ancestors of the transaction at index i.
+fn collect_ancestors(
+    i: usize,
+    parent_indices: &[Vec<usize>],
+    ancestors: &mut Vec<Option<HashSet<usize>>>,
+) {
+    if ancestors[i].is_some() {
+        return;
+    }
+    // Mark the transaction as visited to not loop on invalid, cyclic input.
+    ancestors[i] = Some(HashSet::new());
+    let mut set: HashSet<usize> = HashSet::new();
+    for parent in parent_indices[i].iter() {
+        collect_ancestors(*parent, parent_indices, ancestors);
+        set.insert(*parent);
+        if let Some(parent_ancestors) = &ancestors[*parent] {
+            set.extend(parent_ancestors.iter().copied());
+        }
+    }
+    set.remove(&i);
+    ancestors[i] = Some(set);
+}
+
 pub fn build_transaction(
     reversed_txid: &[u8],
     tx_info: &TxInfo,
@@ -434,6 +763,7 @@ pub fn build_transactions_only_in_block(
     block_id: i64,
     txids_only_in_block: &HashSet<&Txid>,
     block_txid_to_txinfo_map: &HashMap<Txid, TxInfo>,
+    accelerated_txids: &HashSet<Txid>,
     transactions: &mut HashMap<Vec<u8>, shared_model::Transaction>,
     outpoint_to_sanctioned_utxo_map: &HashMap<(Vec<u8>, u32), &shared_model::SanctionedUtxo>,
     sanctioned_addresses: &HashSet<String>,
@@ -454,6 +784,10 @@ pub fn build_transactions_only_in_block(
             block_id,
             position: tx_info.pos,
             transaction_txid: txid_to_reverse.clone(),
+            // labeled with label_transactions_only_in_block() once the node
+            // was asked about the transactions
+            labels: vec![],
+            reject_reason: None,
         });
 
         match build_transaction(
@@ -463,7 +797,12 @@ pub fn build_transactions_only_in_block(
             outpoint_to_sanctioned_utxo_map,
             sanctioned_addresses,
         ) {
-            Ok(t) => add_to_transactions(&t, transactions),
+            Ok(mut t) => {
+                if accelerated_txids.contains(*txid) {
+                    t.tags.push(tags::TxTag::Accelerated as i32);
+                }
+                add_to_transactions(&t, transactions)
+            }
             Err(e) => {
                 log_processing_error(&format!(
                     "Could not build transaction {} in build_transactions_only_in_block: {}",
@@ -475,6 +814,299 @@ pub fn build_transactions_only_in_block(
     transactions_only_in_block
 }
 
+/// The feerate at tags::BLOCK_CUTOFF_FEERATE_PERCENTILE of the weight of the
+/// packages, ordered by ascending package feerate. Unlike the lowest package
+/// feerate, this isn't lowered by a few prioritised packages. None if there
+/// are no packages.
+fn cutoff_feerate(packages: &[&TxPackage]) -> Option<f32> {
+    let mut feerates_and_weights: Vec<(f32, usize)> =
+        packages.iter().map(|p| (p.feerate(), p.weight())).collect();
+    feerates_and_weights.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    let total_weight: usize = feerates_and_weights.iter().map(|(_, w)| w).sum();
+    let cutoff_weight = total_weight as f64 * tags::BLOCK_CUTOFF_FEERATE_PERCENTILE as f64;
+
+    let mut weight: usize = 0;
+    for (feerate, package_weight) in feerates_and_weights.iter() {
+        weight += package_weight;
+        if weight as f64 >= cutoff_weight {
+            return Some(*feerate);
+        }
+    }
+    feerates_and_weights.last().map(|(feerate, _)| *feerate)
+}
+
+/// Detects transactions only in the block that our template would never
+/// include. The block's effective cutoff is a low percentile of the
+/// feerates of the packages consisting only of transactions the block
+/// shares with the template. Transactions only in the block that are part
+/// of a package paying far less than the cutoff were likely accelerated.
+/// The fees the pool gave up are estimated per package as the difference to
+/// paying the cutoff feerate for the package.
+pub fn detect_accelerated_transactions(
+    block_tx_packages: &[TxPackage],
+    txids_only_in_block: &HashSet<&Txid>,
+) -> AccelerationEstimate {
+    let packages: Vec<&TxPackage> = block_tx_packages
+        .iter()
+        .filter(|p| !p.txns.iter().any(|t| t.tx.is_coinbase()))
+        .collect();
+    let shared_packages: Vec<&TxPackage> = packages
+        .iter()
+        .filter(|p| {
+            p.txns
+                .iter()
+                .all(|t| !txids_only_in_block.contains(&t.txid))
+        })
+        .copied()
+        .collect();
+    let cutoff_feerate = match cutoff_feerate(&shared_packages) {
+        Some(feerate) => feerate,
+        None => return AccelerationEstimate::default(),
+    };
+
+    let mut estimate = AccelerationEstimate::default();
+    for package in packages
+        .iter()
+        .filter(|p| p.txns.iter().any(|t| txids_only_in_block.contains(&t.txid)))
+        .filter(|p| p.feerate() < cutoff_feerate * tags::THRESHOLD_FEERATE_FAR_BELOW_CUTOFF)
+    {
+        estimate.txids.extend(
+            package
+                .txns
+                .iter()
+                .filter(|t| txids_only_in_block.contains(&t.txid))
+                .map(|t| t.txid),
+        );
+        let vsize: usize = package.txns.iter().map(|t| t.tx.vsize()).sum();
+        let fee: u64 = package.txns.iter().map(|t| t.fee.to_sat()).sum();
+        let fee_at_cutoff = (cutoff_feerate as f64 * vsize as f64) as u64;
+        estimate.fee_forgone += Amount::from_sat(fee_at_cutoff.saturating_sub(fee));
+    }
+    estimate
+}
+
+/// The public key revealed by a P2WPKH or P2PKH input.
+fn input_pubkey(input: &bitcoin::TxIn) -> Option<bitcoin::PublicKey> {
+    let pubkey_bytes: Vec<u8> = if input.witness.len() == 2 {
+        input.witness.nth(1)?.to_vec()
+    } else {
+        match input.script_sig.instructions().last()?.ok()? {
+            bitcoin::script::Instruction::PushBytes(bytes) => bytes.as_bytes().to_vec(),
+            _ => return None,
+        }
+    };
+    bitcoin::PublicKey::from_slice(&pubkey_bytes).ok()
+}
+
+/// If the transaction pays to or spends from one of the output scripts of
+/// the coinbase transaction. Inputs are only matched if they reveal their
+/// public key, which P2WPKH and P2PKH inputs do.
+pub fn uses_coinbase_output_script(coinbase: &Transaction, tx: &Transaction) -> bool {
+    let scripts: HashSet<&bitcoin::ScriptBuf> = coinbase
+        .output
+        .iter()
+        .map(|o| &o.script_pubkey)
+        .filter(|s| !s.is_op_return())
+        .collect();
+    if tx.output.iter().any(|o| scripts.contains(&o.script_pubkey)) {
+        return true;
+    }
+    tx.input.iter().filter_map(input_pubkey).any(|pubkey| {
+        scripts.contains(&bitcoin::ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()))
+            || pubkey
+                .wpubkey_hash()
+                .is_ok_and(|hash| scripts.contains(&bitcoin::ScriptBuf::new_p2wpkh(&hash)))
+    })
+}
+
+/// Labels why the transactions only in the block might not have been in our
+/// template. Transactions first seen after the template_time arrived too
+/// late for the template. Transactions seen before with a package feerate
+/// below the template_cutoff_feerate, the lowest package feerate in the
+/// template, were below the template cutoff. Transactions directly following
+/// the coinbase transaction that pay to or spend from a coinbase output
+/// script are labeled as pool payouts.
+pub fn label_transactions_only_in_block(
+    transactions_only_in_block: &mut [shared_model::TransactionOnlyInBlock],
+    conflicting_transactions: &[shared_model::ConflictingTransaction],
+    template_time: u64,
+    template_cutoff_feerate: Option<f32>,
+    context: &ExtraTxContext,
+) {
+    let conflicting_txids: HashSet<&Vec<u8>> = conflicting_transactions
+        .iter()
+        .flat_map(|c| c.block_txids.iter())
+        .collect();
+    let positions: HashSet<i32> = transactions_only_in_block
+        .iter()
+        .map(|t| t.position)
+        .collect();
+
+    // the coinbase transaction isn't labeled
+    for tx in transactions_only_in_block
+        .iter_mut()
+        .filter(|t| t.position > 0)
+    {
+        let mut labels: Vec<i32> = vec![];
+        let is_conflicting = conflicting_txids.contains(&tx.transaction_txid);
+
+        match context.first_seen.get(&tx.transaction_txid) {
+            None => labels.push(tags::ExtraTxLabel::NeverSeen as i32),
+            Some(first_seen) if *first_seen > template_time => {
+                labels.push(tags::ExtraTxLabel::SeenAfterTemplate as i32)
+            }
+            Some(_) => {
+                let below_cutoff = match (
+                    context.pkg_feerates.get(&tx.transaction_txid),
+                    template_cutoff_feerate,
+                ) {
+                    (Some(feerate), Some(cutoff)) => *feerate < cutoff,
+                    _ => false,
+                };
+                if below_cutoff && !is_conflicting {
+                    labels.push(tags::ExtraTxLabel::BelowTemplateCutoff as i32);
+                }
+            }
+        }
+
+        tx.reject_reason = context.reject_reasons.get(&tx.transaction_txid).cloned();
+        if tx.reject_reason.is_some() {
+            labels.push(tags::ExtraTxLabel::NonStandard as i32);
+        }
+
+        if is_conflicting {
+            labels.push(tags::ExtraTxLabel::Replaced as i32);
+        }
+
+        // all transactions between the coinbase and this one are extra too
+        if (1..tx.position).all(|pos| positions.contains(&pos))
+            && context.coinbase_script_txids.contains(&tx.transaction_txid)
+        {
+            labels.push(tags::ExtraTxLabel::PoolPayout as i32);
+        }
+
+        tx.labels = labels;
+    }
+}
+
+/// The mempool age bucket (an index into the buckets starting at 0 and at each
+/// of tags::SANCTIONED_CONTROL_AGE_BUCKETS) of each of the passed template
+/// transactions. The age is the time between the first template in
+/// last_templates containing the transaction and the passed template. As the
+/// history only covers the templates since the previous block, transactions
+/// already in the first template are placed in the oldest bucket: they were
+/// most likely in our mempool before the previous block was found.
+pub fn template_tx_age_buckets(
+    last_templates: &TemplateHistory,
+    template: &GetBlockTemplateResult,
+    txids: &HashSet<Txid>,
+) -> HashMap<Txid, usize> {
+    let first_history_time = last_templates
+        .txids_iter()
+        .next()
+        .map(|t| t.header.current_time);
+    last_templates
+        .first_template_times(txids)
+        .into_iter()
+        .map(|(txid, first_seen)| {
+            let bucket = if Some(first_seen) == first_history_time {
+                tags::SANCTIONED_CONTROL_AGE_BUCKETS.len()
+            } else {
+                let age = template.current_time.saturating_sub(first_seen);
+                tags::SANCTIONED_CONTROL_AGE_BUCKETS
+                    .iter()
+                    .filter(|start| **start <= age)
+                    .count()
+            };
+            (txid, bucket)
+        })
+        .collect()
+}
+
+/// Sets the controls of the sanctioned transactions in the template. The
+/// controls of a sanctioned transaction are the non-sanctioned template
+/// transactions with a package feerate within
+/// THRESHOLD_SANCTIONED_CONTROL_FEERATE of its package feerate and in the same
+/// mempool age bucket. How many of them are missing from the block tells us how
+/// often a transaction of this feerate and age is left out without being
+/// sanctioned. Transactions without a known age bucket aren't matched. A
+/// control matching multiple sanctioned transactions is only assigned to the
+/// one with the closest package feerate, so that each control is counted once
+/// per block.
+pub fn set_sanctioned_tx_controls(
+    sanctioned_transaction_infos: &mut [shared_model::SanctionedTransactionInfo],
+    template_txid_to_pkg_feerate: &HashMap<Txid, f32>,
+    template_txid_to_age_bucket: &HashMap<Txid, usize>,
+    txids_only_in_template: &HashSet<&Txid>,
+) {
+    let sanctioned_txids: HashSet<Vec<u8>> = sanctioned_transaction_infos
+        .iter()
+        .map(|info| info.transaction_txid.clone())
+        .collect();
+
+    // package feerate and age bucket of the sanctioned transactions
+    let mut sanctioned_feerates: HashMap<Vec<u8>, (f32, usize)> = HashMap::new();
+    // package feerate, age bucket and whether the transaction is missing from the block
+    let mut controls: Vec<(f32, usize, bool)> = vec![];
+    for (txid, feerate) in template_txid_to_pkg_feerate.iter() {
+        let age_bucket = match template_txid_to_age_bucket.get(txid) {
+            Some(age_bucket) => *age_bucket,
+            None => continue,
+        };
+        let mut txid_display_order = txid.to_byte_array().to_vec();
+        txid_display_order.reverse();
+        if sanctioned_txids.contains(&txid_display_order) {
+            sanctioned_feerates.insert(txid_display_order, (*feerate, age_bucket));
+        } else {
+            controls.push((*feerate, age_bucket, txids_only_in_template.contains(txid)));
+        }
+    }
+
+    // index into the sanctioned_transaction_infos, package feerate and age bucket
+    let sanctioned: Vec<(usize, f32, usize)> = sanctioned_transaction_infos
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| info.in_template)
+        .filter_map(|(i, info)| {
+            sanctioned_feerates
+                .get(&info.transaction_txid)
+                .map(|(feerate, age_bucket)| (i, *feerate, *age_bucket))
+        })
+        .collect();
+
+    // control count and missing control count
+    let mut counts: HashMap<usize, (i32, i32)> =
+        sanctioned.iter().map(|(i, _, _)| (*i, (0, 0))).collect();
+    for (control_feerate, control_age_bucket, missing) in controls.iter() {
+        let closest = sanctioned
+            .iter()
+            .filter(|(_, feerate, age_bucket)| {
+                age_bucket == control_age_bucket
+                    && (control_feerate - feerate).abs()
+                        <= feerate * tags::THRESHOLD_SANCTIONED_CONTROL_FEERATE
+            })
+            .min_by(|(_, a, _), (_, b, _)| {
+                (control_feerate - a)
+                    .abs()
+                    .total_cmp(&(control_feerate - b).abs())
+            });
+        if let Some((i, _, _)) = closest {
+            let count = counts
+                .get_mut(i)
+                .expect("every sanctioned transaction has counts");
+            count.0 += 1;
+            if *missing {
+                count.1 += 1;
+            }
+        }
+    }
+
+    for (i, (control_tx, control_missing_tx)) in counts {
+        sanctioned_transaction_infos[i].control_tx = Some(control_tx);
+        sanctioned_transaction_infos[i].control_missing_tx = Some(control_missing_tx);
+    }
+}
+
 pub fn build_sanctioned_transaction_infos(
     block_id: i64,
     block_tx_data: &BlockTxData,
@@ -526,6 +1158,9 @@ pub fn build_sanctioned_transaction_infos(
                             outpoint_to_sanctioned_utxo_map,
                             sanctioned_addresses,
                         ),
+                        // set in set_sanctioned_tx_controls()
+                        control_tx: None,
+                        control_missing_tx: None,
                     });
                 }
                 Err(e) => {
@@ -571,6 +1206,9 @@ pub fn build_sanctioned_transaction_infos(
                             outpoint_to_sanctioned_utxo_map,
                             sanctioned_addresses,
                         ),
+                        // set in set_sanctioned_tx_controls()
+                        control_tx: None,
+                        control_missing_tx: None,
                     });
                 }
                 Err(e) => {
@@ -683,6 +1321,84 @@ pub fn build_template_tx_data(template: &GetBlockTemplateResult) -> TemplateTxDa
     }
 }
 
+/// Splits the fee difference between the block and the template into the
+/// fees of the missing transactions, the fees of the extra transactions and
+/// a residual. Transactions in a conflicting set are left out of the missing
+/// and extra fees and end up in the residual.
+pub fn build_fee_delta(
+    block_cb_fees: i64,
+    template_cb_fees: i64,
+    transactions_only_in_block: &[shared_model::TransactionOnlyInBlock],
+    transactions_only_in_template: &[shared_model::TransactionOnlyInTemplate],
+    conflicting_transactions: &[shared_model::ConflictingTransaction],
+    transactions: &HashMap<Vec<u8>, shared_model::Transaction>,
+) -> FeeDelta {
+    let conflicting_txids: HashSet<&Vec<u8>> = conflicting_transactions
+        .iter()
+        .flat_map(|c| c.block_txids.iter().chain(c.template_txids.iter()))
+        .collect();
+    let fee = |txid: &Vec<u8>| -> i64 {
+        if conflicting_txids.contains(txid) {
+            return 0;
+        }
+        transactions.get(txid).map(|tx| tx.fee).unwrap_or_default()
+    };
+    let missing_tx_fees: i64 = transactions_only_in_template
+        .iter()
+        .map(|t| fee(&t.transaction_txid))
+        .sum();
+    let extra_tx_fees: i64 = transactions_only_in_block
+        .iter()
+        .map(|t| fee(&t.transaction_txid))
+        .sum();
+    FeeDelta {
+        missing_tx_fees,
+        extra_tx_fees,
+        residual: block_cb_fees - template_cb_fees - extra_tx_fees + missing_tx_fees,
+    }
+}
+
+/// Checks if the block packages, ordered by their position in the block,
+/// follow a descending feerate order like the packages in a template do.
+/// The first package contains the coinbase transaction and is skipped. A
+/// package is out of order if the next package pays a considerably higher
+/// feerate. This points to prioritised transactions or a custom ordering.
+pub fn build_package_ordering(block_pkg_feerates: &[f32]) -> PackageOrdering {
+    let feerates = block_pkg_feerates.get(1..).unwrap_or_default();
+    let out_of_order_pkgs = feerates
+        .windows(2)
+        .filter(|pair| pair[1] > pair[0] * tags::THRESHOLD_PACKAGE_FEERATE_INVERSION)
+        .count();
+    let pairs = feerates.len().saturating_sub(1);
+    PackageOrdering {
+        out_of_order_pkgs: out_of_order_pkgs as i32,
+        conformance: if pairs > 0 {
+            Some(1.0 - out_of_order_pkgs as f32 / pairs as f32)
+        } else {
+            None
+        },
+    }
+}
+
+/// Maps the positions of the transactions shared by block and template.
+/// Returns the block positions in ascending order and the template
+/// positions of the same transactions.
+pub fn build_shared_tx_positions(
+    block: &bitcoin::Block,
+    template_txid_to_txinfo_map: &HashMap<Txid, TxInfo>,
+) -> (Vec<i32>, Vec<i32>) {
+    block
+        .txdata
+        .iter()
+        .enumerate()
+        .filter_map(|(block_pos, tx)| {
+            template_txid_to_txinfo_map
+                .get(&tx.compute_txid())
+                .map(|tx_info| (block_pos as i32, tx_info.pos))
+        })
+        .unzip()
+}
+
 pub fn build_block(
     block: &bitcoin::Block,
     template: &GetBlockTemplateResult,
@@ -695,8 +1411,10 @@ pub fn build_block(
     sanctioned_missing_tx: i32,
     extra_tx: i32,
     shared_tx: i32,
+    similarity: &TxSetSimilarity,
     block_fees: &Amount,
     template_fees: &Amount,
+    block_seen_time: chrono::DateTime<chrono::Utc>,
     outpoint_to_sanctioned_utxo_map: &HashMap<(Vec<u8>, u32), &shared_model::SanctionedUtxo>,
     sanctioned_addresses: &HashSet<String>,
     pools: SharedPoolIDData,
@@ -725,6 +1443,21 @@ pub fn build_block(
     {
         block_tags.push(tags::BlockTag::TaprootSignaling as i32);
     }
+    let package_ordering = build_package_ordering(block_pkg_feerates);
+    if package_ordering.out_of_order_pkgs > 0 {
+        block_tags.push(tags::BlockTag::OutOfOrderPackages as i32);
+    }
+    let (shared_tx_block_positions, shared_tx_template_positions) =
+        build_shared_tx_positions(block, template_txid_to_txinfo_map);
+    let block_weight = block.weight().to_wu() as i32;
+    let template_weight = template
+        .transactions
+        .iter()
+        .map(|tx| tx.weight)
+        .sum::<usize>() as i32;
+    if let Some(tag) = block_fill_tag(block.txdata.len(), block_weight, template_weight) {
+        block_tags.push(tag);
+    }
 
     shared_model::NewBlock {
         hash: block_hash,
@@ -735,13 +1468,13 @@ pub fn build_block(
         missing_tx,
         shared_tx,
         sanctioned_missing_tx,
-        equality: 0.0, // TODO: can be implemented later, if needed
-        block_seen_time: chrono::Utc::now().naive_utc(),
+        equality: similarity.equality,
+        block_seen_time: block_seen_time.naive_utc(),
         block_time: chrono::DateTime::from_timestamp(block.header.time as i64, 0)
             .expect("block timestamp out of range")
             .naive_utc(),
         block_tx: block.txdata.len() as i32,
-        block_weight: block.weight().to_wu() as i32,
+        block_weight,
         block_sanctioned: block
             .txdata
             .iter()
@@ -778,11 +1511,7 @@ pub fn build_block(
                 )
             })
             .count() as i32,
-        template_weight: (template
-            .transactions
-            .iter()
-            .map(|tx| tx.weight)
-            .sum::<usize>()) as i32,
+        template_weight,
         template_cb_fees: template_fees.to_sat() as i64,
         template_pkg_weights: template_pkg_weights.to_vec(),
         template_pkg_feerates: template_pkg_feerates.to_vec(),
@@ -796,14 +1525,129 @@ pub fn build_block(
             .iter()
             .map(|tx| tx.sigops().unwrap_or_default() as i64)
             .sum(),
+        fee_weighted_equality: similarity.fee_weighted_equality,
+        weight_weighted_equality: similarity.weight_weighted_equality,
+        // set from the AccelerationEstimate in build_processed_block()
+        accelerated_tx: 0,
+        accelerated_fee_forgone: 0,
+        shared_tx_block_positions,
+        shared_tx_template_positions,
+        out_of_order_pkgs: package_ordering.out_of_order_pkgs,
+        ordering_conformance: package_ordering.conformance,
+        // set from the parent header after build_processed_block()
+        prev_block_interval: None,
+        version: Some(block.header.version.to_consensus()),
+        // set from the FeeDelta in build_processed_block()
+        missing_tx_fees: 0,
+        extra_tx_fees: 0,
+        fee_delta_residual: 0,
+    }
+}
+
+/// Returns the Empty tag for blocks with only the coinbase transaction and
+/// the UnderFull tag for blocks considerably lighter than the template. The
+/// template weight doesn't include the coinbase transaction and the header.
+pub fn block_fill_tag(block_tx: usize, block_weight: i32, template_weight: i32) -> Option<i32> {
+    if block_tx <= 1 {
+        Some(tags::BlockTag::Empty as i32)
+    } else if (block_weight as f32)
+        < template_weight as f32 * tags::THRESHOLD_BLOCK_CONSIDERED_UNDERFULL
+    {
+        Some(tags::BlockTag::UnderFull as i32)
+    } else {
+        None
+    }
+}
+
+/// Tags the block if it signals for one of the deployments active at its
+/// height.
+pub fn tag_deployment_signaling(block: &mut shared_model::NewBlock, deployments: &[Deployment]) {
+    let version = match block.version {
+        Some(version) => version,
+        None => return,
+    };
+    if deployments
+        .iter()
+        .any(|d| d.is_active(block.height as u32) && d.is_signaled_by(version))
+    {
+        block.tags.push(tags::BlockTag::DeploymentSignaling as i32);
     }
 }
 
+/// Returns the tags for a block timestamp far ahead of when the block was
+/// seen, for a block seen long after its timestamp, and for a block
+/// timestamp at or close to the median time past of its parent. The times
+/// are UNIX timestamps.
+pub fn timestamp_anomaly_tags(
+    block_time: i64,
+    seen_time: i64,
+    prev_median_time_past: Option<i64>,
+) -> Vec<i32> {
+    let mut block_tags: Vec<i32> = vec![];
+    if block_time - seen_time > tags::THRESHOLD_BLOCK_TIME_AHEAD {
+        block_tags.push(tags::BlockTag::TimestampAhead as i32);
+    }
+    if seen_time - block_time > tags::THRESHOLD_BLOCK_SEEN_LATE {
+        block_tags.push(tags::BlockTag::SeenLate as i32);
+    }
+    if prev_median_time_past
+        .is_some_and(|mtp| block_time - mtp <= tags::THRESHOLD_BLOCK_TIME_CLOSE_TO_MTP)
+    {
+        block_tags.push(tags::BlockTag::TimestampCloseToMTP as i32);
+    }
+    block_tags
+}
+
+/// Tags the block if its timestamp is anomalous. Requires the seen time to
+/// be set. The median time past of the parent is None if it's unknown.
+pub fn tag_timestamp_anomalies(
+    block: &mut shared_model::NewBlock,
+    prev_median_time_past: Option<i64>,
+) {
+    let anomalies = timestamp_anomaly_tags(
+        block.block_time.and_utc().timestamp(),
+        block.block_seen_time.and_utc().timestamp(),
+        prev_median_time_past,
+    );
+    block.tags.extend(anomalies);
+}
+
+/// Seconds between the header timestamps of the block and its parent. Can
+/// be negative as the timestamps don't have to be increasing.
+pub fn prev_block_interval(block: &bitcoin::Block, prev_header: &bitcoin::block::Header) -> i32 {
+    (block.header.time as i64 - prev_header.time as i64) as i32
+}
+
+/// Number of blocks the median time past is calculated over.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Calculates the median time past (MTP) of the block with the passed hash
+/// from the timestamps of it and its 10 ancestors, like Bitcoin Core does.
+/// The headers are looked up with `get_header`. Returns None if a header
+/// can't be looked up. Close to genesis, fewer headers are used.
+pub fn median_time_past<F>(hash: &BlockHash, mut get_header: F) -> Option<i64>
+where
+    F: FnMut(&BlockHash) -> Option<bitcoin::block::Header>,
+{
+    let mut times: Vec<i64> = Vec::with_capacity(MEDIAN_TIME_SPAN);
+    let mut hash = *hash;
+    while times.len() < MEDIAN_TIME_SPAN {
+        let header = get_header(&hash)?;
+        times.push(header.time as i64);
+        if header.prev_blockhash == BlockHash::all_zeros() {
+            break;
+        }
+        hash = header.prev_blockhash;
+    }
+    times.sort_unstable();
+    Some(times[times.len() / 2])
+}
+
 pub fn build_transactions_only_in_template(
     block_id: i64,
     txids_only_in_template: &HashSet<&Txid>,
     template_txid_to_txinfo_map: &HashMap<Txid, TxInfo>,
-    template_txid_to_mempool_age: &HashMap<Txid, i32>,
+    context: &MissingTxContext,
     transactions: &mut HashMap<Vec<u8>, shared_model::Transaction>,
     outpoint_to_sanctioned_utxo_map: &HashMap<(Vec<u8>, u32), &shared_model::SanctionedUtxo>,
     sanctioned_addresses: &HashSet<String>,
@@ -820,11 +1664,29 @@ pub fn build_transactions_only_in_template(
         let mut txid_to_reverse = tx_info.txid.to_byte_array().to_vec();
         txid_to_reverse.reverse();
 
+        let mempool_entry = context.mempool_entries.get(*txid);
+        let reasons = classify_missing_transaction(
+            tx_info,
+            &txid_to_reverse,
+            is_tx_sanctioned(
+                &tx_info.tx,
+                outpoint_to_sanctioned_utxo_map,
+                sanctioned_addresses,
+            ),
+            context,
+            txids_only_in_template,
+        );
         transactions_only_in_template.push(shared_model::TransactionOnlyInTemplate {
             block_id,
             position: tx_info.pos,
-            mempool_age_seconds: *template_txid_to_mempool_age.get(*txid).unwrap_or(&-1),
+            mempool_age_seconds: mempool_entry.map(|e| e.age_seconds),
             transaction_txid: txid_to_reverse.clone(),
+            ancestor_count: mempool_entry.map(|e| e.ancestor_count),
+            ancestor_vsize: mempool_entry.map(|e| e.ancestor_vsize),
+            descendant_count: mempool_entry.map(|e| e.descendant_count),
+            descendant_vsize: mempool_entry.map(|e| e.descendant_vsize),
+            fee_delta: mempool_entry.map(|e| e.fee_delta),
+            reasons,
         });
 
         match build_transaction(
@@ -846,14 +1708,72 @@ pub fn build_transactions_only_in_template(
     transactions_only_in_template
 }
 
-fn add_to_transactions(
-    new_tx: &shared_model::Transaction,
-    transactions: &mut HashMap<Vec<u8>, shared_model::Transaction>,
-) {
-    if let Some(existing_tx) = transactions.get_mut(&new_tx.txid) {
-        if existing_tx.tags != new_tx.tags {
-            let mut both_tags: Vec<i32> = existing_tx
-                .tags
+/// Classifies why a transaction only in the template might be missing from
+/// the block. Returns the `tags::MissingReason`s that apply to the transaction.
+/// A transaction without a known reason returns an empty list.
+pub fn classify_missing_transaction(
+    tx_info: &TxInfo,
+    txid_display_order: &[u8],
+    is_sanctioned: bool,
+    context: &MissingTxContext,
+    txids_only_in_template: &HashSet<&Txid>,
+) -> Vec<i32> {
+    let mut reasons: Vec<i32> = vec![];
+
+    if let Some(entry) = context.mempool_entries.get(&tx_info.txid) {
+        if (entry.age_seconds as u64) < tags::THRESHOLD_TRANSACTION_CONSIDERED_YOUNG {
+            reasons.push(tags::MissingReason::Young as i32);
+        }
+    }
+
+    if let Some(lowest_block_pkg_feerate) = context.lowest_block_pkg_feerate {
+        let pkg_feerate = context
+            .txid_to_pkg_feerate
+            .get(&tx_info.txid)
+            .copied()
+            .unwrap_or_else(|| tx_info.feerate());
+        if pkg_feerate
+            <= lowest_block_pkg_feerate * tags::THRESHOLD_FEERATE_CLOSE_TO_LOWEST_BLOCK_PACKAGE
+        {
+            reasons.push(tags::MissingReason::LowFeerate as i32);
+        }
+    }
+
+    if context.conflicting_txids.contains(txid_display_order) {
+        reasons.push(tags::MissingReason::Conflicting as i32);
+    }
+
+    if tx_info
+        .prev_output_txids()
+        .iter()
+        .any(|parent| txids_only_in_template.contains(parent))
+    {
+        reasons.push(tags::MissingReason::MissingParent as i32);
+    }
+
+    if is_sanctioned {
+        reasons.push(tags::MissingReason::Sanctioned as i32);
+    }
+
+    if tx_info.tx.vsize() as u64 >= tags::THRESHOLD_TRANSACTION_CONSIDERED_LARGE {
+        reasons.push(tags::MissingReason::Large as i32);
+    }
+
+    if tx_info.tx.sigops().unwrap_or_default() as u64 > tags::THRESHOLD_SIGOPS_CONSIDERED_MANY {
+        reasons.push(tags::MissingReason::ManySigops as i32);
+    }
+
+    reasons
+}
+
+fn add_to_transactions(
+    new_tx: &shared_model::Transaction,
+    transactions: &mut HashMap<Vec<u8>, shared_model::Transaction>,
+) {
+    if let Some(existing_tx) = transactions.get_mut(&new_tx.txid) {
+        if existing_tx.tags != new_tx.tags {
+            let mut both_tags: Vec<i32> = existing_tx
+                .tags
                 .iter()
                 .chain(new_tx.tags.iter())
                 .cloned()
@@ -1111,6 +2031,68 @@ pub fn build_conflicting_transactions(
     conflicting_transactions
 }
 
+/// Returns the Jaccard index of two sets given the size of their
+/// intersection and the size of the elements only in one of them. Two empty
+/// sets are considered equal.
+fn jaccard_index(shared: f64, only_in_a: f64, only_in_b: f64) -> f32 {
+    let union = shared + only_in_a + only_in_b;
+    if union == 0.0 {
+        return 1.0;
+    }
+    (shared / union) as f32
+}
+
+/// Compares the transactions in the template and block. The coinbase
+/// transaction is never part of the template and isn't counted.
+pub fn build_tx_set_similarity(
+    block_tx_data: &BlockTxData,
+    template_tx_data: &TemplateTxData,
+    txids_only_in_block: &HashSet<&Txid>,
+    txids_only_in_template: &HashSet<&Txid>,
+) -> TxSetSimilarity {
+    let extra: Vec<&TxInfo> = txids_only_in_block
+        .iter()
+        .map(|txid| &block_tx_data.txid_to_txinfo_map[*txid])
+        .filter(|tx_info| !tx_info.tx.is_coinbase())
+        .collect();
+    let missing: Vec<&TxInfo> = txids_only_in_template
+        .iter()
+        .map(|txid| &template_tx_data.txid_to_txinfo_map[*txid])
+        .collect();
+    let shared: Vec<&TxInfo> = block_tx_data
+        .txinfos
+        .iter()
+        .filter(|tx_info| template_tx_data.txids.contains(&tx_info.txid))
+        .collect();
+
+    let fees = |txinfos: &[&TxInfo]| -> f64 {
+        txinfos
+            .iter()
+            .map(|tx_info| tx_info.fee.to_sat() as f64)
+            .sum()
+    };
+    let weights = |txinfos: &[&TxInfo]| -> f64 {
+        txinfos
+            .iter()
+            .map(|tx_info| tx_info.tx.weight().to_wu() as f64)
+            .sum()
+    };
+
+    TxSetSimilarity {
+        equality: jaccard_index(
+            shared.len() as f64,
+            missing.len() as f64,
+            extra.len() as f64,
+        ),
+        fee_weighted_equality: jaccard_index(fees(&shared), fees(&missing), fees(&extra)),
+        weight_weighted_equality: jaccard_index(
+            weights(&shared),
+            weights(&missing),
+            weights(&extra),
+        ),
+    }
+}
+
 pub fn get_sanctioned_missing_tx_count(
     txids_only_in_template: &HashSet<&Txid>,
     data: &TemplateTxData,
@@ -1132,28 +2114,273 @@ pub fn get_sanctioned_missing_tx_count(
 
 pub fn build_debug_template_selection_infos(
     block_id: i64,
-    templates: &VecDeque<GetBlockTemplateResult>,
+    templates: &TemplateHistory,
     block_txids: HashSet<Txid>,
     selected_template_time: u64,
 ) -> Vec<shared_model::DebugTemplateSelectionInfo> {
     templates
-        .iter()
+        .txids_iter()
         .map(|t| {
-            let template_txids: HashSet<Txid> = t.transactions.iter().map(|t| t.txid).collect();
+            let template_txids: HashSet<Txid> = t.txids.into_iter().collect();
             shared_model::DebugTemplateSelectionInfo {
                 block_id,
-                template_time: chrono::DateTime::from_timestamp(t.current_time as i64, 0)
+                template_time: chrono::DateTime::from_timestamp(t.header.current_time as i64, 0)
                     .expect("template timestamp out of range")
                     .naive_utc(),
                 count_missing: template_txids.difference(&block_txids).count() as i32,
                 count_shared: block_txids.intersection(&template_txids).count() as i32,
                 count_extra: block_txids.difference(&template_txids).count() as i32,
-                selected: t.current_time == selected_template_time,
+                selected: t.header.current_time == selected_template_time,
             }
         })
         .collect::<Vec<shared_model::DebugTemplateSelectionInfo>>()
 }
 
+pub fn select_best_template_for_block(
+    last_templates: &TemplateHistory,
+    block_txids: HashSet<Txid>,
+) -> GetBlockTemplateResult {
+    assert!(!last_templates.is_empty());
+
+    // Only the txids are reconstructed while searching. The full template is
+    // only reconstructed for the selected template.
+    let (index, _) = last_templates
+        .txids_iter()
+        .enumerate()
+        .min_by_key(|(_, t)| {
+            let template_txids: HashSet<&Txid> = t.txids.iter().collect();
+            let extra = block_txids
+                .iter()
+                .filter(|txid| !template_txids.contains(txid))
+                .count();
+            let missing = template_txids
+                .iter()
+                .filter(|txid| !block_txids.contains(**txid))
+                .count();
+            missing + extra
+        })
+        .unwrap(); // we can unwrap the Option here as we WILL find a minimum if the last_templates is not empty (which is asserted).
+    last_templates
+        .get(index)
+        .expect("the selected template should be in the template history")
+}
+
+/// Compares the block to the best matching template in last_templates and
+/// builds everything that is written to the database for the block. The
+/// block_seen_time is the time the block was detected. The mempool entries
+/// of the transactions only in the template are requested via the passed
+/// mempool_entries function. Transactions without an entry have an unknown
+/// mempool age.
+pub fn build_processed_block(
+    bitcoin_block: &bitcoin::Block,
+    block_tx_fees: &GetBlockTxFeesResult,
+    last_templates: &TemplateHistory,
+    block_seen_time: chrono::DateTime<chrono::Utc>,
+    sanctioned: &SanctionedData,
+    mempool_entries: impl FnOnce(&HashSet<&Txid>) -> HashMap<Txid, MempoolEntryInfo>,
+    pools: SharedPoolIDData,
+    network: Network,
+) -> ProcessedBlock {
+    let block_tx_data = build_block_tx_data(bitcoin_block, block_tx_fees);
+
+    // For best possible comparison we want to compare a template and a block
+    // where our template was generated at the same time as the pool generated
+    // his template. As we can't know when exactly the pool generated his
+    // template, we assume that it must have been before or at the
+    // timestamp included in the block headder. During mining, the miners likely
+    // engages in nTime rolling, which increases the timestamp in the block header.
+    // Additionally, we don't know if the pools clock is accurate.
+    //
+    // Thus, to aid template selection, we pick the template sharing the most transactions
+    // with the block out of the templates generated before the timestamp in the block header.
+    let template = &select_best_template_for_block(last_templates, block_tx_data.txids.clone());
+
+    let template_tx_data = build_template_tx_data(template);
+
+    let template_tx_packages =
+        build_packages(&template_tx_data.txinfos, &template_tx_parents(template));
+    let template_pkg_feerates: Vec<f32> =
+        template_tx_packages.iter().map(|p| p.feerate()).collect();
+    let template_pkg_weights: Vec<i64> = template_tx_packages
+        .iter()
+        .map(|p| p.weight() as i64)
+        .collect();
+
+    let block_tx_packages = build_packages(
+        &block_tx_data.txinfos,
+        &in_set_tx_parents(&block_tx_data.txinfos),
+    );
+    let block_pkg_feerates: Vec<f32> = block_tx_packages.iter().map(|p| p.feerate()).collect();
+    let block_pkg_weights: Vec<i64> = block_tx_packages
+        .iter()
+        .map(|p| p.weight() as i64)
+        .collect();
+
+    // Set diffs to get the transactions that are in the block
+    // but not in the template and the other way around.
+    let txids_only_in_block: HashSet<&Txid> = block_tx_data
+        .txids
+        .difference(&template_tx_data.txids)
+        .collect();
+    let txids_only_in_template: HashSet<&Txid> = template_tx_data
+        .txids
+        .difference(&block_tx_data.txids)
+        .collect();
+    let shared_tx: usize = block_tx_data
+        .txids
+        .intersection(&template_tx_data.txids)
+        .count();
+
+    let outpoint_to_sanctioned_utxo_map = build_outpoint_to_sanctioned_utxo_map(&sanctioned.utxos);
+
+    let sanctioned_missing_tx = get_sanctioned_missing_tx_count(
+        &txids_only_in_template,
+        &template_tx_data,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+    );
+
+    let similarity = build_tx_set_similarity(
+        &block_tx_data,
+        &template_tx_data,
+        &txids_only_in_block,
+        &txids_only_in_template,
+    );
+
+    let acceleration = detect_accelerated_transactions(&block_tx_packages, &txids_only_in_block);
+
+    let block_fees: Amount =
+        Amount::from_sat(block_tx_fees.tx.iter().map(|tx| tx.fee.to_sat()).sum());
+    let template_fees: Amount =
+        Amount::from_sat(template.transactions.iter().map(|tx| tx.fee.to_sat()).sum());
+    let mut block = build_block(
+        bitcoin_block,
+        template,
+        &template_tx_data.txid_to_txinfo_map,
+        &template_pkg_weights,
+        &template_pkg_feerates,
+        &block_pkg_weights,
+        &block_pkg_feerates,
+        txids_only_in_template.len() as i32,
+        sanctioned_missing_tx as i32,
+        txids_only_in_block.len() as i32,
+        shared_tx as i32,
+        &similarity,
+        &block_fees,
+        &template_fees,
+        block_seen_time,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+        pools,
+        network,
+    );
+    block.accelerated_tx = acceleration.txids.len() as i32;
+    block.accelerated_fee_forgone = acceleration.fee_forgone.to_sat() as i64;
+
+    // The block_id is set once the block is inserted into the database.
+    let block_id: i64 = 0;
+    let mut transactions: HashMap<Vec<u8>, shared_model::Transaction> = HashMap::new();
+
+    let mut conflicting_transactions = build_conflicting_transactions(
+        block_id,
+        &txids_only_in_template,
+        &template_tx_data.txid_to_txinfo_map,
+        &txids_only_in_block,
+        &block_tx_data.txid_to_txinfo_map,
+        &mut transactions,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+    );
+    conflicting_transactions.sort_by(|a, b| a.template_txids.cmp(&b.template_txids));
+
+    let missing_tx_context = MissingTxContext {
+        mempool_entries: mempool_entries(&txids_only_in_template),
+        conflicting_txids: conflicting_transactions
+            .iter()
+            .flat_map(|c| c.template_txids.iter().cloned())
+            .collect(),
+        txid_to_pkg_feerate: txid_to_pkg_feerate(&template_tx_packages),
+        // the coinbase transaction is always in the first package
+        lowest_block_pkg_feerate: block_pkg_feerates.iter().skip(1).copied().reduce(f32::min),
+    };
+    let mut transactions_only_in_template = build_transactions_only_in_template(
+        block_id,
+        &txids_only_in_template,
+        &template_tx_data.txid_to_txinfo_map,
+        &missing_tx_context,
+        &mut transactions,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+    );
+    transactions_only_in_template.sort_by(|a, b| a.transaction_txid.cmp(&b.transaction_txid));
+
+    let mut transactions_only_in_block = build_transactions_only_in_block(
+        block_id,
+        &txids_only_in_block,
+        &block_tx_data.txid_to_txinfo_map,
+        &acceleration.txids,
+        &mut transactions,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+    );
+    transactions_only_in_block.sort_by(|a, b| a.transaction_txid.cmp(&b.transaction_txid));
+
+    let fee_delta = build_fee_delta(
+        block.block_cb_fees,
+        block.template_cb_fees,
+        &transactions_only_in_block,
+        &transactions_only_in_template,
+        &conflicting_transactions,
+        &transactions,
+    );
+    block.missing_tx_fees = fee_delta.missing_tx_fees;
+    block.extra_tx_fees = fee_delta.extra_tx_fees;
+    block.fee_delta_residual = fee_delta.residual;
+
+    let mut sanctioned_transaction_infos = build_sanctioned_transaction_infos(
+        block_id,
+        &block_tx_data,
+        &template_tx_data.txids,
+        &template_tx_data.txid_to_txinfo_map,
+        &txids_only_in_block,
+        &outpoint_to_sanctioned_utxo_map,
+        &sanctioned.addresses,
+        &mut transactions,
+    );
+    sanctioned_transaction_infos.sort_by(|a, b| a.transaction_txid.cmp(&b.transaction_txid));
+    set_sanctioned_tx_controls(
+        &mut sanctioned_transaction_infos,
+        &missing_tx_context.txid_to_pkg_feerate,
+        &template_tx_age_buckets(last_templates, template, &template_tx_data.txids),
+        &txids_only_in_template,
+    );
+
+    let debug_template_selection_infos = build_debug_template_selection_infos(
+        block_id,
+        last_templates,
+        block_tx_data.txids,
+        template.current_time,
+    );
+
+    let mut transactions: Vec<shared_model::Transaction> = transactions.into_values().collect();
+    transactions.sort_by(|a, b| a.txid.cmp(&b.txid));
+
+    ProcessedBlock {
+        block,
+        transactions,
+        transactions_only_in_block,
+        transactions_only_in_template,
+        sanctioned_transaction_infos,
+        conflicting_transactions,
+        newly_sanctioned_utxos: build_newly_created_sanctioned_utxos(
+            bitcoin_block,
+            &sanctioned.addresses,
+        ),
+        debug_template_selection_infos,
+        coinbase_info: build_coinbase_info(bitcoin_block, network),
+    }
+}
+
 pub fn log_processing_error(msg: &str) {
     log::error!(target: LOG_TARGET_PROCESSING, "{}", msg);
     metrics::ERROR_PROCESSING.inc();
@@ -1242,7 +2469,7 @@ mod tests {
         // 'X <- Y' means Y spends X; X is the partent of Y
         // A <- B <- C
         let mut txns = vec![tx_a_info.clone(), tx_b_info.clone(), tx_c_info, tx_d_info]; // order matters
-        let packages = build_packages(&txns.clone());
+        let packages = build_packages(&txns, &in_set_tx_parents(&txns));
 
         println!("There should be exactly two packages");
         assert_eq!(packages.len(), 2);
@@ -1291,21 +2518,863 @@ mod tests {
             tx: tx_e.clone(),
         };
 
-        txns.insert(3, tx_e_info); // order matters
-
-        let packages = build_packages(&txns);
+        txns.insert(3, tx_e_info.clone()); // order matters
 
-        println!("There should be still exactly two packages");
-        assert_eq!(packages.len(), 2);
+        let packages = build_packages(&txns, &in_set_tx_parents(&txns));
 
-        println!("There should be now four transactions in the package");
+        println!("The low-fee child E should be in a separate package");
+        assert_eq!(packages.len(), 3);
         let package = packages.first().unwrap();
-        assert_eq!(package.txns.len(), 4);
+        assert_eq!(package.txns.len(), 3);
+        assert_eq!(package.weight(), tx_a.weight + tx_b.weight + tx_c.weight);
+        assert_eq!(packages[1].txns, vec![tx_e_info.clone()]);
+
+        // A high-fee child of A pays for A, B and E but not for C.
+        let tx_e_info = TxInfo {
+            fee: Amount::from_sat(100_000),
+            ..tx_e_info
+        };
+        txns[3] = tx_e_info.clone();
+        let packages = build_packages(&txns, &in_set_tx_parents(&txns));
+
+        println!("The high-fee child E should be in a package with its ancestors");
+        assert_eq!(packages.len(), 3);
+        assert_eq!(
+            packages[0].txns,
+            vec![tx_a_info.clone(), tx_b_info.clone(), tx_e_info]
+        );
+        assert_eq!(packages[1].txns.len(), 1);
+        assert_eq!(packages[1].txns[0].pos, 2); // C
+        assert!(packages[0].feerate() > packages[1].feerate());
+
+        // Without parent information, every transaction is its own package.
+        let packages = build_packages(&txns, &HashMap::new());
+        assert_eq!(packages.len(), txns.len());
+    }
+
+    #[test]
+    fn test_detect_accelerated_transactions() {
+        let tx = |n: u32, is_coinbase: bool, feerate: u64| -> TxInfo {
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: if is_coinbase {
+                        OutPoint::null()
+                    } else {
+                        OutPoint::new(Txid::all_zeros(), n)
+                    },
+                    ..Default::default()
+                }],
+                output: vec![],
+            };
+            TxInfo {
+                txid: tx.compute_txid(),
+                fee: Amount::from_sat(feerate * tx.vsize() as u64),
+                tx,
+                pos: n as i32,
+            }
+        };
+        let coinbase = tx(0, true, 0);
+        let shared_high = tx(1, false, 20);
+        let shared_cutoff = tx(2, false, 10);
+        let extra_close = tx(3, false, 8);
+        let extra_accelerated = tx(4, false, 1);
+        let packages: Vec<TxPackage> = [
+            &coinbase,
+            &shared_high,
+            &shared_cutoff,
+            &extra_close,
+            &extra_accelerated,
+        ]
+        .iter()
+        .map(|t| TxPackage {
+            txns: vec![(*t).clone()],
+        })
+        .collect();
+        let txids_only_in_block: HashSet<&Txid> =
+            [&coinbase.txid, &extra_close.txid, &extra_accelerated.txid].into();
+
+        println!("Only the extra transaction far below the cutoff of 10 sat/vByte is accelerated");
+        let estimate = detect_accelerated_transactions(&packages, &txids_only_in_block);
+        assert_eq!(
+            estimate,
+            AccelerationEstimate {
+                txids: [extra_accelerated.txid].into(),
+                fee_forgone: Amount::from_sat(9 * extra_accelerated.tx.vsize() as u64),
+            }
+        );
+
+        println!("Without shared packages, there is no cutoff and nothing is accelerated");
+        let estimate = detect_accelerated_transactions(&packages[3..], &txids_only_in_block);
+        assert_eq!(estimate, AccelerationEstimate::default());
+
+        println!("A few prioritised shared packages don't lower the cutoff");
+        let shared_prioritised = tx(5, false, 2);
+        let mut packages_with_prioritised: Vec<TxPackage> = (10..30)
+            .map(|n| TxPackage {
+                txns: vec![tx(n, false, 10)],
+            })
+            .collect();
+        packages_with_prioritised.push(TxPackage {
+            txns: vec![shared_prioritised],
+        });
+        packages_with_prioritised.push(TxPackage {
+            txns: vec![extra_close.clone()],
+        });
+        let estimate =
+            detect_accelerated_transactions(&packages_with_prioritised, &txids_only_in_block);
+        assert_eq!(estimate, AccelerationEstimate::default());
+
+        println!("The fees forgone are estimated per package");
+        let parent = tx(6, false, 0);
+        let child_low = tx(7, false, 0);
+        let child_high = tx(8, false, 14);
+        let mut packages_with_cpfp: Vec<TxPackage> = packages[..3]
+            .iter()
+            .map(|p| TxPackage {
+                txns: p.txns.clone(),
+            })
+            .collect();
+        packages_with_cpfp.push(TxPackage {
+            txns: vec![parent.clone(), child_low.clone(), child_high.clone()],
+        });
+        let txids_only_in_block: HashSet<&Txid> = [
+            &coinbase.txid,
+            &parent.txid,
+            &child_low.txid,
+            &child_high.txid,
+        ]
+        .into();
+        let estimate = detect_accelerated_transactions(&packages_with_cpfp, &txids_only_in_block);
+        assert_eq!(
+            estimate,
+            AccelerationEstimate {
+                txids: [parent.txid, child_low.txid, child_high.txid].into(),
+                fee_forgone: Amount::from_sat(16 * parent.tx.vsize() as u64),
+            }
+        );
+    }
+
+    #[test]
+    fn test_timestamp_anomaly_tags() {
+        assert!(timestamp_anomaly_tags(1700000000, 1700000005, Some(600)).is_empty());
+        println!("Timestamps slightly ahead due to clock drift aren't tagged");
+        assert!(timestamp_anomaly_tags(1700000060, 1700000000, Some(600)).is_empty());
+        assert_eq!(
+            timestamp_anomaly_tags(1700001000, 1700000000, Some(600)),
+            vec![tags::BlockTag::TimestampAhead as i32]
+        );
+        assert_eq!(
+            timestamp_anomaly_tags(1700000000, 1700004000, None),
+            vec![tags::BlockTag::SeenLate as i32]
+        );
+        assert_eq!(
+            timestamp_anomaly_tags(1700000000, 1700000005, Some(1700000000 - 60)),
+            vec![tags::BlockTag::TimestampCloseToMTP as i32]
+        );
+        println!("A timestamp right after the MTP is tagged");
+        assert_eq!(
+            timestamp_anomaly_tags(1700000000, 1700000005, Some(1700000000 - 600)),
+            vec![tags::BlockTag::TimestampCloseToMTP as i32]
+        );
+        assert!(timestamp_anomaly_tags(1700000000, 1700000005, Some(1700000000 - 601)).is_empty());
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let times: [u32; 13] = [
+            100, 50, 300, 200, 900, 400, 600, 500, 700, 800, 1000, 1100, 1200,
+        ];
+        let mut headers: HashMap<BlockHash, bitcoin::block::Header> = HashMap::new();
+        let mut hashes: Vec<BlockHash> = vec![];
+        let mut prev_blockhash = BlockHash::all_zeros();
+        for time in times.iter() {
+            let header = bitcoin::block::Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash,
+                merkle_root: bitcoin::TxMerkleNode::all_zeros(),
+                time: *time,
+                bits: bitcoin::CompactTarget::from_consensus(0x1d00ffff),
+                nonce: 0,
+            };
+            prev_blockhash = header.block_hash();
+            hashes.push(prev_blockhash);
+            headers.insert(prev_blockhash, header);
+        }
+        let tip = hashes[hashes.len() - 1];
+
+        println!("The median of the last 11 timestamps");
+        assert_eq!(
+            median_time_past(&tip, |h| headers.get(h).copied()),
+            Some(700)
+        );
+        println!("Fewer timestamps are used close to genesis");
+        assert_eq!(
+            median_time_past(&hashes[1], |h| headers.get(h).copied()),
+            Some(100)
+        );
+        println!("An unknown header results in None");
+        assert_eq!(median_time_past(&tip, |_| None), None);
+    }
+
+    #[test]
+    fn test_block_fill_tag() {
+        println!("A block with only the coinbase transaction is empty");
+        assert_eq!(
+            block_fill_tag(1, 1000, 3_990_000),
+            Some(tags::BlockTag::Empty as i32)
+        );
+        println!("An empty block is empty even if the template was empty too");
+        assert_eq!(
+            block_fill_tag(1, 1000, 0),
+            Some(tags::BlockTag::Empty as i32)
+        );
+        assert_eq!(
+            block_fill_tag(100, 1_000_000, 3_990_000),
+            Some(tags::BlockTag::UnderFull as i32)
+        );
+        assert_eq!(block_fill_tag(100, 2_000_000, 3_990_000), None);
+        println!("A block lighter than a mostly empty template isn't under-full");
+        assert_eq!(block_fill_tag(2, 5000, 4000), None);
+    }
+
+    #[test]
+    fn test_build_fee_delta() {
+        let tx = |txid: u8, fee: i64| shared_model::Transaction {
+            txid: vec![txid],
+            sanctioned: false,
+            vsize: 100,
+            fee,
+            output_sum: 0,
+            tags: vec![],
+            input_count: 1,
+            inputs: vec![],
+            output_count: 1,
+            outputs: vec![],
+            sigops: 0,
+        };
+        let only_in_block = |txid: u8| shared_model::TransactionOnlyInBlock {
+            block_id: 0,
+            position: txid as i32,
+            transaction_txid: vec![txid],
+            labels: vec![],
+            reject_reason: None,
+        };
+        let only_in_template = |txid: u8| shared_model::TransactionOnlyInTemplate {
+            block_id: 0,
+            position: txid as i32,
+            mempool_age_seconds: None,
+            transaction_txid: vec![txid],
+            ancestor_count: None,
+            ancestor_vsize: None,
+            descendant_count: None,
+            descendant_vsize: None,
+            fee_delta: None,
+            reasons: vec![],
+        };
+        let transactions: HashMap<Vec<u8>, shared_model::Transaction> = vec![
+            tx(1, 1000),
+            tx(2, 300),
+            tx(3, 500),
+            tx(4, 2000),
+            tx(5, 2500),
+        ]
+        .into_iter()
+        .map(|t| (t.txid.clone(), t))
+        .collect();
+        let transactions_only_in_block = vec![only_in_block(1), only_in_block(5)];
+        let transactions_only_in_template = vec![
+            only_in_template(2),
+            only_in_template(3),
+            only_in_template(4),
+        ];
+
+        println!("Missing and extra transactions explain the whole fee difference");
+        assert_eq!(
+            build_fee_delta(
+                10_000 + 1000 + 2500,
+                10_000 + 300 + 500 + 2000,
+                &transactions_only_in_block,
+                &transactions_only_in_template,
+                &[],
+                &transactions,
+            ),
+            FeeDelta {
+                missing_tx_fees: 2800,
+                extra_tx_fees: 3500,
+                residual: 0,
+            }
+        );
+
+        println!("A replacement in the block ends up in the residual");
+        let conflicting_transactions = vec![shared_model::ConflictingTransaction {
+            block_id: 0,
+            template_txids: vec![vec![4]],
+            block_txids: vec![vec![5]],
+            conflicting_outpoints_txids: vec![vec![6]],
+            conflicting_outpoints_vouts: vec![0],
+        }];
+        assert_eq!(
+            build_fee_delta(
+                10_000 + 1000 + 2500,
+                10_000 + 300 + 500 + 2000,
+                &transactions_only_in_block,
+                &transactions_only_in_template,
+                &conflicting_transactions,
+                &transactions,
+            ),
+            FeeDelta {
+                missing_tx_fees: 800,
+                extra_tx_fees: 1000,
+                residual: 500,
+            }
+        );
+    }
+
+    #[test]
+    fn test_set_sanctioned_tx_controls() {
+        let txid = |n: u8| Txid::from_byte_array([n; 32]);
+        let info = |n: u8, in_template: bool| shared_model::SanctionedTransactionInfo {
+            block_id: 0,
+            transaction_txid: vec![n; 32],
+            in_block: !in_template,
+            in_template,
+            addresses: vec![],
+            control_tx: None,
+            control_missing_tx: None,
+        };
+        let mut template_txid_to_pkg_feerate: HashMap<Txid, f32> = HashMap::from([
+            (txid(1), 10.0),
+            (txid(2), 9.5),
+            (txid(3), 10.5),
+            (txid(4), 11.5),
+            (txid(5), 2.0),
+            (txid(7), 11.4),
+            (txid(8), 10.8),
+        ]);
+        let mut template_txid_to_age_bucket: HashMap<Txid, usize> = template_txid_to_pkg_feerate
+            .keys()
+            .map(|t| (*t, 2))
+            .collect();
+        template_txid_to_pkg_feerate.insert(txid(9), 10.1);
+        template_txid_to_age_bucket.insert(txid(9), 0);
+        let missing_txids = [txid(1), txid(2), txid(5), txid(8), txid(9)];
+        let txids_only_in_template: HashSet<&Txid> = missing_txids.iter().collect();
+        let mut sanctioned_transaction_infos = vec![info(1, true), info(6, false), info(7, true)];
+
+        set_sanctioned_tx_controls(
+            &mut sanctioned_transaction_infos,
+            &template_txid_to_pkg_feerate,
+            &template_txid_to_age_bucket,
+            &txids_only_in_template,
+        );
+
+        println!("Only transactions with a similar package feerate and mempool age are controls");
+        assert_eq!(sanctioned_transaction_infos[0].control_tx, Some(2));
+        assert_eq!(sanctioned_transaction_infos[0].control_missing_tx, Some(1));
+
+        println!("Transactions only in the block have no controls");
+        assert_eq!(sanctioned_transaction_infos[1].control_tx, None);
+        assert_eq!(sanctioned_transaction_infos[1].control_missing_tx, None);
+
+        println!("A control matching two sanctioned transactions is counted once for the closest");
+        assert_eq!(sanctioned_transaction_infos[2].control_tx, Some(2));
+        assert_eq!(sanctioned_transaction_infos[2].control_missing_tx, Some(1));
+    }
+
+    #[test]
+    fn test_template_tx_age_buckets() {
+        use crate::template_history::tests::{template, txid};
+        const PREV: &str = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+
+        let mut history = TemplateHistory::new(3 * 60 * 60, 2000);
+        history.push(template(PREV, 1000, &[txid(1)]));
+        history.push(template(PREV, 1100, &[txid(1), txid(2)]));
+        history.push(template(PREV, 1900, &[txid(1), txid(2), txid(3)]));
+        let latest = template(PREV, 2000, &[txid(1), txid(2), txid(3), txid(4)]);
+        history.push(latest.clone());
+        let txids: HashSet<Txid> = (1..=4).map(|n| txid(n).parse().unwrap()).collect();
+
+        let buckets = template_tx_age_buckets(&history, &latest, &txids);
+        let bucket = |n: u32| buckets.get(&txid(n).parse::<Txid>().unwrap()).copied();
+
+        println!("Transactions in the first template of the history are in the oldest bucket");
+        assert_eq!(bucket(1), Some(2));
+        assert_eq!(bucket(2), Some(2));
+        assert_eq!(bucket(3), Some(1));
+        assert_eq!(bucket(4), Some(0));
+    }
+
+    #[test]
+    fn test_build_package_ordering() {
+        println!("Packages in descending feerate order conform");
+        assert_eq!(
+            build_package_ordering(&[0.0, 20.0, 10.5, 10.0, 2.0]),
+            PackageOrdering {
+                out_of_order_pkgs: 0,
+                conformance: Some(1.0),
+            }
+        );
+
+        println!("A prioritised low-feerate package at the top is out of order");
+        assert_eq!(
+            build_package_ordering(&[0.0, 1.0, 20.0, 10.0, 5.0, 2.0]),
+            PackageOrdering {
+                out_of_order_pkgs: 1,
+                conformance: Some(0.75),
+            }
+        );
+
+        println!("Small differences are tolerated");
+        assert_eq!(
+            build_package_ordering(&[0.0, 10.0, 10.5]).out_of_order_pkgs,
+            0
+        );
+
+        println!("A block with only the coinbase package has no ordering");
+        assert_eq!(
+            build_package_ordering(&[0.0]),
+            PackageOrdering {
+                out_of_order_pkgs: 0,
+                conformance: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_build_tx_set_similarity() {
+        let tx = |n: u32, is_coinbase: bool| -> TxInfo {
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::from_consensus(n),
+                input: vec![TxIn {
+                    previous_output: if is_coinbase {
+                        OutPoint::null()
+                    } else {
+                        OutPoint::new(Txid::all_zeros(), n)
+                    },
+                    ..Default::default()
+                }],
+                // the transactions differ in weight
+                output: (0..n)
+                    .map(|_| TxOut {
+                        value: Amount::ZERO,
+                        script_pubkey: ScriptBuf::new(),
+                    })
+                    .collect(),
+            };
+            TxInfo {
+                txid: tx.compute_txid(),
+                tx,
+                pos: 0,
+                fee: Amount::from_sat(n as u64 * 1000),
+            }
+        };
+        let weight = |tx_info: &TxInfo| tx_info.tx.weight().to_wu() as f32;
+        let coinbase = tx(0, true);
+        let a = tx(1, false);
+        let b = tx(3, false);
+        let c = tx(2, false);
+
+        let weight_weighted_equality = weight(&a) / (weight(&a) + weight(&b) + weight(&c));
+        assert!(weight(&a) < weight(&b) && weight(&c) < weight(&b));
+
+        let block_txinfos = vec![coinbase, a.clone(), b];
+        let block_tx_data = BlockTxData {
+            txids: block_txinfos.iter().map(|t| t.txid).collect(),
+            txid_to_txinfo_map: block_txinfos.iter().map(|t| (t.txid, t.clone())).collect(),
+            txinfos: block_txinfos,
+        };
+        let template_txinfos = vec![a, c];
+        let template_tx_data = TemplateTxData {
+            txids: template_txinfos.iter().map(|t| t.txid).collect(),
+            txid_to_txinfo_map: template_txinfos
+                .iter()
+                .map(|t| (t.txid, t.clone()))
+                .collect(),
+            txinfos: template_txinfos,
+        };
+        let txids_only_in_block: HashSet<&Txid> = block_tx_data
+            .txids
+            .difference(&template_tx_data.txids)
+            .collect();
+        let txids_only_in_template: HashSet<&Txid> = template_tx_data
+            .txids
+            .difference(&block_tx_data.txids)
+            .collect();
+
+        // One shared (a), one missing (c) and one extra (b) transaction.
+        // The coinbase isn't counted.
+        assert_eq!(
+            build_tx_set_similarity(
+                &block_tx_data,
+                &template_tx_data,
+                &txids_only_in_block,
+                &txids_only_in_template,
+            ),
+            TxSetSimilarity {
+                equality: 1.0 / 3.0,
+                fee_weighted_equality: 1000.0 / 6000.0,
+                weight_weighted_equality,
+            }
+        );
+
+        // An empty block and an empty template are equal.
+        assert_eq!(jaccard_index(0.0, 0.0, 0.0), 1.0);
+        assert_eq!(jaccard_index(0.0, 1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_classify_missing_transaction() {
+        let tx = |parent: Txid, vout: u32, fee: u64| -> TxInfo {
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(parent, vout),
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(10_000),
+                    script_pubkey: ScriptBuf::new(),
+                }],
+            };
+            TxInfo {
+                txid: tx.compute_txid(),
+                tx,
+                pos: 0,
+                fee: Amount::from_sat(fee),
+            }
+        };
+        let display_order = |txid: &Txid| -> Vec<u8> {
+            let mut bytes = txid.to_byte_array().to_vec();
+            bytes.reverse();
+            bytes
+        };
+        let parent = tx(Txid::all_zeros(), 0, 10_000);
+        let child = tx(parent.txid, 0, 10_000);
+        let txids_only_in_template: HashSet<&Txid> = [&parent.txid, &child.txid].into();
+
+        let mut context = MissingTxContext {
+            mempool_entries: HashMap::new(),
+            conflicting_txids: HashSet::new(),
+            txid_to_pkg_feerate: HashMap::new(),
+            lowest_block_pkg_feerate: Some(1.0),
+        };
+
+        println!("A high-feerate transaction without a mempool entry has no reason");
+        let reasons = classify_missing_transaction(
+            &parent,
+            &display_order(&parent.txid),
+            false,
+            &context,
+            &txids_only_in_template,
+        );
+        assert!(reasons.is_empty());
+
+        println!("A transaction spending a missing parent has the MissingParent reason");
+        let reasons = classify_missing_transaction(
+            &child,
+            &display_order(&child.txid),
+            false,
+            &context,
+            &txids_only_in_template,
+        );
+        assert_eq!(reasons, vec![tags::MissingReason::MissingParent as i32]);
+
+        context.mempool_entries.insert(
+            parent.txid,
+            MempoolEntryInfo {
+                age_seconds: 10,
+                ancestor_count: 1,
+                ancestor_vsize: 100,
+                descendant_count: 2,
+                descendant_vsize: 200,
+                fee_delta: 0,
+            },
+        );
+        context
+            .conflicting_txids
+            .insert(display_order(&parent.txid));
+        context.txid_to_pkg_feerate.insert(parent.txid, 1.05);
+
+        println!(
+            "A young, low-feerate, conflicting and sanctioned transaction has all four reasons"
+        );
+        let reasons = classify_missing_transaction(
+            &parent,
+            &display_order(&parent.txid),
+            true,
+            &context,
+            &txids_only_in_template,
+        );
+        assert_eq!(
+            reasons,
+            vec![
+                tags::MissingReason::Young as i32,
+                tags::MissingReason::LowFeerate as i32,
+                tags::MissingReason::Conflicting as i32,
+                tags::MissingReason::Sanctioned as i32,
+            ]
+        );
+    }
 
-        println!("The package weight should be the sum of the four transaction weights");
+    #[test]
+    fn test_label_transactions_only_in_block() {
+        let row = |position: i32, txid: u8| shared_model::TransactionOnlyInBlock {
+            block_id: 0,
+            position,
+            transaction_txid: vec![txid],
+            labels: vec![],
+            reject_reason: None,
+        };
+        let mut rows = vec![
+            row(0, 0),
+            row(1, 1),
+            row(2, 2),
+            row(5, 5),
+            row(6, 6),
+            row(8, 8),
+            row(9, 9),
+            row(3, 3),
+        ];
+        let conflicting_transactions = vec![shared_model::ConflictingTransaction {
+            block_id: 0,
+            template_txids: vec![vec![10]],
+            block_txids: vec![vec![5]],
+            conflicting_outpoints_txids: vec![vec![20]],
+            conflicting_outpoints_vouts: vec![0],
+        }];
+        let template_time = 1700000000;
+        let context = ExtraTxContext {
+            first_seen: [
+                (vec![2], template_time - 60),
+                (vec![5], template_time - 60),
+                (vec![8], template_time - 60),
+                (vec![9], template_time + 1),
+            ]
+            .into(),
+            pkg_feerates: [
+                (vec![2], 1.0),
+                (vec![5], 1.0),
+                (vec![8], 20.0),
+                (vec![9], 1.0),
+            ]
+            .into(),
+            coinbase_script_txids: [vec![1], vec![2], vec![8]].into(),
+            reject_reasons: [(vec![6], "scriptpubkey".to_string())].into(),
+        };
+        label_transactions_only_in_block(
+            &mut rows,
+            &conflicting_transactions,
+            template_time,
+            Some(5.0),
+            &context,
+        );
+
+        println!("The coinbase transaction isn't labeled");
+        assert!(rows[0].labels.is_empty());
+        assert_eq!(
+            rows[1].labels,
+            vec![
+                tags::ExtraTxLabel::NeverSeen as i32,
+                tags::ExtraTxLabel::PoolPayout as i32
+            ]
+        );
+        assert_eq!(
+            rows[2].labels,
+            vec![
+                tags::ExtraTxLabel::BelowTemplateCutoff as i32,
+                tags::ExtraTxLabel::PoolPayout as i32
+            ]
+        );
+        assert_eq!(rows[3].labels, vec![tags::ExtraTxLabel::Replaced as i32]);
+        assert_eq!(
+            rows[4].labels,
+            vec![
+                tags::ExtraTxLabel::NeverSeen as i32,
+                tags::ExtraTxLabel::NonStandard as i32
+            ]
+        );
+        assert_eq!(rows[4].reject_reason, Some("scriptpubkey".to_string()));
+        println!("A transaction seen in time paying more than the cutoff isn't below it");
+        println!(
+            "A transaction using a coinbase output script isn't a payout if it's not adjacent"
+        );
+        assert!(rows[5].labels.is_empty());
+        println!("A transaction first seen after the template arrived too late");
+        assert_eq!(
+            rows[6].labels,
+            vec![tags::ExtraTxLabel::SeenAfterTemplate as i32]
+        );
+        println!("A transaction after the coinbase isn't a payout without using a coinbase output script");
+        assert_eq!(rows[7].labels, vec![tags::ExtraTxLabel::NeverSeen as i32]);
+    }
+
+    #[test]
+    fn test_uses_coinbase_output_script() {
+        let pubkey = bitcoin::PublicKey::from_slice(
+            &hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap(),
+        )
+        .unwrap();
+        let pool_script = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap());
+        let other_script = ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros());
+        let tx = |input: TxIn, script_pubkey: ScriptBuf| Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![input],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey,
+            }],
+        };
+        let input = |witness: Witness| TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness,
+        };
+        let coinbase = tx(input(Witness::new()), pool_script.clone());
+
+        println!("Paying to the coinbase output script");
+        assert!(uses_coinbase_output_script(
+            &coinbase,
+            &tx(input(Witness::new()), pool_script.clone())
+        ));
+        println!("Spending from the coinbase output script");
+        assert!(uses_coinbase_output_script(
+            &coinbase,
+            &tx(
+                input(Witness::from_slice(&[vec![0u8; 71], pubkey.to_bytes()])),
+                other_script.clone()
+            )
+        ));
+        assert!(!uses_coinbase_output_script(
+            &coinbase,
+            &tx(input(Witness::new()), other_script.clone())
+        ));
+        println!("OP_RETURN outputs of the coinbase aren't matched");
+        let op_return = ScriptBuf::new_op_return([0u8; 4]);
+        let coinbase = tx(input(Witness::new()), op_return.clone());
+        assert!(!uses_coinbase_output_script(
+            &coinbase,
+            &tx(input(Witness::new()), op_return)
+        ));
+    }
+
+    #[test]
+    fn test_build_uncompared_block() {
+        use crate::node::mock::test_block;
+        use std::sync::{Arc, Mutex};
+
+        let pools: SharedPoolIDData = Arc::new(Mutex::new(vec![]));
+        let seen_time = chrono::DateTime::from_timestamp(1700000100, 0)
+            .unwrap()
+            .naive_utc();
+
+        let mut block = test_block(bitcoin::BlockHash::all_zeros(), 1700000000);
+        block.txdata[0].input[0].script_sig = ScriptBuf::builder().push_int(800000).into_script();
+        let uncompared = build_uncompared_block(
+            &block,
+            shared_model::UncomparedReason::NoTemplate,
+            Some(seen_time),
+            pools.clone(),
+            Network::Regtest,
+        )
+        .unwrap();
+        let mut hash = block.block_hash().to_byte_array().to_vec();
+        hash.reverse();
+        assert_eq!(uncompared.hash, hash);
+        assert_eq!(uncompared.prev_hash, vec![0u8; 32]);
+        assert_eq!(uncompared.height, 800000);
+        assert_eq!(uncompared.block_time.and_utc().timestamp(), 1700000000);
+        assert_eq!(uncompared.seen_time, seen_time);
+        assert_eq!(uncompared.pool_name, "Unknown");
+        assert_eq!(
+            uncompared.reason,
+            shared_model::UncomparedReason::NoTemplate as i32
+        );
+
+        println!("Blocks mined during a downtime use the header time as seen time");
+        let uncompared = build_uncompared_block(
+            &block,
+            shared_model::UncomparedReason::Downtime,
+            None,
+            pools,
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(uncompared.seen_time, uncompared.block_time);
+    }
+
+    #[test]
+    fn test_build_coinbase_info() {
+        use crate::node::mock::test_block;
+        use shared_model::MergedMiningChain;
+
+        let mut script_sig = ScriptBuf::builder().push_int(800000).into_bytes();
+        script_sig.push(18);
+        script_sig.extend_from_slice(b"/Foundry USA Pool/");
+        let mut auxpow = AUXPOW_COMMITMENT_PREFIX.to_vec();
+        auxpow.extend_from_slice(&[0xab; 32]);
+        auxpow.extend_from_slice(&[0x01, 0, 0, 0, 0, 0, 0, 0]);
+        script_sig.push(auxpow.len() as u8);
+        script_sig.extend_from_slice(&auxpow);
+        script_sig.push(8);
+        script_sig.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+
+        let payout_script =
+            ScriptBuf::from_hex("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        let mut rsk_script = vec![0x6a, 41];
+        rsk_script.extend_from_slice(RSK_COMMITMENT_PREFIX);
+        rsk_script.extend_from_slice(&[0x11; 32]);
+        let mut witness_commitment_script = WITNESS_COMMITMENT_PREFIX.to_vec();
+        witness_commitment_script.extend_from_slice(&[0x22; 32]);
+
+        let mut block = test_block(bitcoin::BlockHash::all_zeros(), 1700000000);
+        block.txdata[0].input[0].script_sig = ScriptBuf::from_bytes(script_sig.clone());
+        block.txdata[0].output = vec![
+            TxOut {
+                value: Amount::from_sat(312500000),
+                script_pubkey: payout_script.clone(),
+            },
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(rsk_script),
+            },
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(witness_commitment_script),
+            },
+        ];
+
+        let info = build_coinbase_info(&block, Network::Bitcoin).unwrap();
+        assert_eq!(info.script_sig, script_sig);
+        assert_eq!(info.script_sig_tag, "/Foundry USA Pool/");
+        assert_eq!(info.bip34_height, Some(800000));
+        println!("The AuxPoW commitment isn't counted as extranonce");
+        assert_eq!(info.extranonce_len, Some(8));
+        assert_eq!(info.witness_commitment, Some(vec![0x22; 32]));
+        assert_eq!(
+            info.merged_mining_chains,
+            vec![
+                MergedMiningChain::Rsk as i32,
+                MergedMiningChain::AuxPow as i32
+            ]
+        );
+        assert_eq!(
+            info.merged_mining_commitments,
+            vec![vec![0x11; 32], vec![0xab; 32]]
+        );
+        println!("OP_RETURN outputs aren't payouts");
+        assert_eq!(info.payout_outputs, 1);
+        assert_eq!(info.payout_scripts, vec![payout_script.to_bytes()]);
         assert_eq!(
-            package.weight(),
-            tx_a.weight + tx_b.weight + tx_c.weight + tx_e.weight().to_wu() as usize
+            info.payout_addresses,
+            vec!["bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string()]
         );
+        assert_eq!(info.payout_values, vec![312500000]);
     }
 }
@@ -0,0 +1,455 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bitcoin_pool_identification::{parse_json, DEFAULT_MAINNET_POOL_LIST};
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{
+    block::Header, consensus::encode, Block, BlockHash, Network,
+};
+use miningpool_observer_shared::bitcoincore_rpc::json::{
+    GetBlockTemplateResult, GetBlockTxFeesResult,
+};
+use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+use miningpool_observer_shared::chrono;
+use miningpool_observer_shared::config::{Deployment, ReplayConfig};
+use miningpool_observer_shared::db_pool;
+
+use crate::db;
+use crate::model::{ProcessedBlock, SanctionedData, SharedPoolIDData};
+use crate::processing;
+use crate::template_history::TemplateHistory;
+
+pub const LOG_TARGET_REPLAY: &str = "replay";
+
+const TEMPLATES_DIR: &str = "templates";
+const BLOCKS_DIR: &str = "blocks";
+const FEES_DIR: &str = "fees";
+const SANCTIONED_ADDRESSES_FILE: &str = "sanctioned_addresses.txt";
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Hex(encode::FromHexError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "{}", e),
+            ReplayError::Json(e) => write!(f, "{}", e),
+            ReplayError::Hex(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ReplayError {
+    fn from(err: serde_json::Error) -> Self {
+        ReplayError::Json(err)
+    }
+}
+
+impl From<encode::FromHexError> for ReplayError {
+    fn from(err: encode::FromHexError) -> Self {
+        ReplayError::Hex(err)
+    }
+}
+
+/// A directory with recorded templates and blocks. It contains:
+///  - `templates/*.json`: getblocktemplate results (BIP22 JSON)
+///  - `blocks/<hash>.hex`: the raw blocks as hex
+///  - `fees/<hash>.json`: getblock verbosity 2 results with the transaction fees
+///  - `sanctioned_addresses.txt`: optional, one sanctioned address per line
+///
+/// The templates and blocks are only read once they are replayed.
+pub struct Recording {
+    dir: PathBuf,
+    /// The template files in the order of their curtime.
+    templates: Vec<PathBuf>,
+    /// The headers of the recorded blocks by block hash.
+    headers: HashMap<BlockHash, Header>,
+    sanctioned_addresses: HashSet<String>,
+}
+
+/// The paths of the files with the extension in the directory sorted by name.
+fn files_with_extension(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, ReplayError> {
+    let mut files: Vec<PathBuf> = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(extension) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_block(path: &Path) -> Result<Block, ReplayError> {
+    Ok(encode::deserialize_hex(fs::read_to_string(path)?.trim())?)
+}
+
+impl Recording {
+    pub fn open(dir: &Path) -> Result<Recording, ReplayError> {
+        let mut templates: Vec<(u64, PathBuf)> = vec![];
+        for path in files_with_extension(&dir.join(TEMPLATES_DIR), "json")? {
+            let template: GetBlockTemplateResult = serde_json::from_slice(&fs::read(&path)?)?;
+            templates.push((template.current_time, path));
+        }
+        // A stable sort keeps templates with the same curtime in file name order.
+        templates.sort_by_key(|(current_time, _)| *current_time);
+
+        let mut headers: HashMap<BlockHash, Header> = HashMap::new();
+        for path in files_with_extension(&dir.join(BLOCKS_DIR), "hex")? {
+            let header = read_block(&path)?.header;
+            headers.insert(header.block_hash(), header);
+        }
+
+        let sanctioned_addresses = match fs::read_to_string(dir.join(SANCTIONED_ADDRESSES_FILE)) {
+            Ok(list) => list
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Recording {
+            dir: dir.to_path_buf(),
+            templates: templates.into_iter().map(|(_, path)| path).collect(),
+            headers,
+            sanctioned_addresses,
+        })
+    }
+
+    pub fn template_count(&self) -> usize {
+        self.templates.len()
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<(Block, GetBlockTxFeesResult), ReplayError> {
+        let block = read_block(&self.dir.join(BLOCKS_DIR).join(format!("{}.hex", hash)))?;
+        let fees: GetBlockTxFeesResult = serde_json::from_slice(&fs::read(
+            self.dir.join(FEES_DIR).join(format!("{}.json", hash)),
+        )?)?;
+        Ok((block, fees))
+    }
+
+    /// The hash of the recorded block building on prev_hash. If there are
+    /// multiple, the new chain tip is preferred.
+    fn block_building_on(&self, prev_hash: &BlockHash, tip: &BlockHash) -> Option<BlockHash> {
+        if self
+            .headers
+            .get(tip)
+            .is_some_and(|h| h.prev_blockhash == *prev_hash)
+        {
+            return Some(*tip);
+        }
+        let mut hashes: Vec<BlockHash> = self
+            .headers
+            .iter()
+            .filter(|(_, h)| h.prev_blockhash == *prev_hash)
+            .map(|(hash, _)| *hash)
+            .collect();
+        hashes.sort();
+        hashes.first().copied()
+    }
+}
+
+/// Replays the recorded templates in the order of their curtime. Like in the
+/// main loop, a new block is detected once the previous block hash of the
+/// templates changes. The recorded block building on the previous templates
+/// is then passed to process together with the templates and the time the
+/// block was first seen. Returns the number of processed blocks.
+pub fn replay<F>(recording: &Recording, mut process: F) -> Result<usize, ReplayError>
+where
+    F: FnMut(&Block, &GetBlockTxFeesResult, &TemplateHistory, u64) -> Result<(), ReplayError>,
+{
+    let mut last_templates = TemplateHistory::new(
+        crate::MAX_TEMPLATE_HISTORY_AGE.as_secs(),
+        crate::MAX_TEMPLATES_IN_HISTORY,
+    );
+    let mut processed_blocks: usize = 0;
+
+    for path in recording.templates.iter() {
+        let current_template: GetBlockTemplateResult = serde_json::from_slice(&fs::read(path)?)?;
+
+        let previous_prev_hash = match last_templates.latest() {
+            Some(previous_template)
+                if previous_template.previous_block_hash
+                    != current_template.previous_block_hash =>
+            {
+                previous_template.previous_block_hash
+            }
+            _ => {
+                last_templates.push(current_template);
+                continue;
+            }
+        };
+
+        match recording
+            .block_building_on(&previous_prev_hash, &current_template.previous_block_hash)
+        {
+            Some(hash) => {
+                let (block, block_tx_fees) = recording.block(&hash)?;
+                log::info!(
+                    target: LOG_TARGET_REPLAY,
+                    "Replaying block {} with {} templates.",
+                    hash,
+                    last_templates.len()
+                );
+                // The block is first seen when the first template building
+                // on a new block is requested.
+                process(
+                    &block,
+                    &block_tx_fees,
+                    &last_templates,
+                    current_template.current_time,
+                )?;
+                processed_blocks += 1;
+            }
+            None => log::warn!(
+                target: LOG_TARGET_REPLAY,
+                "There is no recorded block building on {}. Skipping {} templates.",
+                previous_prev_hash,
+                last_templates.len()
+            ),
+        }
+
+        last_templates.clear();
+        last_templates.push(current_template);
+    }
+
+    Ok(processed_blocks)
+}
+
+fn write_processed_block_json(
+    output_directory: &Path,
+    processed_block: &ProcessedBlock,
+) -> Result<(), ReplayError> {
+    // The block hash is stored in the byte order it is displayed in.
+    let path = output_directory.join(format!("{}.json", hex::encode(&processed_block.block.hash)));
+    fs::write(path, serde_json::to_vec_pretty(processed_block)?)?;
+    Ok(())
+}
+
+/// Replays the recording configured in the replay section of the daemon
+/// configuration and writes the processed blocks either into the database or
+/// as JSON files into the output directory.
+pub fn run(config: ReplayConfig, database_url: &str, network: Network, deployments: &[Deployment]) {
+    let recording = match Recording::open(&config.directory) {
+        Ok(recording) => recording,
+        Err(e) => panic!(
+            "Could not open the recording in {}: {}",
+            config.directory.display(),
+            e
+        ),
+    };
+    log::info!(
+        target: LOG_TARGET_REPLAY,
+        "Replaying {} templates from {}.",
+        recording.template_count(),
+        config.directory.display()
+    );
+
+    // Always use the pool dataset shipped with the daemon to get the same
+    // result for each replay.
+    let pools: SharedPoolIDData = Arc::new(Mutex::new(
+        parse_json(DEFAULT_MAINNET_POOL_LIST)
+            .expect("the default pool dataset should be parseable"),
+    ));
+    let sanctioned = SanctionedData {
+        addresses: recording.sanctioned_addresses.clone(),
+        utxos: vec![],
+    };
+
+    let mut write: Box<dyn FnMut(ProcessedBlock) -> Result<(), ReplayError>> = match config
+        .output_directory
+    {
+        Some(output_directory) => {
+            if let Err(e) = fs::create_dir_all(&output_directory) {
+                panic!(
+                    "Could not create the replay output directory {}: {}",
+                    output_directory.display(),
+                    e
+                );
+            }
+            Box::new(move |processed_block| {
+                write_processed_block_json(&output_directory, &processed_block)
+            })
+        }
+        None => {
+            let conn_pool = match db_pool::new(database_url) {
+                Ok(pool) => pool,
+                Err(e) => panic!("Could not create a Postgres connection pool: {}", e),
+            };
+            crate::startup_db_mirgation(&conn_pool);
+            let mut connection = match conn_pool.get() {
+                Ok(c) => c,
+                Err(e) => panic!(
+                    "Could not get a database connection from the connection pool: {}",
+                    e
+                ),
+            };
+            Box::new(move |mut processed_block| {
+                if let Err(e) = db::insert_processed_block(&mut processed_block, &mut connection) {
+                    log::error!(
+                        target: LOG_TARGET_REPLAY,
+                        "Could not write the block {} into the database: {}",
+                        hex::encode(&processed_block.block.hash),
+                        e
+                    );
+                }
+                Ok(())
+            })
+        }
+    };
+
+    let result = replay(
+        &recording,
+        |block, block_tx_fees, last_templates, block_seen_time| {
+            let block_seen_time = chrono::DateTime::from_timestamp(block_seen_time as i64, 0)
+                .expect("template timestamp out of range");
+            let mut processed_block = processing::build_processed_block(
+                block,
+                block_tx_fees,
+                last_templates,
+                block_seen_time,
+                &sanctioned,
+                // The mempool isn't recorded. Like for transactions not found
+                // in the mempool, the mempool information is unknown.
+                |_| HashMap::new(),
+                pools.clone(),
+                network,
+            );
+            processed_block.block.prev_block_interval = recording
+                .headers
+                .get(&block.header.prev_blockhash)
+                .map(|prev_header| processing::prev_block_interval(block, prev_header));
+            processing::tag_deployment_signaling(&mut processed_block.block, deployments);
+            // Only the recorded headers are known. Without the 11 headers
+            // before the block, the median time past is unknown.
+            let prev_median_time_past =
+                processing::median_time_past(&block.header.prev_blockhash, |hash| {
+                    recording.headers.get(hash).copied()
+                });
+            processing::tag_timestamp_anomalies(&mut processed_block.block, prev_median_time_past);
+            write(processed_block)
+        },
+    );
+
+    match result {
+        Ok(processed_blocks) => log::info!(
+            target: LOG_TARGET_REPLAY,
+            "Replay finished. Processed {} blocks.",
+            processed_blocks
+        ),
+        Err(e) => panic!("Could not replay the recording: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::mock::test_block;
+    use crate::template_history::tests::{template, txid};
+    use miningpool_observer_shared::bitcoincore_rpc::bitcoin::hashes::Hash;
+    use std::env;
+
+    fn record_block(dir: &Path, block: &Block) {
+        let hash = block.block_hash();
+        fs::write(
+            dir.join(BLOCKS_DIR).join(format!("{}.hex", hash)),
+            encode::serialize_hex(block),
+        )
+        .unwrap();
+        let fees = serde_json::json!({
+            "hash": hash.to_string(),
+            "tx": [{ "txid": block.txdata[0].compute_txid().to_string() }],
+        });
+        fs::write(
+            dir.join(FEES_DIR).join(format!("{}.json", hash)),
+            fees.to_string(),
+        )
+        .unwrap();
+    }
+
+    fn record_template(dir: &Path, name: &str, prev: &BlockHash, current_time: u64) {
+        let t = template(&prev.to_string(), current_time, &[txid(1)]);
+        fs::write(
+            dir.join(TEMPLATES_DIR).join(format!("{}.json", name)),
+            serde_json::to_vec(&t).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = env::temp_dir().join(format!(
+            "miningpool-observer-replay-test-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        for sub_dir in [TEMPLATES_DIR, BLOCKS_DIR, FEES_DIR] {
+            fs::create_dir_all(dir.join(sub_dir)).unwrap();
+        }
+
+        let genesis: BlockHash = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054"
+            .parse()
+            .unwrap();
+        let b1 = test_block(genesis, 1700000025);
+        let c1 = test_block(b1.block_hash(), 1700000040);
+        let c2 = test_block(c1.block_hash(), 1700000045);
+        let unknown = BlockHash::all_zeros();
+        record_block(&dir, &b1);
+        record_block(&dir, &c1);
+        record_block(&dir, &c2);
+
+        // The file names are not in curtime order.
+        record_template(&dir, "e", &genesis, 1700000010);
+        record_template(&dir, "d", &genesis, 1700000020);
+        record_template(&dir, "c", &b1.block_hash(), 1700000030);
+        // b1 and c1 were mined in rapid succession: c1 is processed with the
+        // template building on b1.
+        record_template(&dir, "b", &c2.block_hash(), 1700000050);
+        // There is no recorded block building on c2.
+        record_template(&dir, "a", &unknown, 1700000060);
+        fs::write(dir.join(SANCTIONED_ADDRESSES_FILE), "addr1\n\naddr2\n").unwrap();
+
+        let recording = Recording::open(&dir).unwrap();
+        assert_eq!(recording.template_count(), 5);
+        assert_eq!(
+            recording.sanctioned_addresses,
+            HashSet::from(["addr1".to_string(), "addr2".to_string()])
+        );
+
+        let mut processed: Vec<(BlockHash, usize, u64)> = vec![];
+        let processed_blocks = replay(&recording, |block, fees, templates, seen_time| {
+            assert_eq!(fees.hash, block.block_hash());
+            processed.push((block.block_hash(), templates.len(), seen_time));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(processed_blocks, 2);
+        assert_eq!(
+            processed,
+            vec![
+                (b1.block_hash(), 2, 1700000030),
+                (c1.block_hash(), 1, 1700000050),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
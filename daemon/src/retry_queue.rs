@@ -0,0 +1,336 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+use miningpool_observer_shared::db_pool;
+
+use crate::db;
+use crate::metrics;
+use crate::model::ProcessedBlock;
+
+pub const LOG_TARGET_RETRY_QUEUE: &str = "retry_queue";
+
+const WAIT_TIME_BETWEEN_RETRIES: time::Duration = time::Duration::from_secs(60);
+const QUEUED_BLOCK_FILE_EXTENSION: &str = "json";
+
+#[derive(Debug)]
+pub enum RetryQueueError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for RetryQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryQueueError::Io(e) => write!(f, "{}", e),
+            RetryQueueError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RetryQueueError {}
+
+impl From<io::Error> for RetryQueueError {
+    fn from(err: io::Error) -> Self {
+        RetryQueueError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for RetryQueueError {
+    fn from(err: serde_json::Error) -> Self {
+        RetryQueueError::Json(err)
+    }
+}
+
+/// Processed blocks that couldn't be written to the database. If a directory
+/// is set, each queued block is persisted as a JSON file named after the
+/// block hash so that the queue survives daemon restarts. Otherwise, the
+/// queue is only kept in memory.
+pub struct RetryQueue {
+    dir: Option<PathBuf>,
+    blocks: Vec<ProcessedBlock>,
+}
+
+impl RetryQueue {
+    /// Opens the retry queue and loads the blocks persisted in the directory.
+    pub fn new(dir: Option<PathBuf>) -> Result<RetryQueue, RetryQueueError> {
+        let mut blocks: Vec<ProcessedBlock> = vec![];
+        if let Some(dir) = dir.as_ref() {
+            fs::create_dir_all(dir)?;
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some(QUEUED_BLOCK_FILE_EXTENSION) {
+                    continue;
+                }
+                blocks.push(serde_json::from_slice(&fs::read(&path)?)?);
+            }
+        }
+        blocks.sort_by_key(|b| b.block.height);
+        Ok(RetryQueue { dir, blocks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn path(&self, processed_block: &ProcessedBlock) -> Option<PathBuf> {
+        // The block hash is stored in the byte order it is displayed in.
+        self.dir.as_ref().map(|dir| {
+            dir.join(format!(
+                "{}.{}",
+                hex::encode(&processed_block.block.hash),
+                QUEUED_BLOCK_FILE_EXTENSION
+            ))
+        })
+    }
+
+    /// Adds a block to the queue. The block is kept in memory even if it
+    /// can't be persisted.
+    pub fn push(&mut self, processed_block: ProcessedBlock) -> Result<(), RetryQueueError> {
+        let result = match self.path(&processed_block) {
+            Some(path) => {
+                // Write to a temporary file first and then rename it. This
+                // makes sure we don't end up with a partially written block.
+                let tmp_path = path.with_extension("tmp");
+                fs::write(&tmp_path, serde_json::to_vec(&processed_block)?)
+                    .and_then(|_| fs::rename(&tmp_path, &path))
+                    .map_err(RetryQueueError::from)
+            }
+            None => Ok(()),
+        };
+        self.blocks.push(processed_block);
+        self.blocks.sort_by_key(|b| b.block.height);
+        result
+    }
+
+    /// Tries to write the queued blocks, lowest height first. Written blocks
+    /// are removed from the queue. Stops at the first block that can't be
+    /// written, as the following would likely fail too. Returns the number
+    /// of written blocks.
+    pub fn retry<F>(&mut self, mut write: F) -> Result<usize, RetryQueueError>
+    where
+        F: FnMut(&mut ProcessedBlock) -> bool,
+    {
+        let mut written: usize = 0;
+        while let Some(processed_block) = self.blocks.first_mut() {
+            if !write(processed_block) {
+                break;
+            }
+            let processed_block = self.blocks.remove(0);
+            written += 1;
+            if let Some(path) = self.path(&processed_block) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(written)
+    }
+}
+
+fn write_queued_blocks(queue: &mut RetryQueue, db_pool: &db_pool::PgPool) {
+    let mut conn = match db_pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_RETRY_QUEUE,
+                "Could not get a connection from the connection pool. Not retrying the {} queued blocks: {}",
+                queue.len(),
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+
+    let result = queue.retry(|processed_block| {
+        match db::insert_processed_block(processed_block, &mut conn) {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET_RETRY_QUEUE,
+                    "Could not write the queued block {} into the database. Retrying in {:?}: {}",
+                    hex::encode(&processed_block.block.hash),
+                    WAIT_TIME_BETWEEN_RETRIES,
+                    e
+                );
+                metrics::ERROR_RETRY_QUEUE.inc();
+                false
+            }
+        }
+    });
+    match result {
+        Ok(written) if written > 0 => log::info!(
+            target: LOG_TARGET_RETRY_QUEUE,
+            "Wrote {} queued blocks into the database. {} blocks remain queued.",
+            written,
+            queue.len()
+        ),
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_RETRY_QUEUE,
+                "Could not remove a written block from the retry queue: {}",
+                e
+            );
+            metrics::ERROR_RETRY_QUEUE.inc();
+        }
+    }
+}
+
+/// Starts a thread retrying to write the blocks in the queue and the blocks
+/// sent to the returned sender into the database.
+pub fn start_retry_queue_thread(
+    mut queue: RetryQueue,
+    db_pool: db_pool::PgPool,
+) -> mpsc::Sender<ProcessedBlock> {
+    let (sender, receiver) = mpsc::channel::<ProcessedBlock>();
+
+    thread::spawn(move || loop {
+        metrics::RUNTIME_RETRY_QUEUE_BLOCKS.set(queue.len() as i64);
+        match receiver.recv_timeout(WAIT_TIME_BETWEEN_RETRIES) {
+            Ok(processed_block) => {
+                log::warn!(
+                    target: LOG_TARGET_RETRY_QUEUE,
+                    "Queued block {} to be written into the database later.",
+                    hex::encode(&processed_block.block.hash),
+                );
+                if let Err(e) = queue.push(processed_block) {
+                    log::error!(
+                        target: LOG_TARGET_RETRY_QUEUE,
+                        "Could not persist the queued block. It's only kept in memory: {}",
+                        e
+                    );
+                    metrics::ERROR_RETRY_QUEUE.inc();
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if !queue.is_empty() {
+                    write_queued_blocks(&mut queue, &db_pool);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    });
+
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miningpool_observer_shared::chrono;
+    use miningpool_observer_shared::model::NewBlock;
+    use std::env;
+
+    fn processed_block(hash: u8, height: i32) -> ProcessedBlock {
+        let time = chrono::DateTime::from_timestamp(1700000000, 0)
+            .unwrap()
+            .naive_utc();
+        ProcessedBlock {
+            block: NewBlock {
+                hash: vec![hash; 32],
+                prev_hash: vec![0; 32],
+                height,
+                tags: vec![],
+                missing_tx: 0,
+                extra_tx: 0,
+                shared_tx: 0,
+                sanctioned_missing_tx: 0,
+                equality: 0.0,
+                block_time: time,
+                block_seen_time: time,
+                block_tx: 1,
+                block_sanctioned: 0,
+                block_cb_value: 0,
+                block_cb_fees: 0,
+                block_weight: 0,
+                block_pkg_weights: vec![],
+                block_pkg_feerates: vec![],
+                pool_name: "Unknown".to_string(),
+                pool_link: "".to_string(),
+                pool_id_method: "".to_string(),
+                template_tx: 0,
+                template_time: time,
+                template_sanctioned: 0,
+                template_cb_value: 0,
+                template_cb_fees: 0,
+                template_weight: 0,
+                template_pkg_weights: vec![],
+                template_pkg_feerates: vec![],
+                template_sigops: 0,
+                block_sigops: 0,
+                fee_weighted_equality: 0.0,
+                weight_weighted_equality: 0.0,
+                accelerated_tx: 0,
+                accelerated_fee_forgone: 0,
+                shared_tx_block_positions: vec![],
+                shared_tx_template_positions: vec![],
+                out_of_order_pkgs: 0,
+                ordering_conformance: None,
+                prev_block_interval: None,
+                version: None,
+                missing_tx_fees: 0,
+                extra_tx_fees: 0,
+                fee_delta_residual: 0,
+            },
+            transactions: vec![],
+            transactions_only_in_block: vec![],
+            transactions_only_in_template: vec![],
+            sanctioned_transaction_infos: vec![],
+            conflicting_transactions: vec![],
+            newly_sanctioned_utxos: vec![],
+            debug_template_selection_infos: vec![],
+            coinbase_info: None,
+        }
+    }
+
+    #[test]
+    fn test_retry_queue() {
+        let dir = env::temp_dir().join(format!(
+            "miningpool-observer-retry-queue-test-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut queue = RetryQueue::new(Some(dir.clone())).unwrap();
+        assert!(queue.is_empty());
+        queue.push(processed_block(2, 101)).unwrap();
+        queue.push(processed_block(1, 100)).unwrap();
+        queue.push(processed_block(3, 102)).unwrap();
+
+        // a reopened queue contains the persisted blocks
+        let mut queue = RetryQueue::new(Some(dir.clone())).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        // the blocks are retried by height and retrying stops at the first failure
+        let mut attempted: Vec<i32> = vec![];
+        let written = queue
+            .retry(|b| {
+                attempted.push(b.block.height);
+                b.block.height != 101
+            })
+            .unwrap();
+        assert_eq!(written, 1);
+        assert_eq!(attempted, vec![100, 101]);
+        assert_eq!(RetryQueue::new(Some(dir.clone())).unwrap().len(), 2);
+
+        assert_eq!(queue.retry(|_| true).unwrap(), 2);
+        assert!(queue.is_empty());
+        assert!(RetryQueue::new(Some(dir.clone())).unwrap().is_empty());
+
+        // without a directory, the queue is only kept in memory
+        let mut queue = RetryQueue::new(None).unwrap();
+        queue.push(processed_block(1, 100)).unwrap();
+        assert_eq!(queue.retry(|_| true).unwrap(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
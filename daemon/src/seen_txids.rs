@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::Txid;
+
+/// Keeps a history of the txids seen in the mempool snapshots of our node.
+/// Used to tell if a transaction in a block was known to our node before
+/// the block and when it arrived. Txids not seen for longer than max_age
+/// seconds are dropped.
+pub struct SeenTxids {
+    max_age: u64,
+    /// The txids and the times they were first and last seen at.
+    seen: HashMap<Txid, (u64, u64)>,
+}
+
+impl SeenTxids {
+    pub fn new(max_age: u64) -> SeenTxids {
+        SeenTxids {
+            max_age,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Number of txids in the history.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// The time of the first snapshot the txid was seen in. As the
+    /// snapshots are taken periodically, the transaction might have arrived
+    /// up to a snapshot interval earlier.
+    pub fn first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.seen.get(txid).map(|(first_seen, _)| *first_seen)
+    }
+
+    /// Records the txids of a mempool snapshot taken at the passed time.
+    pub fn update(&mut self, mempool_txids: &[Txid], now: u64) {
+        for txid in mempool_txids {
+            self.seen.entry(*txid).or_insert((now, now)).1 = now;
+        }
+        let max_age = self.max_age;
+        self.seen
+            .retain(|_, (_, last_seen)| now.saturating_sub(*last_seen) <= max_age);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template_history::tests::txid;
+
+    #[test]
+    fn test_seen_txids() {
+        let a: Txid = txid(1).parse().unwrap();
+        let b: Txid = txid(2).parse().unwrap();
+        let mut seen = SeenTxids::new(100);
+        assert_eq!(seen.first_seen(&a), None);
+
+        seen.update(&[a, b], 1000);
+        assert_eq!(seen.first_seen(&a), Some(1000));
+        assert_eq!(seen.first_seen(&b), Some(1000));
+
+        // a left the mempool but is kept until it's older than max_age
+        seen.update(&[b], 1100);
+        assert_eq!(seen.first_seen(&a), Some(1000));
+        seen.update(&[b], 1101);
+        assert_eq!(seen.first_seen(&a), None);
+        // b is still first seen in the first snapshot
+        assert_eq!(seen.first_seen(&b), Some(1000));
+        assert_eq!(seen.len(), 1);
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::hashes::{sha256, Hash, HashEngine};
+use miningpool_observer_shared::bitcoincore_rpc::json::GetBlockTemplateResult;
+use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+use miningpool_observer_shared::chrono;
+use miningpool_observer_shared::config::TemplateArchiveConfig;
+use miningpool_observer_shared::db_pool;
+use miningpool_observer_shared::model::ArchivedTemplate;
+
+use crate::db;
+use crate::metrics;
+
+pub const LOG_TARGET_TEMPLATE_ARCHIVE: &str = "template_archive";
+
+const WAIT_TIME_BETWEEN_TEMPLATE_ARCHIVE_CLEANUPS: time::Duration =
+    time::Duration::from_secs(60 * 60); // 1 hour
+
+#[derive(Debug)]
+pub enum TemplateArchiveError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for TemplateArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateArchiveError::Io(e) => write!(f, "{}", e),
+            TemplateArchiveError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateArchiveError {}
+
+impl From<io::Error> for TemplateArchiveError {
+    fn from(err: io::Error) -> Self {
+        TemplateArchiveError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for TemplateArchiveError {
+    fn from(err: serde_json::Error) -> Self {
+        TemplateArchiveError::Json(err)
+    }
+}
+
+/// Builds the template archive entry for a template. The template is stored
+/// as gzip compressed BIP22 JSON. The hash of the template txids tells
+/// templates requested in the same second apart.
+pub fn build_archived_template(
+    template: &GetBlockTemplateResult,
+) -> Result<ArchivedTemplate, TemplateArchiveError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&serde_json::to_vec(template)?)?;
+
+    let mut prev_hash = template.previous_block_hash.to_byte_array().to_vec();
+    prev_hash.reverse();
+
+    let mut txids_engine = sha256::Hash::engine();
+    for tx in template.transactions.iter() {
+        txids_engine.input(tx.txid.as_byte_array());
+    }
+
+    Ok(ArchivedTemplate {
+        height: template.height as i32,
+        template_time: chrono::DateTime::from_timestamp(template.current_time as i64, 0)
+            .expect("template timestamp out of range")
+            .naive_utc(),
+        prev_hash,
+        longpollid: template.longpollid.clone(),
+        bits: template.bits.clone(),
+        target: template.target.clone(),
+        version: template.version as i32,
+        tx_count: template.transactions.len() as i32,
+        template: encoder.finish()?,
+        txids_hash: sha256::Hash::from_engine(txids_engine)
+            .to_byte_array()
+            .to_vec(),
+    })
+}
+
+fn archive_template(template: &GetBlockTemplateResult, db_pool: &db_pool::PgPool) {
+    let archived_template = match build_archived_template(template) {
+        Ok(t) => t,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Could not build the archive entry for the template at height {}: {}",
+                template.height,
+                e
+            );
+            metrics::ERROR_TEMPLATE_ARCHIVE.inc();
+            return;
+        }
+    };
+
+    let mut conn = match db_pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Could not get a connection from the connection pool. Not archiving the template at height {}: {}",
+                template.height,
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+
+    match db::insert_archived_template(&archived_template, &mut conn) {
+        Ok(0) => {
+            log::info!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Not archiving the template at height {} and time {}: a template with the same transactions is already archived.",
+                template.height,
+                template.current_time
+            );
+            metrics::RUNTIME_DUPLICATE_ARCHIVED_TEMPLATES.inc();
+        }
+        Ok(_) => metrics::RUNTIME_ARCHIVED_TEMPLATES.inc(),
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Could not insert the template at height {} into the template archive: {}",
+                template.height,
+                e
+            );
+            metrics::ERROR_TEMPLATE_ARCHIVE.inc();
+        }
+    }
+}
+
+fn remove_expired_templates(retention_days: u32, db_pool: &db_pool::PgPool) {
+    let cutoff = (chrono::Utc::now() - chrono::Duration::days(retention_days as i64)).naive_utc();
+    let mut conn = match db_pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Could not get a connection from the connection pool. Not removing expired templates: {}",
+                e
+            );
+            metrics::ERROR_DBPOOL.inc();
+            return;
+        }
+    };
+
+    match db::delete_archived_templates_before(&cutoff, &mut conn) {
+        Ok(count) => log::info!(
+            target: LOG_TARGET_TEMPLATE_ARCHIVE,
+            "Removed {} templates older than {} days from the template archive.",
+            count,
+            retention_days
+        ),
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET_TEMPLATE_ARCHIVE,
+                "Could not remove the expired templates from the template archive: {}",
+                e
+            );
+            metrics::ERROR_TEMPLATE_ARCHIVE.inc();
+        }
+    }
+}
+
+/// Starts a thread writing the templates sent to the returned sender into
+/// the template archive. If a retention is configured, the thread
+/// periodically removes the expired templates from the archive.
+pub fn start_template_archive_thread(
+    config: TemplateArchiveConfig,
+    db_pool: db_pool::PgPool,
+) -> mpsc::Sender<GetBlockTemplateResult> {
+    let (sender, receiver) = mpsc::channel::<GetBlockTemplateResult>();
+
+    thread::spawn(move || {
+        let mut last_cleanup: Option<time::Instant> = None;
+        loop {
+            if let Some(retention_days) = config.retention_days {
+                if last_cleanup
+                    .is_none_or(|t| t.elapsed() >= WAIT_TIME_BETWEEN_TEMPLATE_ARCHIVE_CLEANUPS)
+                {
+                    remove_expired_templates(retention_days, &db_pool);
+                    last_cleanup = Some(time::Instant::now());
+                }
+            }
+
+            match receiver.recv_timeout(WAIT_TIME_BETWEEN_TEMPLATE_ARCHIVE_CLEANUPS) {
+                Ok(template) => archive_template(&template, &db_pool),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    });
+
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template_history::tests::{template, txid};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_build_archived_template() {
+        const PREV: &str = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+        let t = template(PREV, 1700000010, &[txid(1), txid(2)]);
+
+        let archived = build_archived_template(&t).unwrap();
+        assert_eq!(archived.height, 900000);
+        assert_eq!(archived.template_time.and_utc().timestamp(), 1700000010);
+        assert_eq!(hex::encode(&archived.prev_hash), PREV);
+        assert_eq!(archived.longpollid, t.longpollid);
+        assert_eq!(hex::encode(&archived.bits), "17034219");
+        assert_eq!(archived.version, 536870912);
+        assert_eq!(archived.tx_count, 2);
+
+        println!("Templates with different transactions have different txid hashes");
+        let other = build_archived_template(&template(PREV, 1700000010, &[txid(1)])).unwrap();
+        assert_eq!(archived.template_time, other.template_time);
+        assert_ne!(archived.txids_hash, other.txids_hash);
+        assert_eq!(archived.txids_hash.len(), 32);
+
+        let mut json = vec![];
+        GzDecoder::new(archived.template.as_slice())
+            .read_to_end(&mut json)
+            .unwrap();
+        let decoded: GetBlockTemplateResult = serde_json::from_slice(&json).unwrap();
+        assert_eq!(decoded, t);
+    }
+}
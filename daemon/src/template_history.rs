@@ -0,0 +1,645 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use miningpool_observer_shared::bitcoincore_rpc::bitcoin::{BlockHash, Txid};
+use miningpool_observer_shared::bitcoincore_rpc::json::{
+    GetBlockTemplateResult, GetBlockTemplateResultTransaction,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub enum TemplateHistoryError {
+    MissingTransaction(Txid),
+}
+
+impl fmt::Display for TemplateHistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateHistoryError::MissingTransaction(txid) => write!(
+                f,
+                "the transaction {} is not known to the template history",
+                txid
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TemplateHistoryError {}
+
+/// A transaction body shared by one or more templates in the history. The
+/// `depends` of a template transaction are indices into the template and
+/// differ between templates. The parents are stored by txid instead and the
+/// `depends` are recomputed when a template is reconstructed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryTransaction {
+    tx: GetBlockTemplateResultTransaction,
+    parents: Vec<Txid>,
+}
+
+/// The changes to the transaction list of a template compared to the
+/// template before it. The added transactions are listed with their
+/// position in the new template in ascending order.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TemplateDiff {
+    pub removed: Vec<Txid>,
+    pub added: Vec<(usize, Txid)>,
+}
+
+impl TemplateDiff {
+    /// Computes the diff between two transaction lists. Transactions that
+    /// keep their relative order are not part of the diff. Transactions that
+    /// moved are removed and added again at their new position.
+    pub fn new(old: &[Txid], new: &[Txid]) -> TemplateDiff {
+        let old_positions: HashMap<&Txid, usize> =
+            old.iter().enumerate().map(|(i, txid)| (txid, i)).collect();
+
+        // (new position, old position) of the transactions in both lists
+        let shared: Vec<(usize, usize)> = new
+            .iter()
+            .enumerate()
+            .filter_map(|(i, txid)| old_positions.get(txid).map(|old_pos| (i, *old_pos)))
+            .collect();
+
+        // The longest run of shared transactions that kept their relative
+        // order doesn't need to be part of the diff.
+        let kept: HashSet<usize> = longest_increasing_subsequence(
+            &shared
+                .iter()
+                .map(|(_, old_pos)| *old_pos)
+                .collect::<Vec<usize>>(),
+        )
+        .iter()
+        .map(|i| shared[*i].1)
+        .collect();
+
+        TemplateDiff {
+            removed: old
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !kept.contains(i))
+                .map(|(_, txid)| *txid)
+                .collect(),
+            added: new
+                .iter()
+                .enumerate()
+                .filter(|(_, txid)| match old_positions.get(txid) {
+                    Some(old_pos) => !kept.contains(old_pos),
+                    None => true,
+                })
+                .map(|(i, txid)| (i, *txid))
+                .collect(),
+        }
+    }
+
+    /// Applies the diff to the transaction list it was computed against.
+    pub fn apply(&self, old: &[Txid]) -> Vec<Txid> {
+        let removed: HashSet<&Txid> = self.removed.iter().collect();
+        let mut txids: Vec<Txid> = old
+            .iter()
+            .filter(|t| !removed.contains(t))
+            .copied()
+            .collect();
+        for (pos, txid) in self.added.iter() {
+            // Only out of range if the diff is applied to the wrong list.
+            txids.insert((*pos).min(txids.len()), *txid);
+        }
+        txids
+    }
+}
+
+/// Returns the indices of a longest strictly increasing subsequence.
+fn longest_increasing_subsequence(values: &[usize]) -> Vec<usize> {
+    // tails[k] is the index of the smallest tail value of all increasing
+    // subsequences with length k+1.
+    let mut tails: Vec<usize> = vec![];
+    let mut predecessors: Vec<Option<usize>> = vec![None; values.len()];
+    for (i, value) in values.iter().enumerate() {
+        let k = tails.partition_point(|t| values[*t] < *value);
+        if k > 0 {
+            predecessors[i] = Some(tails[k - 1]);
+        }
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+
+    let mut indices: Vec<usize> = vec![];
+    let mut next = tails.last().copied();
+    while let Some(i) = next {
+        indices.push(i);
+        next = predecessors[i];
+    }
+    indices.reverse();
+    indices
+}
+
+/// Everything needed to append a template to a history that holds the
+/// template before it. Contains only the transaction bodies not yet known to
+/// the history. Records are persisted to disk by the TemplateStore.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TemplateRecord {
+    /// The template without its transactions.
+    header: GetBlockTemplateResult,
+    diff: TemplateDiff,
+    new_transactions: Vec<HistoryTransaction>,
+}
+
+struct Entry {
+    /// The template without its transactions.
+    header: GetBlockTemplateResult,
+    /// The diff to the transactions of the entry before. Empty for the first
+    /// entry as its transactions are stored in `base_txids`.
+    diff: TemplateDiff,
+}
+
+/// A template with its txids as reconstructed from the history.
+pub struct TemplateTxids<'a> {
+    pub header: &'a GetBlockTemplateResult,
+    pub txids: Vec<Txid>,
+}
+
+/// Keeps a history of block templates in a compact form. Only the txids of
+/// the oldest template are stored. All later templates are stored as a diff
+/// to the template before them. The transaction bodies are deduplicated
+/// between the templates. Templates older than max_age seconds (compared to
+/// the newest template) and templates exceeding max_templates are dropped.
+pub struct TemplateHistory {
+    max_age: u64,
+    max_templates: usize,
+    entries: VecDeque<Entry>,
+    /// The txids of the oldest template.
+    base_txids: Vec<Txid>,
+    /// The txids of the newest template.
+    latest_txids: Vec<Txid>,
+    /// The newest template is kept as a whole, as it's used on every query.
+    latest: Option<GetBlockTemplateResult>,
+    /// The transaction bodies and the number of templates containing them.
+    transactions: HashMap<Txid, (HistoryTransaction, usize)>,
+}
+
+impl TemplateHistory {
+    pub fn new(max_age: u64, max_templates: usize) -> TemplateHistory {
+        TemplateHistory {
+            max_age,
+            max_templates: max_templates.max(1),
+            entries: VecDeque::new(),
+            base_txids: vec![],
+            latest_txids: vec![],
+            latest: None,
+            transactions: HashMap::new(),
+        }
+    }
+
+    /// Number of templates in the history.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of deduplicated transaction bodies in the history.
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// The time of the oldest template in the history each of the passed
+    /// transactions is part of. Transactions in none of the templates are
+    /// missing.
+    pub fn first_template_times(&self, txids: &HashSet<Txid>) -> HashMap<Txid, u64> {
+        let mut times: HashMap<Txid, u64> = HashMap::new();
+        for template in self.txids_iter() {
+            for txid in template.txids.iter().filter(|t| txids.contains(*t)) {
+                times.entry(*txid).or_insert(template.header.current_time);
+            }
+        }
+        times
+    }
+
+    /// The newest template in the history.
+    pub fn latest(&self) -> Option<&GetBlockTemplateResult> {
+        self.latest.as_ref()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.base_txids.clear();
+        self.latest_txids.clear();
+        self.latest = None;
+        self.transactions.clear();
+    }
+
+    /// Moves the templates out of the history into a new history with the
+    /// same bounds. This history is left empty.
+    pub fn take(&mut self) -> TemplateHistory {
+        let empty = TemplateHistory::new(self.max_age, self.max_templates);
+        std::mem::replace(self, empty)
+    }
+
+    /// Appends a template to the history. Returns the record of the template
+    /// which allows rebuilding the history, for example, after a restart.
+    pub fn push(&mut self, template: GetBlockTemplateResult) -> TemplateRecord {
+        let txids: Vec<Txid> = template.transactions.iter().map(|tx| tx.txid).collect();
+        let new_transactions: Vec<HistoryTransaction> = template
+            .transactions
+            .iter()
+            .filter(|tx| !self.transactions.contains_key(&tx.txid))
+            .map(|tx| HistoryTransaction {
+                tx: GetBlockTemplateResultTransaction {
+                    depends: vec![],
+                    ..tx.clone()
+                },
+                parents: tx
+                    .depends
+                    .iter()
+                    .filter_map(|d| txids.get((*d as usize).wrapping_sub(1)))
+                    .copied()
+                    .collect(),
+            })
+            .collect();
+        for tx in new_transactions.iter() {
+            self.transactions.insert(tx.tx.txid, (tx.clone(), 0));
+        }
+
+        let record = TemplateRecord {
+            header: GetBlockTemplateResult {
+                transactions: vec![],
+                ..template.clone()
+            },
+            diff: TemplateDiff::new(&self.latest_txids, &txids),
+            new_transactions,
+        };
+        self.append_entry(record.clone(), txids, template);
+        record
+    }
+
+    /// Appends a template record created by `push()` on a history holding
+    /// the same templates as this one.
+    pub fn append(&mut self, record: TemplateRecord) -> Result<(), TemplateHistoryError> {
+        let txids = record.diff.apply(&self.latest_txids);
+        for txid in txids.iter() {
+            if !self.transactions.contains_key(txid)
+                && !record.new_transactions.iter().any(|t| t.tx.txid == *txid)
+            {
+                return Err(TemplateHistoryError::MissingTransaction(*txid));
+            }
+        }
+        for tx in record.new_transactions.iter() {
+            self.transactions
+                .entry(tx.tx.txid)
+                .or_insert_with(|| (tx.clone(), 0));
+        }
+        let template = self.build_template(&record.header, &txids);
+        self.append_entry(record, txids, template);
+        Ok(())
+    }
+
+    fn append_entry(
+        &mut self,
+        record: TemplateRecord,
+        txids: Vec<Txid>,
+        template: GetBlockTemplateResult,
+    ) {
+        for txid in txids.iter() {
+            if let Some((_, count)) = self.transactions.get_mut(txid) {
+                *count += 1;
+            }
+        }
+
+        if self.entries.is_empty() {
+            self.base_txids = txids.clone();
+        }
+        self.entries.push_back(Entry {
+            header: record.header,
+            diff: if self.entries.is_empty() {
+                TemplateDiff::default()
+            } else {
+                record.diff
+            },
+        });
+        self.latest_txids = txids;
+        self.latest = Some(template);
+
+        let latest_time = self.latest.as_ref().map_or(0, |t| t.current_time);
+        while self.entries.len() > self.max_templates
+            || self
+                .entries
+                .front()
+                .is_some_and(|e| latest_time.saturating_sub(e.header.current_time) > self.max_age)
+        {
+            self.pop_front();
+        }
+    }
+
+    /// Drops the oldest template. The template after it becomes the new base.
+    fn pop_front(&mut self) {
+        if self.entries.pop_front().is_none() {
+            return;
+        }
+        for txid in self.base_txids.iter() {
+            if let Some((_, count)) = self.transactions.get_mut(txid) {
+                *count -= 1;
+                if *count == 0 {
+                    self.transactions.remove(txid);
+                }
+            }
+        }
+        match self.entries.front_mut() {
+            Some(next) => {
+                self.base_txids = next.diff.apply(&self.base_txids);
+                next.diff = TemplateDiff::default();
+            }
+            None => self.clear(),
+        }
+    }
+
+    /// Drops the oldest templates until the oldest template builds on the
+    /// passed block hash. Clears the history if no template does.
+    pub fn drop_templates_not_building_on(&mut self, block_hash: &BlockHash) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|e| e.header.previous_block_hash != *block_hash)
+        {
+            self.pop_front();
+        }
+    }
+
+    fn build_template(
+        &self,
+        header: &GetBlockTemplateResult,
+        txids: &[Txid],
+    ) -> GetBlockTemplateResult {
+        let positions: HashMap<&Txid, usize> = txids
+            .iter()
+            .enumerate()
+            .map(|(i, txid)| (txid, i))
+            .collect();
+        GetBlockTemplateResult {
+            transactions: txids
+                .iter()
+                .filter_map(|txid| self.transactions.get(txid))
+                .map(|(history_tx, _)| GetBlockTemplateResultTransaction {
+                    depends: history_tx
+                        .parents
+                        .iter()
+                        .filter_map(|p| positions.get(p))
+                        .map(|pos| *pos as u32 + 1)
+                        .collect(),
+                    ..history_tx.tx.clone()
+                })
+                .collect(),
+            ..header.clone()
+        }
+    }
+
+    /// Iterates over the templates from oldest to newest. Only the txids of
+    /// the templates are reconstructed.
+    pub fn txids_iter(&self) -> impl Iterator<Item = TemplateTxids<'_>> {
+        let mut txids: Vec<Txid> = vec![];
+        self.entries.iter().enumerate().map(move |(i, entry)| {
+            txids = if i == 0 {
+                self.base_txids.clone()
+            } else {
+                entry.diff.apply(&txids)
+            };
+            TemplateTxids {
+                header: &entry.header,
+                txids: txids.clone(),
+            }
+        })
+    }
+
+    /// Reconstructs the template at the passed index with all transactions.
+    pub fn get(&self, index: usize) -> Option<GetBlockTemplateResult> {
+        if index + 1 == self.entries.len() {
+            return self.latest.clone();
+        }
+        self.txids_iter()
+            .nth(index)
+            .map(|t| self.build_template(t.header, &t.txids))
+    }
+
+    /// Iterates over the fully reconstructed templates from oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = GetBlockTemplateResult> + '_ {
+        self.txids_iter()
+            .map(move |t| self.build_template(t.header, &t.txids))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+
+    const PREV: &str = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+
+    pub fn txid(n: u32) -> String {
+        format!("{:064x}", n)
+    }
+
+    pub fn template(
+        previous_block_hash: &str,
+        current_time: u64,
+        txids: &[String],
+    ) -> GetBlockTemplateResult {
+        let transactions: Vec<serde_json::Value> = txids
+            .iter()
+            .map(|txid| {
+                serde_json::json!({
+                    "data": "00",
+                    "txid": txid,
+                    "hash": txid,
+                    "depends": [],
+                    "fee": 1000,
+                    "sigops": 4,
+                    "weight": 600,
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "capabilities": ["proposal"],
+            "version": 536870912,
+            "rules": ["csv", "!segwit", "taproot"],
+            "vbavailable": {},
+            "vbrequired": 0,
+            "previousblockhash": previous_block_hash,
+            "transactions": transactions,
+            "coinbaseaux": {},
+            "coinbasevalue": 312500000,
+            "longpollid": format!("{}{}", previous_block_hash, current_time),
+            "target": "0000000000000000000342190000000000000000000000000000000000000000",
+            "mintime": 1700000000,
+            "mutable": ["time", "transactions", "prevblock"],
+            "noncerange": "00000000ffffffff",
+            "sigoplimit": 80000,
+            "sizelimit": 4000000,
+            "weightlimit": 4000000,
+            "curtime": current_time,
+            "bits": "17034219",
+            "height": 900000,
+        }))
+        .unwrap()
+    }
+
+    fn txids(ns: &[u32]) -> Vec<Txid> {
+        ns.iter().map(|n| txid(*n).parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_template_diff() {
+        let old = txids(&[1, 2, 3, 4]);
+        let new = txids(&[1, 3, 4, 5, 2]);
+        let diff = TemplateDiff::new(&old, &new);
+        // 2 moved to the end and 5 is new
+        assert_eq!(diff.removed, txids(&[2]));
+        assert_eq!(diff.added, vec![(3, new[3]), (4, new[4])]);
+        assert_eq!(diff.apply(&old), new);
+
+        assert_eq!(TemplateDiff::new(&old, &old), TemplateDiff::default());
+        assert_eq!(TemplateDiff::new(&old, &[]).apply(&old), vec![]);
+        assert_eq!(TemplateDiff::new(&[], &new).apply(&[]), new);
+
+        // shuffled lists with a simple LCG
+        let mut seed: u64 = 42;
+        let mut random = move |max: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % max
+        };
+        for _ in 0..200 {
+            let old: Vec<u32> = (0..random(30) as u32).filter(|_| random(4) > 0).collect();
+            let mut new: Vec<u32> = old.iter().copied().filter(|_| random(5) > 0).collect();
+            for n in 100..(100 + random(10) as u32) {
+                new.insert(random(new.len() as u64 + 1) as usize, n);
+            }
+            for _ in 0..random(4) {
+                if new.len() > 1 {
+                    let a = random(new.len() as u64) as usize;
+                    let b = random(new.len() as u64) as usize;
+                    new.swap(a, b);
+                }
+            }
+            let (old, new) = (txids(&old), txids(&new));
+            assert_eq!(TemplateDiff::new(&old, &new).apply(&old), new);
+        }
+    }
+
+    #[test]
+    fn test_template_history_reconstruction() {
+        let mut t1 = template(PREV, 1700000000, &[txid(1), txid(2), txid(3)]);
+        t1.transactions[2].depends = vec![1, 2];
+        let mut t2 = template(PREV, 1700000010, &[txid(4), txid(1), txid(3)]);
+        t2.transactions[2].depends = vec![2];
+        let t3 = template(PREV, 1700000020, &[txid(5)]);
+        let templates = vec![t1, t2, t3];
+
+        let mut history = TemplateHistory::new(3600, 100);
+        let mut records = vec![];
+        for t in templates.iter() {
+            records.push(history.push(t.clone()));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.transaction_count(), 5);
+        assert_eq!(history.latest(), templates.last());
+        assert_eq!(history.iter().collect::<Vec<_>>(), templates);
+        assert_eq!(history.get(0).as_ref(), templates.first());
+        assert_eq!(history.get(3), None);
+        assert_eq!(
+            history.txids_iter().map(|t| t.txids).collect::<Vec<_>>(),
+            vec![txids(&[1, 2, 3]), txids(&[4, 1, 3]), txids(&[5])]
+        );
+        assert_eq!(
+            history.first_template_times(&txids(&[1, 4, 6]).into_iter().collect()),
+            HashMap::from([(txids(&[1])[0], 1700000000), (txids(&[4])[0], 1700000010)])
+        );
+        // the second record only contains the new transaction body
+        assert_eq!(records[1].new_transactions.len(), 1);
+
+        let mut replayed = TemplateHistory::new(3600, 100);
+        for record in records {
+            replayed.append(record).unwrap();
+        }
+        assert_eq!(replayed.iter().collect::<Vec<_>>(), templates);
+
+        // records can't be appended to a history without the template before
+        let mut history = TemplateHistory::new(3600, 100);
+        history.push(template(PREV, 1700000000, &[txid(1), txid(2)]));
+        let record = history.push(template(PREV, 1700000010, &[txid(2), txid(1)]));
+        assert!(record.new_transactions.is_empty());
+        assert!(TemplateHistory::new(3600, 100).append(record).is_err());
+    }
+
+    #[test]
+    fn test_template_history_bounds() {
+        let templates: Vec<GetBlockTemplateResult> = (0..10)
+            .map(|i| {
+                template(
+                    PREV,
+                    1700000000 + i * 100,
+                    &[txid(1), txid(i as u32 + 10), txid(i as u32 + 11)],
+                )
+            })
+            .collect();
+
+        // by age: templates more than 250 seconds older than the newest are dropped
+        let mut history = TemplateHistory::new(250, 100);
+        for t in templates.iter() {
+            history.push(t.clone());
+        }
+        assert_eq!(history.iter().collect::<Vec<_>>(), templates[7..]);
+        assert_eq!(history.transaction_count(), 5);
+
+        // by count
+        let mut history = TemplateHistory::new(3600, 4);
+        for t in templates.iter() {
+            history.push(t.clone());
+        }
+        assert_eq!(history.iter().collect::<Vec<_>>(), templates[6..]);
+
+        history.clear();
+        assert!(history.is_empty());
+        assert_eq!(history.transaction_count(), 0);
+        assert_eq!(history.latest(), None);
+    }
+
+    #[test]
+    fn test_template_history_take() {
+        let templates: Vec<GetBlockTemplateResult> = (0..5)
+            .map(|i| template(PREV, 1700000000 + i * 100, &[txid(1), txid(i as u32 + 10)]))
+            .collect();
+
+        let mut history = TemplateHistory::new(250, 100);
+        for t in templates.iter() {
+            history.push(t.clone());
+        }
+        let taken = history.take();
+        assert_eq!(taken.iter().collect::<Vec<_>>(), templates[2..]);
+        assert!(history.is_empty());
+        assert_eq!(history.transaction_count(), 0);
+
+        // the emptied history keeps its bounds
+        for t in templates.iter() {
+            history.push(t.clone());
+        }
+        assert_eq!(history.iter().collect::<Vec<_>>(), templates[2..]);
+    }
+
+    #[test]
+    fn test_template_history_drop_templates_not_building_on() {
+        const OTHER_PREV: &str = "0000000000000000000049f29fdbcb3a0ad5d1f1c0a8ba53e0a0fb0ac23a0c6e";
+        let t1 = template(OTHER_PREV, 1700000000, &[txid(1)]);
+        let t2 = template(PREV, 1700000010, &[txid(2)]);
+
+        let mut history = TemplateHistory::new(3600, 100);
+        history.push(t1);
+        history.push(t2.clone());
+        history.drop_templates_not_building_on(&PREV.parse().unwrap());
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec![t2]);
+
+        history.drop_templates_not_building_on(&OTHER_PREV.parse().unwrap());
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use miningpool_observer_shared::bitcoincore_rpc::jsonrpc::serde_json;
+
+use crate::template_history::TemplateRecord;
+#[cfg(test)]
+use miningpool_observer_shared::bitcoincore_rpc::json::GetBlockTemplateResult;
+
+pub const LOG_TARGET_TEMPLATE_STORE: &str = "template_store";
+
+const RECORD_FILE_EXTENSION: &str = "json";
+
+#[derive(Debug)]
+pub enum TemplateStoreError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for TemplateStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateStoreError::Io(e) => write!(f, "{}", e),
+            TemplateStoreError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateStoreError {}
+
+impl From<io::Error> for TemplateStoreError {
+    fn from(err: io::Error) -> Self {
+        TemplateStoreError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for TemplateStoreError {
+    fn from(err: serde_json::Error) -> Self {
+        TemplateStoreError::Json(err)
+    }
+}
+
+/// Persists the template history as one JSON file per template record in a
+/// directory. This allows the daemon to pick up its template history again
+/// after a restart. The records are numbered in the order they were appended.
+/// The directory is owned by the store: on clear(), all files are removed.
+pub struct TemplateStore {
+    dir: PathBuf,
+    next_record: u64,
+}
+
+impl TemplateStore {
+    pub fn new(dir: PathBuf) -> Result<TemplateStore, TemplateStoreError> {
+        fs::create_dir_all(&dir)?;
+        let mut store = TemplateStore {
+            dir,
+            next_record: 0,
+        };
+        store.next_record = match store.record_files()?.last() {
+            Some((number, _)) => number + 1,
+            None => 0,
+        };
+        Ok(store)
+    }
+
+    /// The numbers and paths of the stored records in ascending order.
+    fn record_files(&self) -> Result<Vec<(u64, PathBuf)>, TemplateStoreError> {
+        let mut files: Vec<(u64, PathBuf)> = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(RECORD_FILE_EXTENSION) {
+                continue;
+            }
+            if let Some(number) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                files.push((number, path));
+            }
+        }
+        files.sort_by_key(|(number, _)| *number);
+        Ok(files)
+    }
+
+    /// Appends a template record to the store.
+    pub fn append(&mut self, record: &TemplateRecord) -> Result<(), TemplateStoreError> {
+        let file_name = format!("{:010}.{}", self.next_record, RECORD_FILE_EXTENSION);
+        // Write to a temporary file first and then rename it. This makes
+        // sure we don't end up with a partially written record.
+        let tmp_path = self.dir.join(format!("{}.tmp", file_name));
+        fs::write(&tmp_path, serde_json::to_vec(record)?)?;
+        fs::rename(&tmp_path, self.dir.join(&file_name))?;
+        self.next_record += 1;
+        Ok(())
+    }
+
+    /// Removes all stored records.
+    pub fn clear(&mut self) -> Result<(), TemplateStoreError> {
+        for entry in fs::read_dir(&self.dir)? {
+            fs::remove_file(entry?.path())?;
+        }
+        self.next_record = 0;
+        Ok(())
+    }
+
+    /// Loads the stored records in the order they were appended. As each
+    /// record builds on the records before it, loading stops at the first
+    /// record that can't be parsed.
+    pub fn load(&self) -> Result<Vec<TemplateRecord>, TemplateStoreError> {
+        let mut records: Vec<TemplateRecord> = vec![];
+        for (_, path) in self.record_files()? {
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    log::warn!(
+                        target: LOG_TARGET_TEMPLATE_STORE,
+                        "Could not parse the stored template record {}. Ignoring it and all later records: {}",
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template_history::tests::{template, txid};
+    use crate::template_history::TemplateHistory;
+    use std::env;
+
+    #[test]
+    fn test_template_store_append_and_load() {
+        let dir = env::temp_dir().join(format!(
+            "miningpool-observer-template-store-test-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut store = TemplateStore::new(dir.clone()).unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        const PREV: &str = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+        let t1 = template(PREV, 1700000010, &[txid(1)]);
+        let t2 = template(PREV, 1700000020, &[txid(1), txid(2)]);
+        let t3 = template(PREV, 1700000030, &[txid(2), txid(3)]);
+
+        let mut history = TemplateHistory::new(3600, 100);
+        store.append(&history.push(t1.clone())).unwrap();
+        store.append(&history.push(t2.clone())).unwrap();
+
+        // a reopened store continues after the last record
+        let mut store = TemplateStore::new(dir.clone()).unwrap();
+        store.append(&history.push(t3.clone())).unwrap();
+
+        let mut loaded = TemplateHistory::new(3600, 100);
+        for record in store.load().unwrap() {
+            loaded.append(record).unwrap();
+        }
+        assert_eq!(
+            loaded.iter().collect::<Vec<GetBlockTemplateResult>>(),
+            vec![t1, t2, t3.clone()]
+        );
+
+        // records after an unparseable record are ignored
+        fs::write(dir.join("0000000001.json"), b"not a record").unwrap();
+        assert_eq!(store.load().unwrap().len(), 1);
+
+        store.clear().unwrap();
+        assert!(store.load().unwrap().is_empty());
+        store
+            .append(&TemplateHistory::new(3600, 100).push(t3))
+            .unwrap();
+        assert_eq!(store.load().unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2 @@
+DROP INDEX template_archive_template_time_index;
+DROP TABLE template_archive CASCADE;
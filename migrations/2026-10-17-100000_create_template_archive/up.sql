@@ -0,0 +1,20 @@
+-- stores the block templates requested by the daemon as gzip compressed
+-- BIP22 JSON. Only written if the template archive is enabled in the daemon
+-- config. Allows to rebuild and audit the template and block comparisons.
+-- The selected template of a block can be found with the block height and
+-- template_time.
+
+CREATE TABLE IF NOT EXISTS template_archive (
+    height          INTEGER     NOT NULL,
+    template_time   TIMESTAMP   NOT NULL,
+    prev_hash       BYTEA       NOT NULL,
+    longpollid      TEXT        NOT NULL,
+    bits            BYTEA       NOT NULL,
+    target          BYTEA       NOT NULL,
+    version         INTEGER     NOT NULL,
+    tx_count        INTEGER     NOT NULL,
+    template        BYTEA       NOT NULL,
+    PRIMARY KEY (height, template_time)
+);
+
+CREATE INDEX IF NOT EXISTS template_archive_template_time_index ON template_archive(template_time);
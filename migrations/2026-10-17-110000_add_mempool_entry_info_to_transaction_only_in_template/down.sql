@@ -0,0 +1,9 @@
+ALTER TABLE transaction_only_in_template
+    DROP COLUMN ancestor_count,
+    DROP COLUMN ancestor_vsize,
+    DROP COLUMN descendant_count,
+    DROP COLUMN descendant_vsize,
+    DROP COLUMN fee_delta;
+
+UPDATE transaction_only_in_template SET mempool_age_seconds = -1 WHERE mempool_age_seconds IS NULL;
+ALTER TABLE transaction_only_in_template ALTER COLUMN mempool_age_seconds SET NOT NULL;
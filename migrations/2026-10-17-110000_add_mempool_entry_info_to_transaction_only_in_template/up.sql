@@ -0,0 +1,16 @@
+-- The mempool information of the transactions only in the template is
+-- taken from a single getrawmempool snapshot. Transactions that weren't
+-- found in the mempool have an unknown (NULL) mempool age instead of -1.
+-- The ancestor and descendant sizes are in vByte. The fee_delta is the
+-- difference between the modified and the base fee in sat, for example,
+-- set via prioritisetransaction.
+
+ALTER TABLE transaction_only_in_template ALTER COLUMN mempool_age_seconds DROP NOT NULL;
+UPDATE transaction_only_in_template SET mempool_age_seconds = NULL WHERE mempool_age_seconds < 0;
+
+ALTER TABLE transaction_only_in_template
+    ADD COLUMN ancestor_count      INTEGER,
+    ADD COLUMN ancestor_vsize      INTEGER,
+    ADD COLUMN descendant_count    INTEGER,
+    ADD COLUMN descendant_vsize    INTEGER,
+    ADD COLUMN fee_delta           BIGINT;
@@ -0,0 +1,3 @@
+ALTER TABLE block
+    DROP COLUMN stale,
+    DROP COLUMN reorg_depth;
@@ -0,0 +1,8 @@
+-- Blocks that dropped out of the active chain during a reorg are marked
+-- as stale. The reorg_depth is the number of blocks that were replaced in
+-- the reorg that made the block stale. It's NULL for blocks in the active
+-- chain.
+
+ALTER TABLE block
+    ADD COLUMN stale           BOOLEAN     NOT NULL DEFAULT FALSE,
+    ADD COLUMN reorg_depth     INTEGER;
@@ -0,0 +1,3 @@
+DROP TABLE daemon_run CASCADE;
+DROP INDEX uncompared_block_height_index;
+DROP TABLE uncompared_block CASCADE;
@@ -0,0 +1,25 @@
+-- stores the blocks the daemon saw but couldn't compare to a template.
+-- The reason is stored as integer (see UncomparedReason in the shared
+-- model). Together with the block table, this allows to tell which heights
+-- were observed and where the data has gaps.
+
+CREATE TABLE IF NOT EXISTS uncompared_block (
+    hash            BYTEA       PRIMARY KEY,
+    prev_hash       BYTEA       NOT NULL,
+    height          INTEGER     NOT NULL,
+    block_time      TIMESTAMP   NOT NULL,
+    seen_time       TIMESTAMP   NOT NULL,
+    pool_name       TEXT        NOT NULL,
+    reason          INTEGER     NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS uncompared_block_height_index ON uncompared_block(height);
+
+-- A row per daemon start. The last_seen_time is periodically updated while
+-- the daemon is running. The gaps between the runs are the daemon downtimes.
+
+CREATE TABLE IF NOT EXISTS daemon_run (
+    id              BIGSERIAL   PRIMARY KEY,
+    start_time      TIMESTAMP   NOT NULL,
+    last_seen_time  TIMESTAMP   NOT NULL
+);
@@ -0,0 +1,5 @@
+UPDATE block SET equality = 0.0;
+
+ALTER TABLE block
+    DROP COLUMN fee_weighted_equality,
+    DROP COLUMN weight_weighted_equality;
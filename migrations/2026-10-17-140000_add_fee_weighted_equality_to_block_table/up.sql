@@ -0,0 +1,75 @@
+-- The equality is the Jaccard index of the transactions in the template and
+-- in the block: the shared transactions divided by the transactions in
+-- either of them. The coinbase transaction, which is never part of the
+-- template, isn't counted. The fee_weighted_equality and the
+-- weight_weighted_equality are the same index with each transaction weighted
+-- by the fee it pays and by its weight. All are 1.0 when the template and
+-- block contain the same transactions.
+
+ALTER TABLE block
+    ADD COLUMN fee_weighted_equality    REAL    NOT NULL DEFAULT 0,
+    ADD COLUMN weight_weighted_equality REAL    NOT NULL DEFAULT 0;
+
+-- Backfill the existing blocks. The coinbase transaction is always one of
+-- the extra transactions.
+
+UPDATE block SET equality = CASE
+    WHEN missing_tx + shared_tx + GREATEST(extra_tx - 1, 0) = 0 THEN 1.0
+    ELSE shared_tx::REAL / (missing_tx + shared_tx + GREATEST(extra_tx - 1, 0))
+END;
+
+-- The fees of the shared transactions are the block fees without the fees
+-- of the extra transactions. The fees of all transactions are the block
+-- fees plus the fees of the missing transactions.
+
+UPDATE block SET fee_weighted_equality = CASE
+    WHEN block.block_cb_fees + fees.missing_fees = 0 THEN 1.0
+    ELSE (block.block_cb_fees - fees.extra_fees)::REAL / (block.block_cb_fees + fees.missing_fees)
+END
+FROM (
+    SELECT
+        b.id,
+        COALESCE((
+            SELECT SUM(t.fee)
+            FROM transaction_only_in_block AS tb
+            JOIN transaction AS t ON t.txid = tb.transaction_txid
+            WHERE tb.block_id = b.id
+        ), 0) AS extra_fees,
+        COALESCE((
+            SELECT SUM(t.fee)
+            FROM transaction_only_in_template AS tt
+            JOIN transaction AS t ON t.txid = tt.transaction_txid
+            WHERE tt.block_id = b.id
+        ), 0) AS missing_fees
+    FROM block AS b
+) AS fees
+WHERE fees.id = block.id;
+
+-- The weight of the shared transactions is the template weight without the
+-- weight of the missing transactions. The weight of all transactions is the
+-- template weight plus the weight of the extra transactions. The coinbase
+-- transaction at position 0 isn't counted. Only the vsize of the
+-- transactions is stored, their weight is approximated as four times it.
+
+UPDATE block SET weight_weighted_equality = CASE
+    WHEN block.template_weight + weights.extra_weight = 0 THEN 1.0
+    ELSE GREATEST(block.template_weight - weights.missing_weight, 0)::REAL / (block.template_weight + weights.extra_weight)
+END
+FROM (
+    SELECT
+        b.id,
+        COALESCE((
+            SELECT SUM(t.vsize * 4)
+            FROM transaction_only_in_block AS tb
+            JOIN transaction AS t ON t.txid = tb.transaction_txid
+            WHERE tb.block_id = b.id AND tb.position > 0
+        ), 0) AS extra_weight,
+        COALESCE((
+            SELECT SUM(t.vsize * 4)
+            FROM transaction_only_in_template AS tt
+            JOIN transaction AS t ON t.txid = tt.transaction_txid
+            WHERE tt.block_id = b.id
+        ), 0) AS missing_weight
+    FROM block AS b
+) AS weights
+WHERE weights.id = block.id;
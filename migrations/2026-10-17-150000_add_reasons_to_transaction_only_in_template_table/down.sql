@@ -0,0 +1,2 @@
+ALTER TABLE transaction_only_in_template
+    DROP COLUMN reasons;
@@ -0,0 +1,7 @@
+-- The reasons why a transaction only in the template might be missing from
+-- the block, see `tags::MissingReason`. Existing rows aren't backfilled and
+-- keep an empty array, as the mempool state at the time of the block is no
+-- longer known.
+
+ALTER TABLE transaction_only_in_template
+    ADD COLUMN reasons INTEGER[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+ALTER TABLE transaction_only_in_block
+    DROP COLUMN labels,
+    DROP COLUMN reject_reason;
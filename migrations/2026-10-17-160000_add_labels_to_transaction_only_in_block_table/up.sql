@@ -0,0 +1,8 @@
+-- The labels of a transaction only in the block, see `tags::ExtraTxLabel`,
+-- and the testmempoolaccept reject reason of our node if it wouldn't accept
+-- the transaction. Existing rows aren't backfilled: they keep an empty
+-- array and no reject reason.
+
+ALTER TABLE transaction_only_in_block
+    ADD COLUMN labels INTEGER[] NOT NULL DEFAULT '{}',
+    ADD COLUMN reject_reason TEXT;
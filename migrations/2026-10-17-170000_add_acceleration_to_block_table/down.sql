@@ -0,0 +1,3 @@
+ALTER TABLE block
+    DROP COLUMN accelerated_tx,
+    DROP COLUMN accelerated_fee_forgone;
@@ -0,0 +1,7 @@
+-- The number of transactions only in the block that were likely
+-- accelerated and the fees in sat the pool gave up by including them.
+-- Existing blocks aren't backfilled and default to zero.
+
+ALTER TABLE block
+    ADD COLUMN accelerated_tx INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN accelerated_fee_forgone BIGINT NOT NULL DEFAULT 0;
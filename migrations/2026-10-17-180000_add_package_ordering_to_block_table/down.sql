@@ -0,0 +1,5 @@
+ALTER TABLE block
+    DROP COLUMN shared_tx_block_positions,
+    DROP COLUMN shared_tx_template_positions,
+    DROP COLUMN out_of_order_pkgs,
+    DROP COLUMN ordering_conformance;
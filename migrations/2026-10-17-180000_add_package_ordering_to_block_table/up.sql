@@ -0,0 +1,10 @@
+-- The positions of the shared transactions in the block and in the
+-- template, the number of block packages out of feerate order, and the
+-- share of adjacent packages in feerate order. The conformance is NULL for
+-- blocks with less than two packages. Existing blocks aren't backfilled.
+
+ALTER TABLE block
+    ADD COLUMN shared_tx_block_positions INTEGER[] NOT NULL DEFAULT '{}',
+    ADD COLUMN shared_tx_template_positions INTEGER[] NOT NULL DEFAULT '{}',
+    ADD COLUMN out_of_order_pkgs INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN ordering_conformance REAL;
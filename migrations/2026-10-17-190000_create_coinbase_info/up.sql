@@ -0,0 +1,19 @@
+-- stores the parsed coinbase transaction of a block. The merged mining
+-- chains are stored as integers (see MergedMiningChain in the shared model)
+-- with the commitment at the same array index. The payout outputs are the
+-- coinbase outputs that aren't OP_RETURN outputs.
+
+CREATE TABLE IF NOT EXISTS coinbase_info (
+    block_id                    BIGINT      PRIMARY KEY REFERENCES block(id),
+    script_sig                  BYTEA       NOT NULL,
+    script_sig_tag              TEXT        NOT NULL,
+    bip34_height                INTEGER,
+    extranonce_len              INTEGER,
+    witness_commitment          BYTEA,
+    merged_mining_chains        INTEGER[]   NOT NULL,
+    merged_mining_commitments   BYTEA[]     NOT NULL,
+    payout_outputs              INTEGER     NOT NULL,
+    payout_scripts              BYTEA[]     NOT NULL,
+    payout_addresses            TEXT[]      NOT NULL,
+    payout_values               BIGINT[]    NOT NULL
+);
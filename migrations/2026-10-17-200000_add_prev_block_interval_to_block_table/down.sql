@@ -0,0 +1,2 @@
+ALTER TABLE block
+    DROP COLUMN prev_block_interval;
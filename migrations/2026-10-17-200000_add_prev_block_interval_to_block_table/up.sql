@@ -0,0 +1,6 @@
+-- The seconds between the header timestamps of the block and its parent.
+-- Can be negative. NULL if the parent header isn't known, which includes
+-- all existing blocks as they aren't backfilled.
+
+ALTER TABLE block
+    ADD COLUMN prev_block_interval INTEGER;
@@ -0,0 +1,5 @@
+-- The version field of the block header, used to check which deployments
+-- a block signals for. NULL for existing blocks as they aren't backfilled.
+
+ALTER TABLE block
+    ADD COLUMN version INTEGER;
@@ -0,0 +1,4 @@
+ALTER TABLE block
+    DROP COLUMN missing_tx_fees,
+    DROP COLUMN extra_tx_fees,
+    DROP COLUMN fee_delta_residual;
@@ -0,0 +1,45 @@
+-- The fee difference between a block and its template (block_cb_fees minus
+-- template_cb_fees) is split into the fees of the missing transactions, the
+-- fees of the extra transactions and a residual. Transactions in a
+-- conflicting set are left out of the missing and extra fees. Their fees
+-- end up in the residual together with other replacements.
+
+ALTER TABLE block
+    ADD COLUMN missing_tx_fees      BIGINT  NOT NULL DEFAULT 0,
+    ADD COLUMN extra_tx_fees        BIGINT  NOT NULL DEFAULT 0,
+    ADD COLUMN fee_delta_residual   BIGINT  NOT NULL DEFAULT 0;
+
+-- Backfill the existing blocks.
+
+UPDATE block SET
+    missing_tx_fees = fees.missing_fees,
+    extra_tx_fees = fees.extra_fees,
+    fee_delta_residual = block.block_cb_fees - block.template_cb_fees - fees.extra_fees + fees.missing_fees
+FROM (
+    SELECT
+        b.id,
+        COALESCE((
+            SELECT SUM(t.fee)
+            FROM transaction_only_in_block AS tb
+            JOIN transaction AS t ON t.txid = tb.transaction_txid
+            WHERE tb.block_id = b.id
+            AND NOT EXISTS (
+                SELECT 1
+                FROM conflicting_transactions AS c
+                WHERE c.block_id = b.id AND tb.transaction_txid = ANY(c.block_txids)
+            )
+        ), 0) AS extra_fees,
+        COALESCE((
+            SELECT SUM(t.fee)
+            FROM transaction_only_in_template AS tt
+            JOIN transaction AS t ON t.txid = tt.transaction_txid
+            WHERE tt.block_id = b.id
+            AND NOT EXISTS (
+                SELECT 1
+                FROM conflicting_transactions AS c
+                WHERE c.block_id = b.id AND tt.transaction_txid = ANY(c.template_txids)
+            )
+        ), 0) AS missing_fees
+    FROM block AS b
+) AS fees
+WHERE fees.id = block.id;
@@ -0,0 +1,3 @@
+ALTER TABLE sanctioned_transaction_info
+    DROP COLUMN control_tx,
+    DROP COLUMN control_missing_tx;
@@ -0,0 +1,9 @@
+-- The controls of a sanctioned template transaction are the non-sanctioned
+-- template transactions with a similar package feerate and mempool age. A
+-- control matching multiple sanctioned transactions is only counted for the
+-- one with the closest package feerate. The share of them missing from the
+-- block is the expected miss rate at this feerate and age.
+
+ALTER TABLE sanctioned_transaction_info
+    ADD COLUMN control_tx           INTEGER,
+    ADD COLUMN control_missing_tx   INTEGER;
@@ -0,0 +1,11 @@
+DELETE FROM template_archive AS a
+    USING template_archive AS b
+    WHERE a.height = b.height
+    AND a.template_time = b.template_time
+    AND a.txids_hash > b.txids_hash;
+
+ALTER TABLE template_archive
+    DROP CONSTRAINT template_archive_pkey,
+    ADD PRIMARY KEY (height, template_time);
+
+ALTER TABLE template_archive DROP COLUMN txids_hash;
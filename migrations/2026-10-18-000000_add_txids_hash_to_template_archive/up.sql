@@ -0,0 +1,16 @@
+-- Templates are only requested with a one second resolution. Multiple
+-- templates at the same height and second, for example after a longpoll
+-- returned, collided on the (height, template_time) primary key and all
+-- but the first were dropped. The SHA256 of the template txids in template
+-- order is added to the primary key, so only templates with the same
+-- transactions in the same second are treated as duplicates. Existing
+-- templates get an empty hash.
+
+ALTER TABLE template_archive
+    ADD COLUMN txids_hash   BYTEA   NOT NULL DEFAULT '';
+
+ALTER TABLE template_archive ALTER COLUMN txids_hash DROP DEFAULT;
+
+ALTER TABLE template_archive
+    DROP CONSTRAINT template_archive_pkey,
+    ADD PRIMARY KEY (height, template_time, txids_hash);
@@ -1,8 +1,9 @@
+use std::convert::TryFrom;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::{env, error, fmt, fs, io};
 
-use bitcoincore_rpc::bitcoin::Network;
+use bitcoincore_rpc::bitcoin::{block, Network};
 use bitcoincore_rpc::Auth;
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
@@ -14,6 +15,7 @@ const DEFAULT_SANCTIONED_ADDRESSES_URL: &str = "https://raw.githubusercontent.co
 const DEFAULT_POOL_IDENTIFICATOIN_DATASET_URL: &str =
     "https://raw.githubusercontent.com/bitcoin-data/mining-pools/generated/pool-list.json";
 const DEFAULT_POOL_IDENTIFICATOIN_NETWORK: Network = Network::Bitcoin;
+const DEFAULT_ZMQ_TOPIC: &str = "hashblock";
 
 #[derive(Deserialize)]
 struct DaemonTomlConfig {
@@ -25,9 +27,16 @@ struct DaemonTomlConfig {
     database_url: String,
     log_level: String,
     retag_transactions: bool,
+    template_longpolling: Option<bool>,
+    template_history_path: Option<PathBuf>,
+    retry_queue_path: Option<PathBuf>,
     prometheus: PrometheusConfig,
     sanctioned_addresses_url: Option<String>,
     pool_identificatoin: Option<PoolIdentificationTomlConfig>,
+    zmq: Option<ZmqTomlConfig>,
+    template_archive: Option<TemplateArchiveConfig>,
+    replay: Option<ReplayConfig>,
+    deployments: Option<Vec<Deployment>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -74,15 +83,113 @@ impl From<PoolIdentificationTomlConfig> for PoolIdentificationConfig {
     }
 }
 
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TemplateArchiveConfig {
+    pub enable: bool,
+    /// Number of days archived templates are kept. Kept forever if not set.
+    pub retention_days: Option<u32>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    /// Directory with the recorded templates, blocks and fees to replay.
+    pub directory: PathBuf,
+    /// If set, the processed blocks are written as JSON files into this
+    /// directory instead of into the database.
+    pub output_directory: Option<PathBuf>,
+}
+
+/// A soft fork deployment signaled with a BIP9 version bit. Signaling is
+/// tracked from the start_height on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Deployment {
+    pub name: String,
+    pub bit: u8,
+    pub start_height: u32,
+    /// First height the deployment isn't tracked anymore, e.g. after the
+    /// lock-in. Tracked indefinitely if not set.
+    pub timeout_height: Option<u32>,
+}
+
+impl Deployment {
+    pub fn is_active(&self, height: u32) -> bool {
+        height >= self.start_height && self.timeout_height.is_none_or(|t| height < t)
+    }
+
+    pub fn is_signaled_by(&self, version: i32) -> bool {
+        block::Version::from_consensus(version).is_signalling_soft_fork(self.bit)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ZmqTomlConfig {
+    pub address: String,
+    pub topic: Option<String>,
+}
+
+/// The Bitcoin Core ZMQ topic to subscribe to for block notifications.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZmqTopic {
+    /// `zmqpubhashblock`: the hash of each connected block.
+    HashBlock,
+    /// `zmqpubsequence`: block connect and disconnect (and mempool) events.
+    Sequence,
+}
+
+impl ZmqTopic {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZmqTopic::HashBlock => "hashblock",
+            ZmqTopic::Sequence => "sequence",
+        }
+    }
+}
+
+impl FromStr for ZmqTopic {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hashblock" => Ok(ZmqTopic::HashBlock),
+            "sequence" => Ok(ZmqTopic::Sequence),
+            _ => Err(ConfigError::InvalidZmqTopic(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ZmqConfig {
+    pub address: String,
+    pub topic: ZmqTopic,
+}
+
+impl TryFrom<ZmqTomlConfig> for ZmqConfig {
+    type Error = ConfigError;
+
+    fn try_from(toml: ZmqTomlConfig) -> Result<Self, Self::Error> {
+        Ok(ZmqConfig {
+            address: toml.address,
+            topic: ZmqTopic::from_str(&toml.topic.unwrap_or(DEFAULT_ZMQ_TOPIC.to_string()))?,
+        })
+    }
+}
+
 pub struct DaemonConfig {
     pub rpc_url: String,
     pub rpc_auth: Auth,
     pub database_url: String,
     pub log_level: LevelFilter,
     pub retag_transactions: bool,
+    pub template_longpolling: bool,
+    pub template_history_path: Option<PathBuf>,
+    pub retry_queue_path: Option<PathBuf>,
     pub prometheus: PrometheusConfig,
     pub sanctioned_addresses_url: String,
     pub pool_identification: PoolIdentificationConfig,
+    pub zmq: Option<ZmqConfig>,
+    pub template_archive: TemplateArchiveConfig,
+    pub replay: Option<ReplayConfig>,
+    pub deployments: Vec<Deployment>,
 }
 
 pub fn load_daemon_config() -> Result<DaemonConfig, ConfigError> {
@@ -109,17 +216,29 @@ pub fn load_daemon_config() -> Result<DaemonConfig, ConfigError> {
 
     let log_level = LevelFilter::from_str(&config.log_level)?;
 
+    let zmq = match config.zmq {
+        Some(zmq) => Some(ZmqConfig::try_from(zmq)?),
+        None => None,
+    };
+
     return Ok(DaemonConfig {
         rpc_url: format!("http://{}:{}", config.rpc_host, config.rpc_port),
         rpc_auth,
         database_url: config.database_url,
         log_level,
         retag_transactions: config.retag_transactions,
+        template_longpolling: config.template_longpolling.unwrap_or(false),
+        template_history_path: config.template_history_path,
+        retry_queue_path: config.retry_queue_path,
         prometheus: config.prometheus,
         sanctioned_addresses_url: config
             .sanctioned_addresses_url
             .unwrap_or(DEFAULT_SANCTIONED_ADDRESSES_URL.to_string()),
         pool_identification: config.pool_identificatoin.unwrap_or_default().into(),
+        zmq,
+        template_archive: config.template_archive.unwrap_or_default(),
+        replay: config.replay,
+        deployments: config.deployments.unwrap_or_default(),
     });
 }
 
@@ -138,6 +257,7 @@ pub struct WebTomlConfig {
     debug_pages: Option<bool>,
     www_dir_path: String,
     site: WebSiteConfig,
+    deployments: Option<Vec<Deployment>>,
 }
 
 #[derive(Clone)]
@@ -148,6 +268,7 @@ pub struct WebConfig {
     pub debug_pages: bool,
     pub www_dir_path: String,
     pub site: WebSiteConfig,
+    pub deployments: Vec<Deployment>,
 }
 
 pub fn load_web_config() -> Result<WebConfig, ConfigError> {
@@ -163,6 +284,7 @@ pub fn load_web_config() -> Result<WebConfig, ConfigError> {
         debug_pages: config.debug_pages.unwrap_or(false),
         www_dir_path: config.www_dir_path,
         site: config.site,
+        deployments: config.deployments.unwrap_or_default(),
     })
 }
 
@@ -171,6 +293,7 @@ pub enum ConfigError {
     CookieFileDoesNotExist,
     NoRpcAuth,
     InvalidLogLevel(log::ParseLevelError),
+    InvalidZmqTopic(String),
     TomlError(toml::de::Error),
     ReadError(io::Error),
 }
@@ -181,6 +304,7 @@ impl fmt::Display for ConfigError {
             ConfigError::CookieFileDoesNotExist => write!(f, "the .cookie file path set via rpc_cookie_file does not exist"),
             ConfigError::NoRpcAuth => write!(f, "please specify a Bitcoin Core RPC .cookie file (option: 'rpc_cookie_file') or a rpc_user and rpc_password"),
             ConfigError::InvalidLogLevel(e) => write!(f, "the specified log level is invalid: {}", e),
+            ConfigError::InvalidZmqTopic(t) => write!(f, "the specified ZMQ topic '{}' is invalid: use either 'hashblock' or 'sequence'", t),
             ConfigError::TomlError(e) => write!(f, "the TOML in the configuration file could not be parsed: {}", e),
             ConfigError::ReadError(e) => write!(f, "the configuration file could not be read: {}", e),
         }
@@ -192,6 +316,7 @@ impl error::Error for ConfigError {
         match *self {
             ConfigError::NoRpcAuth => None,
             ConfigError::CookieFileDoesNotExist => None,
+            ConfigError::InvalidZmqTopic(_) => None,
             ConfigError::TomlError(ref e) => Some(e),
             ConfigError::ReadError(ref e) => Some(e),
             ConfigError::InvalidLogLevel(ref e) => Some(e),
@@ -239,4 +364,25 @@ mod tests {
             EXAMPLE_DAEMON_CONFIG
         ));
     }
+
+    #[test]
+    fn test_deployment() {
+        use crate::config::Deployment;
+
+        let taproot = Deployment {
+            name: "taproot".to_string(),
+            bit: 2,
+            start_height: 681408,
+            timeout_height: Some(687456),
+        };
+        assert!(!taproot.is_active(681407));
+        assert!(taproot.is_active(681408));
+        assert!(!taproot.is_active(687456));
+        assert!(taproot.is_signaled_by(0x20000004));
+        println!("Version rolling doesn't affect the deployment bit");
+        assert!(taproot.is_signaled_by(0x3fffe004));
+        assert!(!taproot.is_signaled_by(0x20000000));
+        println!("Versions without the BIP9 top bits don't signal");
+        assert!(!taproot.is_signaled_by(0x00000004));
+    }
 }
@@ -1,14 +1,15 @@
 use super::schema::{
-    block, conflicting_transactions, debug_template_selection, sanctioned_addresses,
-    sanctioned_transaction_info, sanctioned_utxo, sanctioned_utxo_scan_info, transaction,
-    transaction_only_in_block, transaction_only_in_template,
+    block, coinbase_info, conflicting_transactions, debug_template_selection, sanctioned_addresses,
+    sanctioned_transaction_info, sanctioned_utxo, sanctioned_utxo_scan_info, template_archive,
+    transaction, transaction_only_in_block, transaction_only_in_template, uncompared_block,
 };
 
 use bitcoincore_rpc::json::serde_hex;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use std::convert::TryFrom;
 use std::hash::{Hash, Hasher};
 
 // Database models shared between web and daemon.
@@ -31,6 +32,8 @@ pub struct Block {
     /// Count of sanctioned transactions present in the template,
     /// but not present in the block.
     pub sanctioned_missing_tx: i32,
+    /// Jaccard index of the template and block transactions. The coinbase
+    /// transaction isn't counted.
     pub equality: f32,
     pub block_time: NaiveDateTime,
     pub block_seen_time: NaiveDateTime,
@@ -59,12 +62,52 @@ pub struct Block {
     pub template_pkg_feerates: Vec<f32>,
     pub template_sigops: i64,
     pub block_sigops: i64,
+    /// True if the block dropped out of the active chain.
+    pub stale: bool,
+    /// Number of blocks replaced in the reorg that made the block stale.
+    pub reorg_depth: Option<i32>,
+    /// Like the equality, but each transaction is weighted by its fee.
+    pub fee_weighted_equality: f32,
+    /// Like the equality, but each transaction is weighted by its weight.
+    pub weight_weighted_equality: f32,
+    /// Count of transactions only in the block that were likely accelerated.
+    pub accelerated_tx: i32,
+    /// Estimated fees in sat the pool gave up by including the accelerated
+    /// transactions instead of transactions from the open fee market.
+    pub accelerated_fee_forgone: i64,
+    /// Positions of the shared transactions in the block in ascending order.
+    pub shared_tx_block_positions: Vec<i32>,
+    /// Positions of the shared transactions in the template. The n-th
+    /// position belongs to the n-th shared transaction in the block.
+    pub shared_tx_template_positions: Vec<i32>,
+    /// Count of block packages followed by a package paying a considerably
+    /// higher feerate.
+    pub out_of_order_pkgs: i32,
+    /// Share of the adjacent block packages in feerate order. None if the
+    /// block has less than two packages.
+    pub ordering_conformance: Option<f32>,
+    /// Seconds between the header timestamps of the block and its parent.
+    /// None if the parent header isn't known.
+    pub prev_block_interval: Option<i32>,
+    /// The block header version. None for blocks processed before the
+    /// version was stored.
+    pub version: Option<i32>,
+    /// Fees in sat of the template transactions missing from the block.
+    /// Conflicting transactions aren't counted.
+    pub missing_tx_fees: i64,
+    /// Fees in sat of the extra transactions in the block. Conflicting
+    /// transactions aren't counted.
+    pub extra_tx_fees: i64,
+    /// Part of the fee difference between block and template not explained
+    /// by the missing and extra transactions. Caused by conflicting
+    /// transactions and replacements.
+    pub fee_delta_residual: i64,
 }
 
 /// This is used to construct a [Block] for insertion into the database.
 /// Compared to [Block] this does not contain the id field.
 /// diesel.rs needs two types for inserting something with and SERIAL as id.
-#[derive(Insertable, Serialize, Debug)]
+#[derive(Insertable, Serialize, Deserialize, Debug)]
 #[diesel(table_name = block)]
 pub struct NewBlock {
     #[serde(with = "serde_hex")]
@@ -79,6 +122,8 @@ pub struct NewBlock {
     /// Count of sanctioned transactions present in the template,
     /// but not present in the block.
     pub sanctioned_missing_tx: i32,
+    /// Jaccard index of the template and block transactions. The coinbase
+    /// transaction isn't counted.
     pub equality: f32,
     pub block_time: NaiveDateTime,
     pub block_seen_time: NaiveDateTime,
@@ -107,9 +152,56 @@ pub struct NewBlock {
     pub template_pkg_feerates: Vec<f32>,
     pub template_sigops: i64,
     pub block_sigops: i64,
+    /// Like the equality, but each transaction is weighted by its fee.
+    #[serde(default)]
+    pub fee_weighted_equality: f32,
+    /// Like the equality, but each transaction is weighted by its weight.
+    #[serde(default)]
+    pub weight_weighted_equality: f32,
+    /// Count of transactions only in the block that were likely accelerated.
+    #[serde(default)]
+    pub accelerated_tx: i32,
+    /// Estimated fees in sat the pool gave up by including the accelerated
+    /// transactions instead of transactions from the open fee market.
+    #[serde(default)]
+    pub accelerated_fee_forgone: i64,
+    /// Positions of the shared transactions in the block in ascending order.
+    #[serde(default)]
+    pub shared_tx_block_positions: Vec<i32>,
+    /// Positions of the shared transactions in the template. The n-th
+    /// position belongs to the n-th shared transaction in the block.
+    #[serde(default)]
+    pub shared_tx_template_positions: Vec<i32>,
+    /// Count of block packages followed by a package paying a considerably
+    /// higher feerate.
+    #[serde(default)]
+    pub out_of_order_pkgs: i32,
+    /// Share of the adjacent block packages in feerate order. None if the
+    /// block has less than two packages.
+    #[serde(default)]
+    pub ordering_conformance: Option<f32>,
+    /// Seconds between the header timestamps of the block and its parent.
+    /// None if the parent header isn't known.
+    #[serde(default)]
+    pub prev_block_interval: Option<i32>,
+    /// The block header version.
+    #[serde(default)]
+    pub version: Option<i32>,
+    /// Fees in sat of the template transactions missing from the block.
+    /// Conflicting transactions aren't counted.
+    #[serde(default)]
+    pub missing_tx_fees: i64,
+    /// Fees in sat of the extra transactions in the block. Conflicting
+    /// transactions aren't counted.
+    #[serde(default)]
+    pub extra_tx_fees: i64,
+    /// Part of the fee difference between block and template not explained
+    /// by the missing and extra transactions.
+    #[serde(default)]
+    pub fee_delta_residual: i64,
 }
 
-#[derive(Debug, Insertable, Queryable, Serialize, Clone)]
+#[derive(Debug, Insertable, Queryable, Serialize, Deserialize, Clone)]
 #[diesel(table_name = transaction)]
 pub struct Transaction {
     #[serde(with = "serde_hex")]
@@ -132,24 +224,43 @@ impl Hash for Transaction {
     }
 }
 
-#[derive(Insertable, Queryable, Serialize)]
+#[derive(Insertable, Queryable, Serialize, Deserialize)]
 #[diesel(table_name = transaction_only_in_block)]
 pub struct TransactionOnlyInBlock {
     pub block_id: i64,
     pub position: i32,
     pub transaction_txid: Vec<u8>,
+    /// Labels explaining why the transaction might not have been in the
+    /// template. See `tags::ExtraTxLabel`.
+    #[serde(default)]
+    pub labels: Vec<i32>,
+    /// The testmempoolaccept reject reason if our node rejects the
+    /// transaction as non-standard.
+    #[serde(default)]
+    pub reject_reason: Option<String>,
 }
 
-#[derive(Insertable, Queryable, Serialize, Clone)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Clone)]
 #[diesel(table_name = transaction_only_in_template)]
 pub struct TransactionOnlyInTemplate {
     pub block_id: i64,
     pub position: i32,
-    pub mempool_age_seconds: i32,
+    /// None if the transaction wasn't found in the mempool.
+    pub mempool_age_seconds: Option<i32>,
     pub transaction_txid: Vec<u8>,
+    pub ancestor_count: Option<i32>,
+    pub ancestor_vsize: Option<i32>,
+    pub descendant_count: Option<i32>,
+    pub descendant_vsize: Option<i32>,
+    /// Difference between the modified and the base fee in sat.
+    pub fee_delta: Option<i64>,
+    /// Reasons why the transaction might be missing from the block. See
+    /// `tags::MissingReason`.
+    #[serde(default)]
+    pub reasons: Vec<i32>,
 }
 
-#[derive(Insertable, Queryable, Serialize)]
+#[derive(Insertable, Queryable, Serialize, Deserialize)]
 #[diesel(table_name = sanctioned_transaction_info)]
 pub struct SanctionedTransactionInfo {
     pub block_id: i64,
@@ -157,9 +268,18 @@ pub struct SanctionedTransactionInfo {
     pub in_block: bool,
     pub in_template: bool,
     pub addresses: Vec<String>,
+    /// Count of non-sanctioned template transactions with a similar package
+    /// feerate and mempool age. A control is only counted for the sanctioned transaction with
+    /// the closest package feerate. None if the transaction isn't in the
+    /// template or for transactions processed before the controls were stored.
+    #[serde(default)]
+    pub control_tx: Option<i32>,
+    /// Count of the controls missing from the block.
+    #[serde(default)]
+    pub control_missing_tx: Option<i32>,
 }
 
-#[derive(Insertable, Queryable, Serialize, Debug)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Debug)]
 #[diesel(table_name = conflicting_transactions)]
 pub struct ConflictingTransaction {
     pub block_id: i64,
@@ -169,7 +289,7 @@ pub struct ConflictingTransaction {
     pub conflicting_outpoints_vouts: Vec<i32>,
 }
 
-#[derive(Insertable, Queryable, Serialize, Debug)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Debug)]
 #[diesel(table_name = sanctioned_utxo)]
 pub struct SanctionedUtxo {
     #[serde(with = "serde_hex")]
@@ -190,7 +310,7 @@ pub struct SanctionedUtxoScanInfo {
     pub utxo_count: i32,
 }
 
-#[derive(Insertable, Queryable, Serialize, Debug, Clone)]
+#[derive(Insertable, Queryable, Serialize, Deserialize, Debug, Clone)]
 #[diesel(table_name = debug_template_selection)]
 pub struct DebugTemplateSelectionInfo {
     pub block_id: i64,
@@ -201,6 +321,140 @@ pub struct DebugTemplateSelectionInfo {
     pub selected: bool,
 }
 
+/// A block template in the template archive. The template is stored as gzip
+/// compressed BIP22 JSON.
+#[derive(Insertable, Queryable, Debug, Clone)]
+#[diesel(table_name = template_archive)]
+pub struct ArchivedTemplate {
+    pub height: i32,
+    pub template_time: NaiveDateTime,
+    pub prev_hash: Vec<u8>,
+    pub longpollid: String,
+    pub bits: Vec<u8>,
+    pub target: Vec<u8>,
+    pub version: i32,
+    pub tx_count: i32,
+    pub template: Vec<u8>,
+    /// SHA256 of the template txids in template order. Tells templates
+    /// requested in the same second apart.
+    pub txids_hash: Vec<u8>,
+}
+
+/// A block the daemon saw but couldn't compare to a template.
+#[derive(Insertable, Queryable, Serialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = uncompared_block)]
+pub struct UncomparedBlock {
+    #[serde(with = "serde_hex")]
+    pub hash: Vec<u8>,
+    #[serde(with = "serde_hex")]
+    pub prev_hash: Vec<u8>,
+    pub height: i32,
+    pub block_time: NaiveDateTime,
+    /// The time the daemon saw the block. The block_time for blocks mined
+    /// during a downtime of the daemon.
+    pub seen_time: NaiveDateTime,
+    pub pool_name: String,
+    /// An [UncomparedReason].
+    pub reason: i32,
+}
+
+/// Why a block couldn't be compared to a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UncomparedReason {
+    // the value is important for database backwards compatibilty
+    // make sure to add new reasons to the try_from fn below!
+    /// There was no template building on the parent of the block. For
+    /// example, when multiple blocks were found between two template
+    /// requests.
+    NoTemplate = 1,
+    /// The block couldn't be requested from Bitcoin Core.
+    RpcFailure = 2,
+    /// The block was found while the daemon wasn't running.
+    Downtime = 3,
+}
+
+impl TryFrom<i32> for UncomparedReason {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            x if x == UncomparedReason::NoTemplate as i32 => Ok(UncomparedReason::NoTemplate),
+            x if x == UncomparedReason::RpcFailure as i32 => Ok(UncomparedReason::RpcFailure),
+            x if x == UncomparedReason::Downtime as i32 => Ok(UncomparedReason::Downtime),
+            _ => Err(()),
+        }
+    }
+}
+
+impl UncomparedReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UncomparedReason::NoTemplate => "no template",
+            UncomparedReason::RpcFailure => "RPC failure",
+            UncomparedReason::Downtime => "daemon downtime",
+        }
+    }
+}
+
+/// The parsed coinbase transaction of a block.
+#[derive(Insertable, Queryable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[diesel(table_name = coinbase_info)]
+pub struct CoinbaseInfo {
+    pub block_id: i64,
+    #[serde(with = "serde_hex")]
+    pub script_sig: Vec<u8>,
+    /// The printable text in the scriptSig, e.g. the pool name.
+    pub script_sig_tag: String,
+    pub bip34_height: Option<i32>,
+    /// Estimated length of the extranonce in the scriptSig. None if no
+    /// extranonce could be found.
+    pub extranonce_len: Option<i32>,
+    #[serde(with = "serde_hex::opt")]
+    pub witness_commitment: Option<Vec<u8>>,
+    /// A [MergedMiningChain] per commitment in merged_mining_commitments.
+    pub merged_mining_chains: Vec<i32>,
+    pub merged_mining_commitments: Vec<Vec<u8>>,
+    /// Count of the coinbase outputs that aren't OP_RETURN outputs.
+    pub payout_outputs: i32,
+    pub payout_scripts: Vec<Vec<u8>>,
+    /// The address of each payout script. Empty if the script has no address.
+    pub payout_addresses: Vec<String>,
+    pub payout_values: Vec<i64>,
+}
+
+/// A merged mining chain committed to in a coinbase transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergedMiningChain {
+    // the value is important for database backwards compatibilty
+    // make sure to add new chains to the try_from fn below!
+    /// Rootstock commits to its block with an `RSKBLOCK:` OP_RETURN output.
+    Rsk = 1,
+    /// AuxPoW chains, like Namecoin, commit to their merkle root in the
+    /// scriptSig after the `fabe6d6d` magic bytes.
+    AuxPow = 2,
+}
+
+impl TryFrom<i32> for MergedMiningChain {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            x if x == MergedMiningChain::Rsk as i32 => Ok(MergedMiningChain::Rsk),
+            x if x == MergedMiningChain::AuxPow as i32 => Ok(MergedMiningChain::AuxPow),
+            _ => Err(()),
+        }
+    }
+}
+
+impl MergedMiningChain {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MergedMiningChain::Rsk => "RSK",
+            MergedMiningChain::AuxPow => "AuxPoW",
+        }
+    }
+}
+
 #[derive(Insertable, Queryable, Serialize, Debug, Clone)]
 #[diesel(table_name = sanctioned_addresses)]
 pub struct SanctionedAddress {
@@ -34,6 +34,38 @@ diesel::table! {
         template_pkg_feerates -> Array<Float4>,
         template_sigops -> Int8,
         block_sigops -> Int8,
+        stale -> Bool,
+        reorg_depth -> Nullable<Int4>,
+        fee_weighted_equality -> Float4,
+        weight_weighted_equality -> Float4,
+        accelerated_tx -> Int4,
+        accelerated_fee_forgone -> Int8,
+        shared_tx_block_positions -> Array<Int4>,
+        shared_tx_template_positions -> Array<Int4>,
+        out_of_order_pkgs -> Int4,
+        ordering_conformance -> Nullable<Float4>,
+        prev_block_interval -> Nullable<Int4>,
+        version -> Nullable<Int4>,
+        missing_tx_fees -> Int8,
+        extra_tx_fees -> Int8,
+        fee_delta_residual -> Int8,
+    }
+}
+
+diesel::table! {
+    coinbase_info (block_id) {
+        block_id -> Int8,
+        script_sig -> Bytea,
+        script_sig_tag -> Text,
+        bip34_height -> Nullable<Int4>,
+        extranonce_len -> Nullable<Int4>,
+        witness_commitment -> Nullable<Bytea>,
+        merged_mining_chains -> Array<Int4>,
+        merged_mining_commitments -> Array<Bytea>,
+        payout_outputs -> Int4,
+        payout_scripts -> Array<Bytea>,
+        payout_addresses -> Array<Text>,
+        payout_values -> Array<Int8>,
     }
 }
 
@@ -47,6 +79,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    daemon_run (id) {
+        id -> Int8,
+        start_time -> Timestamp,
+        last_seen_time -> Timestamp,
+    }
+}
+
 diesel::table! {
     debug_template_selection (block_id, template_time) {
         block_id -> Int8,
@@ -78,6 +118,8 @@ diesel::table! {
         in_block -> Bool,
         in_template -> Bool,
         addresses -> Array<Text>,
+        control_tx -> Nullable<Int4>,
+        control_missing_tx -> Nullable<Int4>,
     }
 }
 
@@ -101,6 +143,21 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    template_archive (height, template_time, txids_hash) {
+        height -> Int4,
+        template_time -> Timestamp,
+        prev_hash -> Bytea,
+        longpollid -> Text,
+        bits -> Bytea,
+        target -> Bytea,
+        version -> Int4,
+        tx_count -> Int4,
+        template -> Bytea,
+        txids_hash -> Bytea,
+    }
+}
+
 diesel::table! {
     transaction (txid) {
         txid -> Bytea,
@@ -122,6 +179,8 @@ diesel::table! {
         block_id -> Int8,
         position -> Int4,
         transaction_txid -> Bytea,
+        labels -> Array<Int4>,
+        reject_reason -> Nullable<Text>,
     }
 }
 
@@ -129,8 +188,26 @@ diesel::table! {
     transaction_only_in_template (block_id, transaction_txid) {
         block_id -> Int8,
         position -> Int4,
-        mempool_age_seconds -> Int4,
+        mempool_age_seconds -> Nullable<Int4>,
         transaction_txid -> Bytea,
+        ancestor_count -> Nullable<Int4>,
+        ancestor_vsize -> Nullable<Int4>,
+        descendant_count -> Nullable<Int4>,
+        descendant_vsize -> Nullable<Int4>,
+        fee_delta -> Nullable<Int8>,
+        reasons -> Array<Int4>,
+    }
+}
+
+diesel::table! {
+    uncompared_block (hash) {
+        hash -> Bytea,
+        prev_hash -> Bytea,
+        height -> Int4,
+        block_time -> Timestamp,
+        seen_time -> Timestamp,
+        pool_name -> Text,
+        reason -> Int4,
     }
 }
 
@@ -140,14 +217,18 @@ diesel::joinable!(transaction_only_in_template -> transaction (transaction_txid)
 
 diesel::allow_tables_to_appear_in_same_query!(
     block,
+    coinbase_info,
     conflicting_transactions,
+    daemon_run,
     debug_template_selection,
     node_info,
     sanctioned_addresses,
     sanctioned_transaction_info,
     sanctioned_utxo,
     sanctioned_utxo_scan_info,
+    template_archive,
     transaction,
     transaction_only_in_block,
     transaction_only_in_template,
+    uncompared_block,
 );
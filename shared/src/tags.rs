@@ -12,6 +12,16 @@ pub const THRESHOLD_VALUE_CONSIDERED_HIGH: u64 = 100 * COIN; // sat (100 BTC)
 pub const THRESHOLD_TRANSACTION_CONSIDERED_YOUNG: u64 = 90; // seconds
 pub const THRESHOLD_SIGOPS_CONSIDERED_MANY: u64 = 1000; // sigops
 pub const THRESHOLD_SIGOP_LIMIT_CLOSE: u64 = 70_000; // sigops
+pub const THRESHOLD_FEERATE_CLOSE_TO_LOWEST_BLOCK_PACKAGE: f32 = 1.1; // ratio
+pub const THRESHOLD_FEERATE_FAR_BELOW_CUTOFF: f32 = 0.5; // ratio
+pub const BLOCK_CUTOFF_FEERATE_PERCENTILE: f32 = 0.05; // share of the weight of the packages shared with the template
+pub const THRESHOLD_PACKAGE_FEERATE_INVERSION: f32 = 1.1; // ratio
+pub const THRESHOLD_BLOCK_CONSIDERED_UNDERFULL: f32 = 0.5; // ratio of the template weight
+pub const THRESHOLD_BLOCK_TIME_AHEAD: i64 = 600; // seconds
+pub const THRESHOLD_BLOCK_SEEN_LATE: i64 = 3600; // seconds
+pub const THRESHOLD_BLOCK_TIME_CLOSE_TO_MTP: i64 = 600; // seconds after the median time past
+pub const THRESHOLD_SANCTIONED_CONTROL_FEERATE: f32 = 0.1; // relative package feerate distance
+pub const SANCTIONED_CONTROL_AGE_BUCKETS: [u64; 2] = [THRESHOLD_TRANSACTION_CONSIDERED_YOUNG, 600]; // seconds, where the mempool age buckets of the controls start
 
 type BootstrapColor = &'static str;
 // const BLUE: BootstrapColor = "primary";
@@ -51,6 +61,7 @@ pub enum TxTag {
     // informational (3000-3999)
     // DEPRECATED = 3100, (can be reused)
     Young = 3110,
+    Accelerated = 3120,
 
     // make sure to add new tag to the try_from fn below!
 
@@ -99,6 +110,7 @@ impl TryFrom<i32> for TxTag {
             x if x == TxTag::Young as i32 => Ok(TxTag::Young),
             x if x == TxTag::ManySigops as i32 => Ok(TxTag::ManySigops),
             x if x == TxTag::Inscription as i32 => Ok(TxTag::Inscription),
+            x if x == TxTag::Accelerated as i32 => Ok(TxTag::Accelerated),
             // FIXME: add new tags here
             _ => Err(()),
         }
@@ -106,7 +118,7 @@ impl TryFrom<i32> for TxTag {
 }
 
 impl TxTag {
-    pub const TX_TAGS: &'static [TxTag; 23] = &[
+    pub const TX_TAGS: &'static [TxTag; 24] = &[
         // important / danger
         TxTag::FromSanctioned,
         TxTag::ToSanctioned,
@@ -119,6 +131,7 @@ impl TxTag {
         TxTag::ManySigops,
         // informational
         TxTag::Young,
+        TxTag::Accelerated,
         // secondary
         TxTag::Coinbase,
         TxTag::SegWit,
@@ -380,6 +393,17 @@ impl TxTag {
                     text_color: WHITE,
                 }
             },
+            TxTag::Accelerated => {
+                Tag {
+                    name: "Accelerated".to_string(),
+                    description: vec![
+                            format!("The transaction was only in the block and its package feerate is less than {:.0}% of the block's cutoff feerate: the {:.0}th percentile of the package feerates the block shares with the template, weighted by the package weight.", THRESHOLD_FEERATE_FAR_BELOW_CUTOFF * 100.0, BLOCK_CUTOFF_FEERATE_PERCENTILE * 100.0),
+                            "Our template would never include it. The pool likely received an out-of-band payment to include the transaction, for example, via a transaction accelerator.".to_string(),
+                        ],
+                    color: CYAN,
+                    text_color: WHITE,
+                }
+            },
             TxTag::Inscription => {
                 Tag {
                     name: "Inscription".to_string(),
@@ -403,9 +427,16 @@ pub enum BlockTag {
 
     // warning (2000-2999)
     SigopsLimitClose = 2100,
+    OutOfOrderPackages = 2110,
+    Empty = 2120,
+    UnderFull = 2130,
+    TimestampAhead = 2140,
+    SeenLate = 2150,
 
     // informational (3000-3999)
     TaprootSignaling = 3100,
+    DeploymentSignaling = 3110,
+    TimestampCloseToMTP = 3120,
     // secondary (4000-4999)
     // StartHere = 4100,
 }
@@ -417,6 +448,13 @@ impl TryFrom<i32> for BlockTag {
         match v {
             x if x == BlockTag::TaprootSignaling as i32 => Ok(BlockTag::TaprootSignaling),
             x if x == BlockTag::SigopsLimitClose as i32 => Ok(BlockTag::SigopsLimitClose),
+            x if x == BlockTag::OutOfOrderPackages as i32 => Ok(BlockTag::OutOfOrderPackages),
+            x if x == BlockTag::Empty as i32 => Ok(BlockTag::Empty),
+            x if x == BlockTag::UnderFull as i32 => Ok(BlockTag::UnderFull),
+            x if x == BlockTag::DeploymentSignaling as i32 => Ok(BlockTag::DeploymentSignaling),
+            x if x == BlockTag::TimestampAhead as i32 => Ok(BlockTag::TimestampAhead),
+            x if x == BlockTag::SeenLate as i32 => Ok(BlockTag::SeenLate),
+            x if x == BlockTag::TimestampCloseToMTP as i32 => Ok(BlockTag::TimestampCloseToMTP),
             // FIXME: add new tags here
             _ => Err(()),
         }
@@ -424,14 +462,21 @@ impl TryFrom<i32> for BlockTag {
 }
 
 impl BlockTag {
-    pub const BLOCK_TAGS: &'static [BlockTag; 2] = &[
+    pub const BLOCK_TAGS: &'static [BlockTag; 9] = &[
         // important / danger
         //
         // warning
         BlockTag::SigopsLimitClose,
+        BlockTag::OutOfOrderPackages,
+        BlockTag::Empty,
+        BlockTag::UnderFull,
+        BlockTag::TimestampAhead,
+        BlockTag::SeenLate,
         //
         // informational
         BlockTag::TaprootSignaling,
+        BlockTag::DeploymentSignaling,
+        BlockTag::TimestampCloseToMTP,
         // secondary
         //
     ];
@@ -448,12 +493,269 @@ impl BlockTag {
                 color: CYAN,
                 text_color: WHITE,
             },
+            BlockTag::OutOfOrderPackages => Tag {
+                name: "Out-of-Order Packages".to_string(),
+                description: vec![format!(
+                    "A transaction package in the block is followed by a package paying a more than {:.0}% higher feerate.",
+                    (THRESHOLD_PACKAGE_FEERATE_INVERSION - 1.0) * 100.0
+                ),
+                "The pool might have prioritized transactions, for example, with <code>prioritisetransaction</code>, or uses a custom transaction ordering.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            BlockTag::Empty => Tag {
+                name: "Empty".to_string(),
+                description: vec!["The block contains only the coinbase transaction.".to_string(),
+                "Pools mine empty blocks when they start working on a new block before they validated its parent, which is called validationless or SPV mining.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            BlockTag::UnderFull => Tag {
+                name: "Under-full".to_string(),
+                description: vec![format!(
+                    "The block weight is less than {:.0}% of the template weight.",
+                    THRESHOLD_BLOCK_CONSIDERED_UNDERFULL * 100.0
+                )],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            BlockTag::TimestampAhead => Tag {
+                name: "Timestamp Ahead".to_string(),
+                description: vec![format!(
+                    "The block timestamp is more than {} minutes ahead of when the block was seen.",
+                    THRESHOLD_BLOCK_TIME_AHEAD / 60
+                ),
+                "The clock of the pool might be wrong or the pool moves its timestamps ahead.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            BlockTag::SeenLate => Tag {
+                name: "Seen Late".to_string(),
+                description: vec![format!(
+                    "The block was seen more than {} minutes after its timestamp.",
+                    THRESHOLD_BLOCK_SEEN_LATE / 60
+                ),
+                "The clock of the pool might be wrong, the pool didn't update the timestamp of its jobs, or the block propagated slowly.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
             BlockTag::TaprootSignaling => Tag {
                 name: "Taproot Signaling".to_string(),
                 description: vec!["The block signals for Taproot.".to_string()],
                 color: CYAN,
                 text_color: WHITE,
             },
+            BlockTag::DeploymentSignaling => Tag {
+                name: "Deployment Signaling".to_string(),
+                description: vec!["The block signals for a soft fork deployment that is currently tracked.".to_string()],
+                color: CYAN,
+                text_color: WHITE,
+            },
+            BlockTag::TimestampCloseToMTP => Tag {
+                name: "Timestamp Close to MTP".to_string(),
+                description: vec![format!(
+                    "The block timestamp is at most {} minutes after the median time of the past 11 blocks (MTP).",
+                    THRESHOLD_BLOCK_TIME_CLOSE_TO_MTP / 60
+                ),
+                "A block timestamp must be after the MTP to be valid. A timestamp close to it is unusual and might be used to manipulate the difficulty adjustment.".to_string()],
+                color: CYAN,
+                text_color: WHITE,
+            },
+        }
+    }
+}
+
+/// A reason why a transaction might be missing from a block. A missing
+/// transaction can have multiple reasons.
+pub enum MissingReason {
+    // the value is important for database backwards compatibilty
+    // make sure to add new reasons to the try_from fn below!
+    Young = 1,
+    LowFeerate = 2,
+    Conflicting = 3,
+    MissingParent = 4,
+    Sanctioned = 5,
+    Large = 6,
+    ManySigops = 7,
+}
+
+impl TryFrom<i32> for MissingReason {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            x if x == MissingReason::Young as i32 => Ok(MissingReason::Young),
+            x if x == MissingReason::LowFeerate as i32 => Ok(MissingReason::LowFeerate),
+            x if x == MissingReason::Conflicting as i32 => Ok(MissingReason::Conflicting),
+            x if x == MissingReason::MissingParent as i32 => Ok(MissingReason::MissingParent),
+            x if x == MissingReason::Sanctioned as i32 => Ok(MissingReason::Sanctioned),
+            x if x == MissingReason::Large as i32 => Ok(MissingReason::Large),
+            x if x == MissingReason::ManySigops as i32 => Ok(MissingReason::ManySigops),
+            // FIXME: add new reasons here
+            _ => Err(()),
+        }
+    }
+}
+
+impl MissingReason {
+    pub const MISSING_REASONS: &'static [MissingReason; 7] = &[
+        MissingReason::Young,
+        MissingReason::LowFeerate,
+        MissingReason::Conflicting,
+        MissingReason::MissingParent,
+        MissingReason::Sanctioned,
+        MissingReason::Large,
+        MissingReason::ManySigops,
+    ];
+
+    pub fn value(&self) -> Tag {
+        match self {
+            MissingReason::Young => Tag {
+                name: "Young".to_string(),
+                description: vec![format!(
+                    "The transaction was in the mempool for less than {} seconds when the block was found.",
+                    THRESHOLD_TRANSACTION_CONSIDERED_YOUNG
+                ),
+                "It might not have propagated to the pool yet or the pool didn't update its template since.".to_string()],
+                color: CYAN,
+                text_color: WHITE,
+            },
+            MissingReason::LowFeerate => Tag {
+                name: "Low Feerate".to_string(),
+                description: vec![format!(
+                    "The transaction's package feerate is below or less than {:.0}% above the lowest package feerate included in the block.",
+                    (THRESHOLD_FEERATE_CLOSE_TO_LOWEST_BLOCK_PACKAGE - 1.0) * 100.0
+                ),
+                "The pool might have known about transactions paying a higher feerate.".to_string()],
+                color: CYAN,
+                text_color: WHITE,
+            },
+            MissingReason::Conflicting => Tag {
+                name: "Conflicting".to_string(),
+                description: vec!["The transaction conflicts with a transaction in the block. Both spend the same output.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            MissingReason::MissingParent => Tag {
+                name: "Missing Parent".to_string(),
+                description: vec!["The transaction spends an output of another transaction missing from the block. It can't be included without its parent.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            MissingReason::Sanctioned => Tag {
+                name: "Sanctioned".to_string(),
+                description: vec!["The transaction pays to or spends from a sanctioned address. The pool might filter it.".to_string()],
+                color: RED,
+                text_color: WHITE,
+            },
+            MissingReason::Large => Tag {
+                name: "Large".to_string(),
+                description: vec![format!(
+                    "The transaction is larger than {} vByte. Some pools don't include large transactions.",
+                    THRESHOLD_TRANSACTION_CONSIDERED_LARGE
+                )],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            MissingReason::ManySigops => Tag {
+                name: "Many Sigops".to_string(),
+                description: vec![format!(
+                    "The transaction has more than {} sigops. Some pools use a lower sigop limit than the consensus limit.",
+                    THRESHOLD_SIGOPS_CONSIDERED_MANY
+                )],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+        }
+    }
+}
+
+/// A label explaining why a transaction only in the block might not have
+/// been in our template. An extra transaction can have multiple labels.
+pub enum ExtraTxLabel {
+    // the value is important for database backwards compatibilty
+    // make sure to add new labels to the try_from fn below!
+    NeverSeen = 1,
+    BelowTemplateCutoff = 2,
+    NonStandard = 3,
+    Replaced = 4,
+    PoolPayout = 5,
+    SeenAfterTemplate = 6,
+}
+
+impl TryFrom<i32> for ExtraTxLabel {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            x if x == ExtraTxLabel::NeverSeen as i32 => Ok(ExtraTxLabel::NeverSeen),
+            x if x == ExtraTxLabel::BelowTemplateCutoff as i32 => {
+                Ok(ExtraTxLabel::BelowTemplateCutoff)
+            }
+            x if x == ExtraTxLabel::NonStandard as i32 => Ok(ExtraTxLabel::NonStandard),
+            x if x == ExtraTxLabel::Replaced as i32 => Ok(ExtraTxLabel::Replaced),
+            x if x == ExtraTxLabel::PoolPayout as i32 => Ok(ExtraTxLabel::PoolPayout),
+            x if x == ExtraTxLabel::SeenAfterTemplate as i32 => Ok(ExtraTxLabel::SeenAfterTemplate),
+            // FIXME: add new labels here
+            _ => Err(()),
+        }
+    }
+}
+
+impl ExtraTxLabel {
+    pub const EXTRA_TX_LABELS: &'static [ExtraTxLabel; 6] = &[
+        ExtraTxLabel::NeverSeen,
+        ExtraTxLabel::SeenAfterTemplate,
+        ExtraTxLabel::BelowTemplateCutoff,
+        ExtraTxLabel::NonStandard,
+        ExtraTxLabel::Replaced,
+        ExtraTxLabel::PoolPayout,
+    ];
+
+    pub fn value(&self) -> Tag {
+        match self {
+            ExtraTxLabel::NeverSeen => Tag {
+                name: "Never Seen".to_string(),
+                description: vec!["The transaction was never seen in our mempool or in one of our templates before the block.".to_string(),
+                "The pool might have received it privately or it didn't propagate to us.".to_string()],
+                color: RED,
+                text_color: WHITE,
+            },
+            ExtraTxLabel::SeenAfterTemplate => Tag {
+                name: "Seen After Template".to_string(),
+                description: vec!["The transaction was first seen by our node after our template was created.".to_string(),
+                "It likely arrived too late to be included in our template.".to_string()],
+                color: GRAY,
+                text_color: WHITE,
+            },
+            ExtraTxLabel::BelowTemplateCutoff => Tag {
+                name: "Below Template Cutoff".to_string(),
+                description: vec!["The transaction was known to our node when our template was created but its package feerate is below the lowest package feerate in our template.".to_string(),
+                "The pool might have prioritized it.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            ExtraTxLabel::NonStandard => Tag {
+                name: "Non-Standard".to_string(),
+                description: vec!["The transaction was never seen by our node and breaks a rule of Bitcoin Core's default policy.".to_string(),
+                "It doesn't propagate through the network and must have been sent to the pool directly.".to_string()],
+                color: RED,
+                text_color: WHITE,
+            },
+            ExtraTxLabel::Replaced => Tag {
+                name: "Replaced".to_string(),
+                description: vec!["The transaction conflicts with a transaction in our template.".to_string(),
+                "The pool mined a different version, for example, a version that was replaced via RBF in our mempool.".to_string()],
+                color: YELLOW,
+                text_color: BLACK,
+            },
+            ExtraTxLabel::PoolPayout => Tag {
+                name: "Pool Payout".to_string(),
+                description: vec!["The transaction is placed directly after the coinbase transaction and pays to or spends from one of the coinbase output scripts.".to_string(),
+                "Pools often place their own payout transactions there.".to_string()],
+                color: CYAN,
+                text_color: WHITE,
+            },
         }
     }
 }
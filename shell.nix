@@ -7,5 +7,9 @@ pkgs.mkShell {
       pkgs.rustc
       pkgs.postgresql
       pkgs.diesel-cli
+      pkgs.pkg-config
+    ];
+    buildInputs = [
+      pkgs.zeromq
     ];
 }
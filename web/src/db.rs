@@ -1,26 +1,56 @@
 use crate::model::{
-    AvgPoolFees, BlockWithTx, ConflictingOutpoint, ConflictingTransactionSet,
-    ConflictingTranscationInfo, DebugTemplateSelectionInfosAndBlock, MissingSanctionedTransaction,
-    MissingTransaction, MissingTransactionBlockInfo, PoolSanctionedTableEntry,
+    AvgPoolFees, BlockWithTx, CensorshipScores, Coinbase, ConflictingOutpoint,
+    ConflictingTransactionSet, ConflictingTranscationInfo, Coverage, DaemonDowntime,
+    DebugTemplateSelectionInfosAndBlock, DeploymentSignaling, MissingHeightRange,
+    MissingSanctionedTransaction, MissingTransaction, MissingTransactionBlockInfo, ObservedHeights,
+    PoolAcceleration, PoolCensorshipCounts, PoolCensorshipScore, PoolEmptyBlocks,
+    PoolEqualityOverTime, PoolOrdering, PoolProfile, PoolProfileStats, PoolSanctionedTableEntry,
+    PoolSignaling, PoolTimestamps, PoolVersionRolling, SignalingPeriod, UncomparedBlockWithReason,
+    UncomparedReasonCount,
 };
+use miningpool_observer_shared::config::Deployment;
 use miningpool_observer_shared::model::{
-    Block, ConflictingTransaction, DebugTemplateSelectionInfo, SanctionedAddress,
-    SanctionedTransactionInfo, SanctionedUtxoScanInfo, Transaction, TransactionOnlyInBlock,
-    TransactionOnlyInTemplate,
+    ArchivedTemplate, Block, CoinbaseInfo, ConflictingTransaction, DebugTemplateSelectionInfo,
+    SanctionedAddress, SanctionedTransactionInfo, SanctionedUtxoScanInfo, Transaction,
+    TransactionOnlyInBlock, TransactionOnlyInTemplate, UncomparedBlock, UncomparedReason,
 };
 use miningpool_observer_shared::schema;
+use miningpool_observer_shared::tags;
 
 use miningpool_observer_shared::diesel;
 use miningpool_observer_shared::diesel::dsl::{count, sql};
 use miningpool_observer_shared::diesel::pg::PgConnection;
 use miningpool_observer_shared::diesel::prelude::*;
-use miningpool_observer_shared::diesel::sql_types::{BigInt, Bytea};
+use miningpool_observer_shared::diesel::sql_types::{BigInt, Bytea, Integer, Nullable, Text};
 use miningpool_observer_shared::diesel::{sql_query, QueryableByName};
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 pub const MAX_BLOCKS_PER_PAGE: i64 = 25;
 pub const MAX_UTXOSET_SCANS_PER_PAGE: i64 = 100;
+pub const MAX_COVERAGE_ENTRIES: i64 = 100;
+pub const EQUALITY_CHART_WEEKS: i32 = 26;
+pub const EQUALITY_CHART_MAX_POOLS: i64 = 8;
+pub const ACCELERATION_TABLE_DAYS: i32 = 30;
+pub const ORDERING_TABLE_DAYS: i32 = 30;
+pub const EMPTY_BLOCKS_TABLE_DAYS: i32 = 90;
+pub const VERSION_ROLLING_TABLE_DAYS: i32 = 30;
+pub const TIMESTAMPS_TABLE_DAYS: i32 = 30;
+pub const SIGNALING_PERIODS: i32 = 6;
+pub const POOL_PROFILE_WINDOWS_DAYS: [u32; 4] = [7, 30, 90, 365];
+pub const POOL_PROFILE_DEFAULT_WINDOW_DAYS: u32 = 30;
+pub const POOL_PROFILE_RECENT_BLOCKS: i64 = 10;
+pub const CENSORSHIP_SCORE_DAYS: i32 = 365;
+const RETARGET_INTERVAL: i32 = 2016;
+/// The top three version bits must be 001 for a block to signal with BIP9.
+const BIP9_VERSION_TOP_MASK: i32 = 0xE0000000u32 as i32;
+const BIP9_VERSION_TOP_BITS: i32 = 0x20000000;
+/// The version bits BIP320 allows miners to use as additional nonce space.
+const BIP320_VERSION_ROLLING_MASK: i32 = 0x1FFFE000;
+/// The daemon updates the last seen time of its run every minute. A gap of
+/// more than three minutes between two runs is considered a downtime.
+const DAEMON_DOWNTIME_THRESHOLD_SECONDS: i32 = 3 * 60;
 
 pub fn blocks(
     conn: &mut PgConnection,
@@ -77,6 +107,22 @@ pub fn block(block_hash: &[u8], conn: &mut PgConnection) -> Result<Block, diesel
     block.find(block_hash).first(conn)
 }
 
+/// Select the archived template that was selected for the block. As only
+/// changed templates are archived, this is the newest archived template for
+/// the block height that isn't newer than the selected template.
+pub fn archived_template_for_block(
+    block_hash: &[u8],
+    conn: &mut PgConnection,
+) -> Result<ArchivedTemplate, diesel::result::Error> {
+    let b = block(block_hash, conn)?;
+    use schema::template_archive::dsl::*;
+    template_archive
+        .filter(height.eq(b.height))
+        .filter(template_time.le(b.template_time))
+        .order(template_time.desc())
+        .first(conn)
+}
+
 pub fn unknown_pool_blocks(conn: &mut PgConnection) -> Result<Vec<Block>, diesel::result::Error> {
     use schema::block::dsl::*;
     block
@@ -95,6 +141,7 @@ pub fn block_with_tx(
         block,
         txns_only_in_template: transaction_only_in_template_by_block_id(block_id, conn)?,
         txns_only_in_block: transaction_only_in_block_by_block_id(block_id, conn)?,
+        coinbase: coinbase_info_by_block_id(block_id, conn)?.map(Coinbase::from),
     })
 }
 
@@ -330,6 +377,63 @@ pub fn debug_sanctioned_table(
     sql_query(QUERY_DEBUG_SANCTIONED_TABLE_TOTAL).load::<PoolSanctionedTableEntry>(conn)
 }
 
+const QUERY_POOL_CENSORSHIP_COUNTS: &str = r#"
+SELECT
+    b.pool_name,
+    COUNT(DISTINCT b.id) AS blocks,
+    COUNT(*) AS sanctioned_tx,
+    COUNT(CASE WHEN NOT s.in_block THEN 1 END) AS sanctioned_missing_tx,
+    COALESCE(SUM(s.control_tx), 0)::INT8 AS control_tx,
+    COALESCE(SUM(s.control_missing_tx), 0)::INT8 AS control_missing_tx
+FROM sanctioned_transaction_info s
+JOIN
+    block b ON b.id = s.block_id
+WHERE
+    s.in_template
+    AND s.control_tx IS NOT NULL
+    AND NOT b.stale
+    AND b.block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    b.pool_name
+ORDER BY
+    sanctioned_tx DESC,
+    b.pool_name ASC
+"#;
+
+/// Returns the censorship scores of the pools that had sanctioned
+/// transactions in their templates.
+pub fn censorship_scores(
+    conn: &mut PgConnection,
+) -> Result<CensorshipScores, diesel::result::Error> {
+    let counts = sql_query(QUERY_POOL_CENSORSHIP_COUNTS)
+        .bind::<Integer, _>(CENSORSHIP_SCORE_DAYS)
+        .load::<PoolCensorshipCounts>(conn)?;
+
+    Ok(CensorshipScores {
+        method: format!(
+            "For each sanctioned transaction in our template, the non-sanctioned template transactions \
+            with a package feerate within {:.0}% of its package feerate and the same mempool age bucket are its controls. \
+            The mempool age is the time since the transaction was first in one of our templates. \
+            The age buckets start at {} seconds. Transactions already in our first template after the previous block are in the oldest bucket. \
+            The miss rate is the share of transactions missing from the block. \
+            The score is the miss rate of the sanctioned transactions minus the miss rate of their controls. \
+            A control matching multiple sanctioned transactions in a block is only counted for the one with the closest package feerate. \
+            The miss rates have Wilson score intervals and the score has a Newcombe hybrid score interval.",
+            tags::THRESHOLD_SANCTIONED_CONTROL_FEERATE * 100.0,
+            std::iter::once(0)
+                .chain(tags::SANCTIONED_CONTROL_AGE_BUCKETS)
+                .map(|start| start.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+        ),
+        confidence_level: 0.95,
+        window_days: CENSORSHIP_SCORE_DAYS,
+        control_feerate_tolerance: tags::THRESHOLD_SANCTIONED_CONTROL_FEERATE,
+        control_age_buckets: tags::SANCTIONED_CONTROL_AGE_BUCKETS.to_vec(),
+        pools: counts.into_iter().map(PoolCensorshipScore::from).collect(),
+    })
+}
+
 pub fn missing_sanctioned_txns_for_block(
     req_hash: &[u8],
     conn: &mut PgConnection,
@@ -380,7 +484,10 @@ SELECT
     count(pool_name),
     percentile_disc(0.5) within group (order by (block_cb_fees::float / template_cb_fees::float)) median,
     percentile_disc(0.25) within group (order by (block_cb_fees::float / template_cb_fees::float)) q1,
-    percentile_disc(0.75) within group (order by (block_cb_fees::float / template_cb_fees::float)) q3
+    percentile_disc(0.75) within group (order by (block_cb_fees::float / template_cb_fees::float)) q3,
+    AVG(missing_tx_fees)::FLOAT8 avg_missing_tx_fees,
+    AVG(extra_tx_fees)::FLOAT8 avg_extra_tx_fees,
+    AVG(fee_delta_residual)::FLOAT8 avg_fee_delta_residual
 FROM block
 GROUP BY
     pool_name
@@ -389,6 +496,327 @@ HAVING
 ORDER BY median DESC
 ;"#;
 
+const QUERY_POOL_EQUALITY_OVER_TIME: &str = r#"
+WITH recent_blocks AS (
+    SELECT
+        pool_name,
+        block_time,
+        equality,
+        fee_weighted_equality,
+        weight_weighted_equality
+    FROM block
+    WHERE
+        NOT stale
+        AND block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 week'
+        AND ($3::TEXT IS NULL OR pool_name = $3)
+), top_pools AS (
+    SELECT pool_name
+    FROM recent_blocks
+    GROUP BY pool_name
+    ORDER BY COUNT(*) DESC
+    LIMIT $2
+)
+SELECT
+    pool_name,
+    DATE_TRUNC('week', block_time) AS week,
+    COUNT(*) AS blocks,
+    AVG(equality)::FLOAT8 AS equality,
+    AVG(fee_weighted_equality)::FLOAT8 AS fee_weighted_equality,
+    AVG(weight_weighted_equality)::FLOAT8 AS weight_weighted_equality
+FROM recent_blocks
+WHERE
+    pool_name IN (SELECT pool_name FROM top_pools)
+GROUP BY
+    pool_name,
+    week
+ORDER BY
+    pool_name,
+    week
+"#;
+
+/// Returns the weekly average equality of the pools with the most blocks.
+/// If a pool is passed, only its equality is returned.
+pub fn pool_equality_over_time(
+    conn: &mut PgConnection,
+    pool: Option<String>,
+) -> Result<Vec<PoolEqualityOverTime>, diesel::result::Error> {
+    sql_query(QUERY_POOL_EQUALITY_OVER_TIME)
+        .bind::<Integer, _>(EQUALITY_CHART_WEEKS)
+        .bind::<BigInt, _>(EQUALITY_CHART_MAX_POOLS)
+        .bind::<Nullable<Text>, _>(pool)
+        .load::<PoolEqualityOverTime>(conn)
+}
+
+const QUERY_POOL_ACCELERATION: &str = r#"
+SELECT
+    pool_name,
+    COUNT(*) AS blocks,
+    COUNT(CASE WHEN accelerated_tx > 0 THEN 1 END) AS blocks_with_accelerated_tx,
+    COALESCE(SUM(accelerated_tx), 0)::BIGINT AS accelerated_tx,
+    COALESCE(SUM(accelerated_fee_forgone), 0)::BIGINT AS accelerated_fee_forgone
+FROM block
+WHERE
+    NOT stale
+    AND block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    pool_name
+ORDER BY
+    blocks_with_accelerated_tx DESC,
+    blocks DESC
+"#;
+
+/// Returns how often the pools included likely accelerated transactions in
+/// their recent blocks.
+pub fn pool_acceleration(
+    conn: &mut PgConnection,
+) -> Result<Vec<PoolAcceleration>, diesel::result::Error> {
+    sql_query(QUERY_POOL_ACCELERATION)
+        .bind::<Integer, _>(ACCELERATION_TABLE_DAYS)
+        .load::<PoolAcceleration>(conn)
+}
+
+const QUERY_POOL_ORDERING: &str = r#"
+SELECT
+    pool_name,
+    COUNT(ordering_conformance) AS blocks,
+    COUNT(CASE WHEN out_of_order_pkgs > 0 THEN 1 END) AS blocks_with_out_of_order_pkgs,
+    AVG(ordering_conformance)::FLOAT8 AS ordering_conformance
+FROM block
+WHERE
+    NOT stale
+    AND ordering_conformance IS NOT NULL
+    AND block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    pool_name
+ORDER BY
+    ordering_conformance ASC,
+    blocks DESC
+"#;
+
+/// Returns how well the recent blocks of the pools follow a descending
+/// package feerate order.
+pub fn pool_ordering(conn: &mut PgConnection) -> Result<Vec<PoolOrdering>, diesel::result::Error> {
+    sql_query(QUERY_POOL_ORDERING)
+        .bind::<Integer, _>(ORDERING_TABLE_DAYS)
+        .load::<PoolOrdering>(conn)
+}
+
+const QUERY_POOL_EMPTY_BLOCKS: &str = r#"
+SELECT
+    b.pool_name,
+    COUNT(*) AS blocks,
+    COUNT(CASE WHEN $2 = ANY(b.tags) THEN 1 END) AS empty_blocks,
+    COUNT(CASE WHEN $3 = ANY(b.tags) THEN 1 END) AS underfull_blocks,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY b.prev_block_interval)
+        FILTER (WHERE $2 = ANY(b.tags) OR $3 = ANY(b.tags)))::FLOAT8 AS median_prev_block_interval,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY EXTRACT(EPOCH FROM b.block_seen_time - p.block_seen_time))
+        FILTER (WHERE $2 = ANY(b.tags) OR $3 = ANY(b.tags)))::FLOAT8 AS median_prev_block_seen_interval
+FROM block b
+LEFT JOIN
+    block p ON p.hash = b.prev_hash
+WHERE
+    NOT b.stale
+    AND b.block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    b.pool_name
+ORDER BY
+    COUNT(CASE WHEN $2 = ANY(b.tags) OR $3 = ANY(b.tags) THEN 1 END)::FLOAT8 / COUNT(*) DESC,
+    blocks DESC
+"#;
+
+/// Returns the empty and under-full blocks of the pools and how quickly
+/// they followed their parent.
+pub fn pool_empty_blocks(
+    conn: &mut PgConnection,
+) -> Result<Vec<PoolEmptyBlocks>, diesel::result::Error> {
+    sql_query(QUERY_POOL_EMPTY_BLOCKS)
+        .bind::<Integer, _>(EMPTY_BLOCKS_TABLE_DAYS)
+        .bind::<Integer, _>(tags::BlockTag::Empty as i32)
+        .bind::<Integer, _>(tags::BlockTag::UnderFull as i32)
+        .load::<PoolEmptyBlocks>(conn)
+}
+
+const QUERY_DEPLOYMENT_SIGNALING: &str = r#"
+SELECT
+    pool_name,
+    height / $1 AS period,
+    COUNT(*) AS blocks,
+    COUNT(CASE WHEN (version & $2) = $3 AND (version & $4) <> 0 THEN 1 END) AS signaling_blocks
+FROM block
+WHERE
+    NOT stale
+    AND version IS NOT NULL
+    AND height >= $5
+    AND height < $6
+    AND height / $1 > (SELECT MAX(height) FROM block) / $1 - $7
+GROUP BY
+    pool_name,
+    period
+ORDER BY
+    period DESC,
+    blocks DESC,
+    pool_name ASC
+"#;
+
+/// Returns the signaling per pool in the last retarget periods of the
+/// deployment.
+pub fn deployment_signaling(
+    conn: &mut PgConnection,
+    deployment: &Deployment,
+) -> Result<DeploymentSignaling, diesel::result::Error> {
+    let pools = sql_query(QUERY_DEPLOYMENT_SIGNALING)
+        .bind::<Integer, _>(RETARGET_INTERVAL)
+        .bind::<Integer, _>(BIP9_VERSION_TOP_MASK)
+        .bind::<Integer, _>(BIP9_VERSION_TOP_BITS)
+        .bind::<Integer, _>(1i32 << deployment.bit)
+        .bind::<Integer, _>(deployment.start_height as i32)
+        .bind::<Integer, _>(deployment.timeout_height.map_or(i32::MAX, |h| h as i32))
+        .bind::<Integer, _>(SIGNALING_PERIODS)
+        .load::<PoolSignaling>(conn)?;
+
+    let mut periods: Vec<SignalingPeriod> = vec![];
+    for pool in pools {
+        if periods.last().map(|p| p.period) != Some(pool.period) {
+            periods.push(SignalingPeriod {
+                period: pool.period,
+                first_height: pool.period * RETARGET_INTERVAL,
+                blocks: 0,
+                signaling_blocks: 0,
+                pools: vec![],
+            });
+        }
+        let period = periods.last_mut().unwrap();
+        period.blocks += pool.blocks;
+        period.signaling_blocks += pool.signaling_blocks;
+        period.pools.push(pool);
+    }
+
+    Ok(DeploymentSignaling {
+        name: deployment.name.clone(),
+        bit: deployment.bit,
+        periods,
+    })
+}
+
+const QUERY_POOL_VERSION_ROLLING: &str = r#"
+SELECT
+    pool_name,
+    COUNT(*) AS blocks,
+    COUNT(CASE WHEN (version & $2) <> 0 THEN 1 END) AS version_rolling_blocks,
+    LPAD(TO_HEX(BIT_OR(version & $2)), 8, '0') AS version_rolling_bits
+FROM block
+WHERE
+    NOT stale
+    AND version IS NOT NULL
+    AND block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    pool_name
+ORDER BY
+    blocks DESC,
+    pool_name ASC
+"#;
+
+/// Returns which of the BIP320 version rolling bits the pools used.
+pub fn pool_version_rolling(
+    conn: &mut PgConnection,
+) -> Result<Vec<PoolVersionRolling>, diesel::result::Error> {
+    sql_query(QUERY_POOL_VERSION_ROLLING)
+        .bind::<Integer, _>(VERSION_ROLLING_TABLE_DAYS)
+        .bind::<Integer, _>(BIP320_VERSION_ROLLING_MASK)
+        .load::<PoolVersionRolling>(conn)
+}
+
+const QUERY_POOL_TIMESTAMPS: &str = r#"
+SELECT
+    pool_name,
+    COUNT(*) AS blocks,
+    AVG(EXTRACT(EPOCH FROM block_time - block_seen_time))::FLOAT8 AS avg_offset,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY EXTRACT(EPOCH FROM block_time - block_seen_time)))::FLOAT8 AS median_offset,
+    MIN(EXTRACT(EPOCH FROM block_time - block_seen_time))::FLOAT8 AS min_offset,
+    MAX(EXTRACT(EPOCH FROM block_time - block_seen_time))::FLOAT8 AS max_offset,
+    COUNT(CASE WHEN $2 = ANY(tags) THEN 1 END) AS timestamp_ahead_blocks,
+    COUNT(CASE WHEN $3 = ANY(tags) THEN 1 END) AS seen_late_blocks,
+    COUNT(CASE WHEN $4 = ANY(tags) THEN 1 END) AS timestamp_close_to_mtp_blocks
+FROM block
+WHERE
+    NOT stale
+    AND block_time > (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 day'
+GROUP BY
+    pool_name
+ORDER BY
+    blocks DESC,
+    pool_name ASC
+"#;
+
+/// Returns the offsets between the header timestamps and the time we saw
+/// the blocks of the pools.
+pub fn pool_timestamps(
+    conn: &mut PgConnection,
+) -> Result<Vec<PoolTimestamps>, diesel::result::Error> {
+    sql_query(QUERY_POOL_TIMESTAMPS)
+        .bind::<Integer, _>(TIMESTAMPS_TABLE_DAYS)
+        .bind::<Integer, _>(tags::BlockTag::TimestampAhead as i32)
+        .bind::<Integer, _>(tags::BlockTag::SeenLate as i32)
+        .bind::<Integer, _>(tags::BlockTag::TimestampCloseToMTP as i32)
+        .load::<PoolTimestamps>(conn)
+}
+
+const QUERY_POOL_PROFILE_STATS: &str = r#"
+SELECT
+    COUNT(CASE WHEN pool_name = $1 THEN 1 END) AS blocks,
+    COUNT(*) AS total_blocks,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY missing_tx)
+        FILTER (WHERE pool_name = $1))::FLOAT8 AS median_missing_tx,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY extra_tx)
+        FILTER (WHERE pool_name = $1))::FLOAT8 AS median_extra_tx,
+    (PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY shared_tx)
+        FILTER (WHERE pool_name = $1))::FLOAT8 AS median_shared_tx,
+    (SUM(block_cb_fees) FILTER (WHERE pool_name = $1))::FLOAT8
+        / NULLIF(SUM(template_cb_fees) FILTER (WHERE pool_name = $1), 0)::FLOAT8 AS fee_capture_ratio,
+    COUNT(CASE WHEN pool_name = $1 AND block_sanctioned > 0 THEN 1 END) AS blocks_with_sanctioned,
+    COALESCE(SUM(block_sanctioned) FILTER (WHERE pool_name = $1), 0)::INT8 AS block_sanctioned_tx,
+    COALESCE(SUM(template_sanctioned) FILTER (WHERE pool_name = $1), 0)::INT8 AS template_sanctioned_tx,
+    COALESCE(SUM(sanctioned_missing_tx) FILTER (WHERE pool_name = $1), 0)::INT8 AS sanctioned_missing_tx,
+    COUNT(CASE WHEN pool_name = $1 AND $3 = ANY(tags) THEN 1 END) AS empty_blocks
+FROM block
+WHERE
+    NOT stale
+    AND block_time > (NOW() AT TIME ZONE 'UTC') - $2 * INTERVAL '1 day'
+"#;
+
+/// Returns the profile of a pool over the last `window_days` days and its
+/// most recent blocks. Returns a NotFound error if we never saw a block of
+/// the pool.
+pub fn pool_profile(
+    conn: &mut PgConnection,
+    pool: &str,
+    window_days: u32,
+) -> Result<PoolProfile, diesel::result::Error> {
+    use schema::block::dsl::{block, block_time, pool_name, stale};
+    let recent_blocks: Vec<Block> = block
+        .filter(pool_name.eq(pool))
+        .filter(stale.eq(false))
+        .limit(POOL_PROFILE_RECENT_BLOCKS)
+        .order(block_time.desc())
+        .load::<Block>(conn)?;
+    if recent_blocks.is_empty() {
+        return Err(diesel::result::Error::NotFound);
+    }
+
+    let stats = sql_query(QUERY_POOL_PROFILE_STATS)
+        .bind::<Text, _>(pool)
+        .bind::<Integer, _>(window_days as i32)
+        .bind::<Integer, _>(tags::BlockTag::Empty as i32)
+        .get_result::<PoolProfileStats>(conn)?;
+
+    Ok(PoolProfile {
+        pool_name: pool.to_string(),
+        window_days,
+        stats,
+        recent_blocks,
+    })
+}
+
 pub fn avg_fees_by_pool(
     conn: &mut PgConnection,
 ) -> Result<Vec<AvgPoolFees>, diesel::result::Error> {
@@ -407,6 +835,17 @@ fn transaction_only_in_template_by_block_id(
         .load(conn)
 }
 
+fn coinbase_info_by_block_id(
+    p_block_id: i64,
+    conn: &mut PgConnection,
+) -> Result<Option<CoinbaseInfo>, diesel::result::Error> {
+    use schema::coinbase_info::dsl::*;
+    coinbase_info
+        .filter(block_id.eq(p_block_id))
+        .first::<CoinbaseInfo>(conn)
+        .optional()
+}
+
 fn transaction_only_in_block_by_block_id(
     p_block_id: i64,
     conn: &mut PgConnection,
@@ -509,6 +948,7 @@ pub fn missing_transactions(
                         pool: block.pool_name.clone(),
                         last_block_pkg_feerate: *block.block_pkg_feerates.last().unwrap_or(&0.0),
                         template_tx_count: block.template_tx,
+                        reasons: oitt.reasons.clone(),
                     }
                 })
                 .collect();
@@ -632,6 +1072,7 @@ pub fn single_missing_transaction(
                     pool: block.pool_name.clone(),
                     last_block_pkg_feerate: *block.block_pkg_feerates.last().unwrap_or(&0.0),
                     template_tx_count: block.template_tx,
+                    reasons: oitt.reasons.clone(),
                 }
             })
             .collect();
@@ -759,3 +1200,113 @@ pub fn sanctioned_addresses(conn: &mut PgConnection) -> Result<Vec<String>, dies
     let addresses = sanctioned_addresses.load::<SanctionedAddress>(conn)?;
     Ok(addresses.iter().map(|a| a.address.clone()).collect())
 }
+
+const QUERY_OBSERVED_HEIGHTS: &str = r#"
+SELECT
+    MIN(height) AS first_height,
+    MAX(height) AS last_height,
+    (SELECT COUNT(*) FROM block) AS compared_blocks,
+    (SELECT COUNT(*) FROM uncompared_block) AS uncompared_blocks
+FROM (
+    SELECT height FROM block
+    UNION
+    SELECT height FROM uncompared_block
+) AS observed
+"#;
+
+const QUERY_MISSING_HEIGHT_RANGES: &str = r#"
+SELECT
+    height + 1 AS first_height,
+    next_height - 1 AS last_height
+FROM (
+    SELECT
+        height,
+        LEAD(height) OVER (ORDER BY height) AS next_height
+    FROM (
+        SELECT height FROM block
+        UNION
+        SELECT height FROM uncompared_block
+    ) AS observed
+) AS heights
+WHERE
+    next_height > height + 1
+ORDER BY
+    first_height DESC
+LIMIT $1
+"#;
+
+const QUERY_DAEMON_DOWNTIMES: &str = r#"
+SELECT
+    last_seen_time AS start_time,
+    next_start_time AS end_time
+FROM (
+    SELECT
+        last_seen_time,
+        LEAD(start_time) OVER (ORDER BY start_time) AS next_start_time
+    FROM daemon_run
+) AS runs
+WHERE
+    (
+        next_start_time IS NULL
+        AND last_seen_time < (NOW() AT TIME ZONE 'UTC') - $1 * INTERVAL '1 second'
+    ) OR (
+        next_start_time - last_seen_time > $1 * INTERVAL '1 second'
+    )
+ORDER BY
+    start_time DESC
+LIMIT $2
+"#;
+
+pub fn coverage(conn: &mut PgConnection) -> Result<Coverage, diesel::result::Error> {
+    let observed_heights = sql_query(QUERY_OBSERVED_HEIGHTS).get_result::<ObservedHeights>(conn)?;
+
+    let reason_counts: Vec<(i32, i64)> = schema::uncompared_block::table
+        .group_by(schema::uncompared_block::reason)
+        .select((
+            schema::uncompared_block::reason,
+            count(schema::uncompared_block::hash),
+        ))
+        .order_by(schema::uncompared_block::reason.asc())
+        .load(conn)?;
+
+    let recent_uncompared_blocks: Vec<UncomparedBlock> = schema::uncompared_block::table
+        .order_by(schema::uncompared_block::height.desc())
+        .limit(MAX_COVERAGE_ENTRIES)
+        .load(conn)?;
+
+    let missing_height_ranges = sql_query(QUERY_MISSING_HEIGHT_RANGES)
+        .bind::<BigInt, _>(MAX_COVERAGE_ENTRIES)
+        .load::<MissingHeightRange>(conn)?;
+
+    let downtimes = sql_query(QUERY_DAEMON_DOWNTIMES)
+        .bind::<Integer, _>(DAEMON_DOWNTIME_THRESHOLD_SECONDS)
+        .bind::<BigInt, _>(MAX_COVERAGE_ENTRIES)
+        .load::<DaemonDowntime>(conn)?;
+
+    Ok(Coverage {
+        observed_heights,
+        reason_counts: reason_counts
+            .into_iter()
+            .map(|(reason, count)| UncomparedReasonCount {
+                reason: uncompared_reason_name(reason),
+                count,
+            })
+            .collect(),
+        recent_uncompared_blocks: recent_uncompared_blocks
+            .into_iter()
+            .map(|block| UncomparedBlockWithReason {
+                reason: uncompared_reason_name(block.reason),
+                block,
+            })
+            .collect(),
+        missing_height_ranges,
+        downtimes,
+    })
+}
+
+fn uncompared_reason_name(reason: i32) -> String {
+    match UncomparedReason::try_from(reason) {
+        Ok(reason) => reason.name().to_string(),
+        Err(_) => format!("unknown reason {}", reason),
+    }
+}
@@ -2,8 +2,8 @@ use actix_web::{web, Error, HttpResponse, Result};
 
 use crate::{db, error, model, util};
 
-use db::MAX_BLOCKS_PER_PAGE;
-use miningpool_observer_shared::{config, db_pool, tags};
+use db::{EQUALITY_CHART_MAX_POOLS, EQUALITY_CHART_WEEKS, MAX_BLOCKS_PER_PAGE};
+use miningpool_observer_shared::{config, db_pool, diesel, tags};
 use tags::THRESHOLD_TRANSACTION_CONSIDERED_YOUNG;
 
 use std::collections::HashMap;
@@ -13,6 +13,7 @@ use miningpool_observer_shared::model::*;
 
 const QUERY_PAGE: &str = "page";
 const QUERY_POOL: &str = "pool";
+const QUERY_WINDOW: &str = "window";
 
 //##### INDEX
 
@@ -83,6 +84,16 @@ pub async fn templates_and_blocks(
 
     ctx.insert("POOLS", &pools);
 
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let equality_pool = Some(mining_pool).filter(|p| !p.is_empty());
+    let equality_over_time =
+        web::block(move || db::pool_equality_over_time(&mut conn, equality_pool))
+            .await?
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("equality_over_time", &equality_over_time);
+    ctx.insert("EQUALITY_CHART_WEEKS", &EQUALITY_CHART_WEEKS);
+    ctx.insert("EQUALITY_CHART_MAX_POOLS", &EQUALITY_CHART_MAX_POOLS);
+
     let s = tmpl
         .render("templates_and_blocks.html", &ctx)
         .map_err(error::template_error)?;
@@ -367,6 +378,227 @@ Disallow: /debug/*";
         .body(robots_txt))
 }
 
+pub async fn coverage(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_COVERAGE", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let coverage = web::block(move || db::coverage(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("coverage", &coverage);
+
+    let s = tmpl
+        .render("coverage.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn acceleration(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_ACCELERATION", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("ACCELERATION_TABLE_DAYS", &db::ACCELERATION_TABLE_DAYS);
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let pool_acceleration = web::block(move || db::pool_acceleration(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("pool_acceleration", &pool_acceleration);
+
+    let s = tmpl
+        .render("acceleration.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn ordering(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_ORDERING", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("ORDERING_TABLE_DAYS", &db::ORDERING_TABLE_DAYS);
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let pool_ordering = web::block(move || db::pool_ordering(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("pool_ordering", &pool_ordering);
+
+    let s = tmpl
+        .render("ordering.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn empty_blocks(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_EMPTY_BLOCKS", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("EMPTY_BLOCKS_TABLE_DAYS", &db::EMPTY_BLOCKS_TABLE_DAYS);
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let pool_empty_blocks = web::block(move || db::pool_empty_blocks(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("pool_empty_blocks", &pool_empty_blocks);
+
+    let s = tmpl
+        .render("empty_blocks.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn signaling(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    deployments: web::Data<Vec<config::Deployment>>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_SIGNALING", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("SIGNALING_PERIODS", &db::SIGNALING_PERIODS);
+    ctx.insert(
+        "VERSION_ROLLING_TABLE_DAYS",
+        &db::VERSION_ROLLING_TABLE_DAYS,
+    );
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let (deployment_signaling, pool_version_rolling) = web::block(move || {
+        let deployment_signaling = deployments
+            .iter()
+            .map(|deployment| db::deployment_signaling(&mut conn, deployment))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pool_version_rolling = db::pool_version_rolling(&mut conn)?;
+        Ok::<_, diesel::result::Error>((deployment_signaling, pool_version_rolling))
+    })
+    .await?
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("deployment_signaling", &deployment_signaling);
+    ctx.insert("pool_version_rolling", &pool_version_rolling);
+
+    let s = tmpl
+        .render("signaling.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn timestamps(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_TIMESTAMPS", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("TIMESTAMPS_TABLE_DAYS", &db::TIMESTAMPS_TABLE_DAYS);
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let pool_timestamps = web::block(move || db::pool_timestamps(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("pool_timestamps", &pool_timestamps);
+
+    let s = tmpl
+        .render("timestamps.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn pool_profile(
+    pool_name: web::Path<String>,
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+    ctx.insert("QUERY_WINDOW", &QUERY_WINDOW);
+    ctx.insert("POOL_PROFILE_WINDOWS_DAYS", &db::POOL_PROFILE_WINDOWS_DAYS);
+
+    let mut window_days = db::POOL_PROFILE_DEFAULT_WINDOW_DAYS;
+    if let Some(query_window) = query.get(QUERY_WINDOW) {
+        window_days = util::parse_pool_profile_window(query_window)?;
+    }
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let profile = web::block(move || db::pool_profile(&mut conn, &pool_name, window_days))
+        .await?
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => actix_web::error::ErrorNotFound(e),
+            _ => actix_web::error::ErrorInternalServerError(e),
+        })?;
+    ctx.insert("profile", &profile);
+
+    let s = tmpl
+        .render("pool.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn censorship(
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    node_version: web::Data<String>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("NAV_PAGE_CENSORSHIP", &true);
+    ctx.insert("CONFIG", config.get_ref());
+    ctx.insert("NODE_VERSION", node_version.get_ref());
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let scores = web::block(move || db::censorship_scores(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    ctx.insert("scores", &scores);
+
+    let s = tmpl
+        .render("censorship.html", &ctx)
+        .map_err(error::template_error)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+pub async fn censorship_json(pool: web::Data<db_pool::PgPool>) -> Result<HttpResponse, Error> {
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let scores = web::block(move || db::censorship_scores(&mut conn))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(scores))
+}
+
 pub async fn faq(
     tmpl: web::Data<tera::Tera>,
     pool: web::Data<db_pool::PgPool>,
@@ -380,7 +612,25 @@ pub async fn faq(
         .map(|t| t.value())
         .collect();
     ctx.insert("TX_TAG_VECTOR", &tx_tags);
+    let missing_reasons: Vec<tags::Tag> = tags::MissingReason::MISSING_REASONS
+        .iter()
+        .map(|t| t.value())
+        .collect();
     ctx.insert("BLOCK_TAG_VECTOR", &block_tags);
+    let extra_tx_labels: Vec<tags::Tag> = tags::ExtraTxLabel::EXTRA_TX_LABELS
+        .iter()
+        .map(|t| t.value())
+        .collect();
+    ctx.insert("MISSING_REASON_VECTOR", &missing_reasons);
+    ctx.insert("EXTRA_TX_LABEL_VECTOR", &extra_tx_labels);
+    ctx.insert(
+        "THRESHOLD_FEERATE_FAR_BELOW_CUTOFF",
+        &tags::THRESHOLD_FEERATE_FAR_BELOW_CUTOFF,
+    );
+    ctx.insert(
+        "BLOCK_CUTOFF_FEERATE_PERCENTILE",
+        &tags::BLOCK_CUTOFF_FEERATE_PERCENTILE,
+    );
     ctx.insert("NAV_PAGE_FAQ", &true);
     ctx.insert("CONFIG", config.get_ref());
     ctx.insert("NODE_VERSION", node_version.get_ref());
@@ -559,6 +809,32 @@ pub async fn debug_template_selection_infos(
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
 
+pub async fn debug_archived_template(
+    hash_str: web::Path<String>,
+    pool: web::Data<db_pool::PgPool>,
+    debug_pages_enabled: web::Data<bool>,
+) -> Result<HttpResponse, Error> {
+    if !debug_pages_enabled.get_ref() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let hash = util::parse_block_hash_str(&hash_str)?;
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let archived_template = web::block(move || db::archived_template_for_block(&hash, &mut conn))
+        .await?
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => actix_web::error::ErrorNotFound(e),
+            _ => actix_web::error::ErrorInternalServerError(e),
+        })?;
+
+    // The template is archived as gzip compressed JSON and can be passed on
+    // as is.
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(("Content-Encoding", "gzip"))
+        .body(archived_template.template))
+}
+
 pub async fn debug_sanctioned_by_pool(
     tmpl: web::Data<tera::Tera>,
     pool: web::Data<db_pool::PgPool>,
@@ -5,6 +5,7 @@ mod error;
 mod handler;
 mod model;
 mod ogimage;
+mod stats;
 mod util;
 
 use actix_files::Files;
@@ -48,6 +49,8 @@ async fn main() -> std::io::Result<()> {
         };
         tera.register_function("block_tag_id_to_tag", util::block_tag_id_to_tag());
         tera.register_function("tx_tag_id_to_tag", util::tx_tag_id_to_tag());
+        tera.register_function("missing_reason_id_to_tag", util::missing_reason_id_to_tag());
+        tera.register_function("extra_tx_label_id_to_tag", util::extra_tx_label_id_to_tag());
         tera.register_function("seconds_to_duration", util::seconds_to_duration());
 
         let mut conn = pool.clone().get().unwrap();
@@ -75,6 +78,7 @@ async fn main() -> std::io::Result<()> {
             .app_data(Data::new(pool.clone()))
             .app_data(Data::new(cloned_config.site.clone()))
             .app_data(Data::new(cloned_config.debug_pages))
+            .app_data(Data::new(cloned_config.deployments.clone()))
             .app_data(Data::new(usvg_options))
             .app_data(Data::new(node_version))
             .wrap(middleware::Logger::default())
@@ -174,6 +178,10 @@ async fn main() -> std::io::Result<()> {
                 "/debug/template-selection",
                 web::get().to(handler::debug_template_selection_infos),
             )
+            .route(
+                "/debug/template-archive/{hash}",
+                web::get().to(handler::debug_archived_template),
+            )
             .route(
                 "/debug/sanctioned",
                 web::get().to(handler::debug_sanctioned_by_pool),
@@ -185,6 +193,19 @@ async fn main() -> std::io::Result<()> {
             //
             // OTHER PAGES
             //
+            .route("/coverage", web::get().to(handler::coverage))
+            .route("/acceleration", web::get().to(handler::acceleration))
+            .route("/ordering", web::get().to(handler::ordering))
+            .route("/empty-blocks", web::get().to(handler::empty_blocks))
+            .route("/signaling", web::get().to(handler::signaling))
+            .route("/timestamps", web::get().to(handler::timestamps))
+            .route("/censorship", web::get().to(handler::censorship))
+            .route("/censorship.json", web::get().to(handler::censorship_json))
+            .route("/pool/{name}", web::get().to(handler::pool_profile))
+            .route(
+                "/og_image/pool/{name}.png",
+                web::get().to(ogimage::ogimage_pool_profile),
+            )
             .route("/faq", web::get().to(handler::faq))
             .route(
                 "/og_image/faq.png",
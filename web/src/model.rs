@@ -1,10 +1,15 @@
 use miningpool_observer_shared::bitcoincore_rpc::json::serde_hex;
 use miningpool_observer_shared::chrono::NaiveDateTime;
 use miningpool_observer_shared::diesel;
-use miningpool_observer_shared::diesel::sql_types::{BigInt, Double, Text};
+use miningpool_observer_shared::diesel::sql_types::{
+    BigInt, Double, Integer, Nullable, Text, Timestamp,
+};
 use miningpool_observer_shared::diesel::{deserialize::Queryable, deserialize::QueryableByName};
 use miningpool_observer_shared::model;
+
+use crate::stats;
 use serde::Serialize;
+use std::convert::TryFrom;
 
 // Web-only models
 
@@ -16,8 +21,10 @@ pub struct MissingTransactionBlockInfo {
     pub height: i32,
     pub pool: String,
     pub template_position: i32,
-    pub mempool_age: i32,
+    pub mempool_age: Option<i32>,
     pub template_tx_count: i32,
+    /// Reasons why the transaction might be missing from this block.
+    pub reasons: Vec<i32>,
     pub last_block_pkg_feerate: f32,
 }
 
@@ -45,6 +52,283 @@ pub struct AvgPoolFees {
     pub q1: f64,
     #[diesel(sql_type = Double)]
     pub q3: f64,
+    #[diesel(sql_type = Double)]
+    pub avg_missing_tx_fees: f64,
+    #[diesel(sql_type = Double)]
+    pub avg_extra_tx_fees: f64,
+    #[diesel(sql_type = Double)]
+    pub avg_fee_delta_residual: f64,
+}
+
+/// The average equality of a pool's blocks in a week.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolEqualityOverTime {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = Timestamp)]
+    pub week: NaiveDateTime,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = Double)]
+    pub equality: f64,
+    #[diesel(sql_type = Double)]
+    pub fee_weighted_equality: f64,
+    #[diesel(sql_type = Double)]
+    pub weight_weighted_equality: f64,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolAcceleration {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub blocks_with_accelerated_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub accelerated_tx: i64,
+    /// Estimated fees in sat the pool gave up.
+    #[diesel(sql_type = BigInt)]
+    pub accelerated_fee_forgone: i64,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolOrdering {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub blocks_with_out_of_order_pkgs: i64,
+    /// Average share of the adjacent block packages in feerate order.
+    #[diesel(sql_type = Double)]
+    pub ordering_conformance: f64,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolEmptyBlocks {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub empty_blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub underfull_blocks: i64,
+    /// Median seconds between the header timestamps of the empty and
+    /// under-full blocks and their parents.
+    #[diesel(sql_type = Nullable<Double>)]
+    pub median_prev_block_interval: Option<f64>,
+    /// Median seconds between seeing the parent and seeing the empty and
+    /// under-full blocks. Only known if we saw the parent too.
+    #[diesel(sql_type = Nullable<Double>)]
+    pub median_prev_block_seen_interval: Option<f64>,
+}
+
+/// The blocks of a pool in a retarget period signaling for a deployment.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolSignaling {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = Integer)]
+    pub period: i32,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub signaling_blocks: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignalingPeriod {
+    pub period: i32,
+    pub first_height: i32,
+    pub blocks: i64,
+    pub signaling_blocks: i64,
+    pub pools: Vec<PoolSignaling>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeploymentSignaling {
+    pub name: String,
+    pub bit: u8,
+    /// The recent retarget periods, newest first.
+    pub periods: Vec<SignalingPeriod>,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolVersionRolling {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub version_rolling_blocks: i64,
+    /// The BIP320 bits set in any of the pool's block versions as hex.
+    #[diesel(sql_type = Text)]
+    pub version_rolling_bits: String,
+}
+
+/// The nTime offsets of a pool's blocks. The offset is the header timestamp
+/// minus the time we saw the block in seconds.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolTimestamps {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = Double)]
+    pub avg_offset: f64,
+    #[diesel(sql_type = Double)]
+    pub median_offset: f64,
+    #[diesel(sql_type = Double)]
+    pub min_offset: f64,
+    #[diesel(sql_type = Double)]
+    pub max_offset: f64,
+    #[diesel(sql_type = BigInt)]
+    pub timestamp_ahead_blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub seen_late_blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub timestamp_close_to_mtp_blocks: i64,
+}
+
+/// Aggregates over the blocks of a pool in a time window. The medians and
+/// the fee capture ratio are None if the pool didn't find a block in the
+/// window.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct PoolProfileStats {
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    /// Blocks of all pools in the window.
+    #[diesel(sql_type = BigInt)]
+    pub total_blocks: i64,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub median_missing_tx: Option<f64>,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub median_extra_tx: Option<f64>,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub median_shared_tx: Option<f64>,
+    /// Block fees divided by the template fees of all blocks of the pool.
+    #[diesel(sql_type = Nullable<Double>)]
+    pub fee_capture_ratio: Option<f64>,
+    #[diesel(sql_type = BigInt)]
+    pub blocks_with_sanctioned: i64,
+    #[diesel(sql_type = BigInt)]
+    pub block_sanctioned_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub template_sanctioned_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub sanctioned_missing_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub empty_blocks: i64,
+}
+
+#[derive(Serialize)]
+pub struct PoolProfile {
+    pub pool_name: String,
+    pub window_days: u32,
+    pub stats: PoolProfileStats,
+    pub recent_blocks: Vec<model::Block>,
+}
+
+/// Counts of the sanctioned template transactions of a pool and of their
+/// feerate-matched controls.
+#[derive(Debug, QueryableByName)]
+pub struct PoolCensorshipCounts {
+    #[diesel(sql_type = Text)]
+    pub pool_name: String,
+    #[diesel(sql_type = BigInt)]
+    pub blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub sanctioned_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub sanctioned_missing_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub control_tx: i64,
+    #[diesel(sql_type = BigInt)]
+    pub control_missing_tx: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfidenceInterval {
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl From<(f64, f64)> for ConfidenceInterval {
+    fn from((lower, upper): (f64, f64)) -> Self {
+        ConfidenceInterval { lower, upper }
+    }
+}
+
+/// How much more often a pool leaves out sanctioned transactions than
+/// non-sanctioned transactions with a similar feerate.
+#[derive(Debug, Serialize)]
+pub struct PoolCensorshipScore {
+    pub pool_name: String,
+    /// Blocks with at least one sanctioned transaction in the template.
+    pub blocks: i64,
+    pub sanctioned_tx: i64,
+    pub sanctioned_missing_tx: i64,
+    pub sanctioned_miss_rate: f64,
+    pub sanctioned_miss_rate_ci: ConfidenceInterval,
+    pub control_tx: i64,
+    pub control_missing_tx: i64,
+    pub control_miss_rate: f64,
+    pub control_miss_rate_ci: ConfidenceInterval,
+    /// The sanctioned miss rate minus the control miss rate.
+    pub score: f64,
+    pub score_ci: ConfidenceInterval,
+    /// True if the whole confidence interval of the score is above zero.
+    pub significant: bool,
+}
+
+impl From<PoolCensorshipCounts> for PoolCensorshipScore {
+    fn from(counts: PoolCensorshipCounts) -> Self {
+        let sanctioned_miss_rate = stats::rate(counts.sanctioned_missing_tx, counts.sanctioned_tx);
+        let sanctioned_ci = stats::wilson_interval(
+            counts.sanctioned_missing_tx,
+            counts.sanctioned_tx,
+            stats::Z_95,
+        );
+        let control_miss_rate = stats::rate(counts.control_missing_tx, counts.control_tx);
+        let control_ci =
+            stats::wilson_interval(counts.control_missing_tx, counts.control_tx, stats::Z_95);
+        let score_ci = stats::newcombe_interval(
+            sanctioned_miss_rate,
+            sanctioned_ci,
+            control_miss_rate,
+            control_ci,
+        );
+        PoolCensorshipScore {
+            pool_name: counts.pool_name,
+            blocks: counts.blocks,
+            sanctioned_tx: counts.sanctioned_tx,
+            sanctioned_missing_tx: counts.sanctioned_missing_tx,
+            sanctioned_miss_rate,
+            sanctioned_miss_rate_ci: sanctioned_ci.into(),
+            control_tx: counts.control_tx,
+            control_missing_tx: counts.control_missing_tx,
+            control_miss_rate,
+            control_miss_rate_ci: control_ci.into(),
+            score: sanctioned_miss_rate - control_miss_rate,
+            significant: score_ci.0 > 0.0,
+            score_ci: score_ci.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CensorshipScores {
+    /// A description of how the scores are calculated.
+    pub method: String,
+    pub confidence_level: f64,
+    pub window_days: i32,
+    pub control_feerate_tolerance: f32,
+    /// The seconds after which the mempool age buckets of the controls start.
+    /// The first bucket starts at zero.
+    pub control_age_buckets: Vec<u64>,
+    pub pools: Vec<PoolCensorshipScore>,
 }
 
 #[derive(Serialize)]
@@ -97,4 +381,123 @@ pub struct BlockWithTx {
     pub block: model::Block,
     pub txns_only_in_template: Vec<(model::TransactionOnlyInTemplate, model::Transaction)>,
     pub txns_only_in_block: Vec<(model::TransactionOnlyInBlock, model::Transaction)>,
+    /// None for blocks processed before the coinbase was parsed.
+    pub coinbase: Option<Coinbase>,
+}
+
+#[derive(Serialize)]
+pub struct MergedMiningCommitment {
+    pub chain: String,
+    #[serde(with = "serde_hex")]
+    pub commitment: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct CoinbasePayout {
+    #[serde(with = "serde_hex")]
+    pub script: Vec<u8>,
+    pub address: String,
+    pub value: i64,
+}
+
+#[derive(Serialize)]
+pub struct Coinbase {
+    #[serde(with = "serde_hex")]
+    pub script_sig: Vec<u8>,
+    pub script_sig_tag: String,
+    pub bip34_height: Option<i32>,
+    pub extranonce_len: Option<i32>,
+    #[serde(with = "serde_hex::opt")]
+    pub witness_commitment: Option<Vec<u8>>,
+    pub merged_mining_commitments: Vec<MergedMiningCommitment>,
+    pub payout_outputs: i32,
+    pub payouts: Vec<CoinbasePayout>,
+}
+
+impl From<model::CoinbaseInfo> for Coinbase {
+    fn from(info: model::CoinbaseInfo) -> Self {
+        Coinbase {
+            script_sig: info.script_sig,
+            script_sig_tag: info.script_sig_tag,
+            bip34_height: info.bip34_height,
+            extranonce_len: info.extranonce_len,
+            witness_commitment: info.witness_commitment,
+            merged_mining_commitments: info
+                .merged_mining_chains
+                .iter()
+                .zip(info.merged_mining_commitments)
+                .map(|(chain, commitment)| MergedMiningCommitment {
+                    chain: model::MergedMiningChain::try_from(*chain)
+                        .map(|c| c.name().to_string())
+                        .unwrap_or_else(|_| "unknown".to_string()),
+                    commitment,
+                })
+                .collect(),
+            payout_outputs: info.payout_outputs,
+            payouts: info
+                .payout_scripts
+                .into_iter()
+                .zip(info.payout_addresses)
+                .zip(info.payout_values)
+                .map(|((script, address), value)| CoinbasePayout {
+                    script,
+                    address,
+                    value,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct ObservedHeights {
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub first_height: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub last_height: Option<i32>,
+    #[diesel(sql_type = BigInt)]
+    pub compared_blocks: i64,
+    #[diesel(sql_type = BigInt)]
+    pub uncompared_blocks: i64,
+}
+
+/// Heights between first_height and last_height (inclusive) for which no
+/// block was observed.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct MissingHeightRange {
+    #[diesel(sql_type = Integer)]
+    pub first_height: i32,
+    #[diesel(sql_type = Integer)]
+    pub last_height: i32,
+}
+
+/// A time the daemon wasn't running. The end_time is None if the daemon is
+/// currently not running.
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct DaemonDowntime {
+    #[diesel(sql_type = Timestamp)]
+    pub start_time: NaiveDateTime,
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    pub end_time: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UncomparedReasonCount {
+    pub reason: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UncomparedBlockWithReason {
+    pub block: model::UncomparedBlock,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Coverage {
+    pub observed_heights: ObservedHeights,
+    pub reason_counts: Vec<UncomparedReasonCount>,
+    pub recent_uncompared_blocks: Vec<UncomparedBlockWithReason>,
+    pub missing_height_ranges: Vec<MissingHeightRange>,
+    pub downtimes: Vec<DaemonDowntime>,
 }
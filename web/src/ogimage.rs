@@ -1,5 +1,5 @@
 use actix_web::{error as actix_error, web, Error, HttpResponse, Result};
-use miningpool_observer_shared::{config, db_pool, tags};
+use miningpool_observer_shared::{config, db_pool, diesel, tags};
 use serde::Serialize;
 use tiny_skia::Pixmap;
 
@@ -135,6 +135,72 @@ pub async fn ogimage_template_and_block(
     }
 }
 
+pub async fn ogimage_pool_profile(
+    pool_name: web::Path<String>,
+    tmpl: web::Data<tera::Tera>,
+    pool: web::Data<db_pool::PgPool>,
+    config: web::Data<config::WebSiteConfig>,
+    usvg_opts: web::Data<usvg::Options>,
+) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("config", config.get_ref());
+
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let profile = web::block(move || {
+        db::pool_profile(&mut conn, &pool_name, db::POOL_PROFILE_DEFAULT_WINDOW_DAYS)
+    })
+    .await?
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => actix_error::ErrorNotFound(e),
+        _ => actix_error::ErrorInternalServerError(e),
+    })?;
+
+    #[derive(Serialize)]
+    struct Data {
+        pool: String,
+        window_days: u32,
+        blocks: i64,
+        share: f64,
+        median_missing: f64,
+        median_extra: f64,
+        median_shared: f64,
+        empty_blocks: i64,
+    }
+
+    let stats = profile.stats;
+    ctx.insert(
+        "data",
+        &Data {
+            pool: profile.pool_name,
+            window_days: profile.window_days,
+            blocks: stats.blocks,
+            share: if stats.total_blocks > 0 {
+                (stats.blocks as f64 / stats.total_blocks as f64 * 1000.0).round() / 10.0
+            } else {
+                0.0
+            },
+            median_missing: stats.median_missing_tx.unwrap_or_default(),
+            median_extra: stats.median_extra_tx.unwrap_or_default(),
+            median_shared: stats.median_shared_tx.unwrap_or_default(),
+            empty_blocks: stats.empty_blocks,
+        },
+    );
+
+    let s = tmpl
+        .render("svg/subpage_pool.svg", &ctx)
+        .map_err(error::template_error)?;
+
+    match render_and_encode(&s, usvg_opts.get_ref()) {
+        Ok(png_data) => Ok(HttpResponse::Ok().content_type("image/png").body(png_data)),
+        Err(e) => {
+            log::error!("Could not render og::image subpage_pool: {}", e);
+            Err(actix_error::ErrorInternalServerError(
+                "Render or Encoding Error",
+            ))
+        }
+    }
+}
+
 pub async fn ogimage_block_with_conflicting_transactions(
     hash_str: web::Path<String>,
     tmpl: web::Data<tera::Tera>,
@@ -0,0 +1,69 @@
+/// z-score of a two-sided 95% confidence interval.
+pub const Z_95: f64 = 1.959964;
+
+/// Returns the Wilson score interval of the proportion `successes / n`.
+/// Without observations, the interval spans everything from 0 to 1.
+pub fn wilson_interval(successes: i64, n: i64, z: f64) -> (f64, f64) {
+    if n <= 0 {
+        return (0.0, 1.0);
+    }
+    let n = n as f64;
+    let p = successes as f64 / n;
+    let z2 = z * z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    (
+        (center - half_width).max(0.0),
+        (center + half_width).min(1.0),
+    )
+}
+
+/// Returns Newcombe's hybrid score interval of the difference `p1 - p2` from
+/// the Wilson score intervals of both proportions.
+pub fn newcombe_interval(p1: f64, ci1: (f64, f64), p2: f64, ci2: (f64, f64)) -> (f64, f64) {
+    let d = p1 - p2;
+    let lower = d - ((p1 - ci1.0).powi(2) + (ci2.1 - p2).powi(2)).sqrt();
+    let upper = d + ((ci1.1 - p1).powi(2) + (p2 - ci2.0).powi(2)).sqrt();
+    (lower, upper)
+}
+
+/// Returns `successes / n` or 0 without observations.
+pub fn rate(successes: i64, n: i64) -> f64 {
+    if n <= 0 {
+        return 0.0;
+    }
+    successes as f64 / n as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: (f64, f64), b: (f64, f64)) {
+        assert!(
+            (a.0 - b.0).abs() < 0.0001 && (a.1 - b.1).abs() < 0.0001,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_wilson_interval() {
+        assert_close(wilson_interval(0, 0, Z_95), (0.0, 1.0));
+        assert_close(wilson_interval(0, 10, Z_95), (0.0, 0.2775));
+        assert_close(wilson_interval(10, 10, Z_95), (0.7225, 1.0));
+        assert_close(wilson_interval(81, 263, Z_95), (0.2553, 0.3662));
+    }
+
+    #[test]
+    fn test_newcombe_interval() {
+        // Newcombe (1998), example (a): 56/70 - 48/80
+        let ci1 = wilson_interval(56, 70, Z_95);
+        let ci2 = wilson_interval(48, 80, Z_95);
+        assert_close(
+            newcombe_interval(rate(56, 70), ci1, rate(48, 80), ci2),
+            (0.0524, 0.3339),
+        );
+    }
+}
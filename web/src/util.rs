@@ -5,9 +5,12 @@ use actix_web::{error, Error};
 
 use miningpool_observer_shared::tags;
 
+use crate::db::POOL_PROFILE_WINDOWS_DAYS;
+
 const ERROR_INVALID_INT: &str = "INVALID INT";
 const ERROR_INVALID_BLOCK_HASH: &str = "INVALID BLOCK HASH";
 const ERROR_INVALID_TXID: &str = "INVALID TXID";
+const ERROR_INVALID_WINDOW: &str = "INVALID WINDOW";
 
 pub fn parse_uint(uint_str: &str) -> Result<u32, Error> {
     match uint_str.parse::<u32>() {
@@ -19,6 +22,17 @@ pub fn parse_uint(uint_str: &str) -> Result<u32, Error> {
     }
 }
 
+/// Parses the window of a pool profile in days. Only the windows in
+/// POOL_PROFILE_WINDOWS_DAYS are valid.
+pub fn parse_pool_profile_window(window_str: &str) -> Result<u32, Error> {
+    let window = parse_uint(window_str)?;
+    if !POOL_PROFILE_WINDOWS_DAYS.contains(&window) {
+        log::warn!(target: "web_handler", "parse_pool_profile_window: invalid window {}", window);
+        return Err(error::ErrorNotFound(ERROR_INVALID_WINDOW));
+    }
+    Ok(window)
+}
+
 pub fn parse_block_hash_str(hash_str: &str) -> Result<Vec<u8>, Error> {
     if hash_str.len() != 64 || !hash_str.starts_with("000000") {
         log::warn!(target: "web_handler", "parse_block_hash_str: invalid block height {}", hash_str);
@@ -95,13 +109,61 @@ pub fn block_tag_id_to_tag() -> impl tera::Function {
     )
 }
 
-/// Converts seconds to a duration String.
+pub fn missing_reason_id_to_tag() -> impl tera::Function {
+    Box::new(
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            match args.get("id") {
+                Some(val) => match tera::from_value::<i32>(val.clone()) {
+                    Ok(v) => {
+                        match tags::MissingReason::try_from(v) {
+                            Ok(v) => {
+                                Ok(tera::to_value(v.value()).unwrap())
+                            },
+                            Err(e) => {
+                                Err(format!("Could not find MissingReason for value {:?}. Is the mapping implemented?: {:?}", v, e).into())
+                            }
+                        }
+                    }
+                    Err(_) => Err(format!("Can't parse 'id' with val={} as i32.", val).into()),
+                },
+                None => Err("No parameter 'id' passed to missing_reason_id_to_tag()".into()),
+            }
+        },
+    )
+}
+
+pub fn extra_tx_label_id_to_tag() -> impl tera::Function {
+    Box::new(
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            match args.get("id") {
+                Some(val) => match tera::from_value::<i32>(val.clone()) {
+                    Ok(v) => {
+                        match tags::ExtraTxLabel::try_from(v) {
+                            Ok(v) => {
+                                Ok(tera::to_value(v.value()).unwrap())
+                            },
+                            Err(e) => {
+                                Err(format!("Could not find ExtraTxLabel for value {:?}. Is the mapping implemented?: {:?}", v, e).into())
+                            }
+                        }
+                    }
+                    Err(_) => Err(format!("Can't parse 'id' with val={} as i32.", val).into()),
+                },
+                None => Err("No parameter 'id' passed to extra_tx_label_id_to_tag()".into()),
+            }
+        },
+    )
+}
+
+/// Converts seconds to a duration String. Unknown (null or negative)
+/// durations are shown as "Unknown".
 pub fn seconds_to_duration() -> impl tera::Function {
     Box::new(
         move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
             match args.get("seconds") {
-                Some(val) => match tera::from_value::<i32>(val.clone()) {
-                    Ok(v) => Ok(if v < 0 {
+                Some(val) => match tera::from_value::<Option<i32>>(val.clone()) {
+                    Ok(None) => Ok(tera::to_value("Unknown").unwrap()),
+                    Ok(Some(v)) => Ok(if v < 0 {
                         tera::to_value("Unknown").unwrap()
                     } else {
                         let d = std::time::Duration::from_secs(v as u64);
@@ -135,6 +197,20 @@ pub fn seconds_to_duration() -> impl tera::Function {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_seconds_to_duration() {
+        use tera::Function;
+        let f = seconds_to_duration();
+        let call = |v: tera::Value| {
+            f.call(&HashMap::from([("seconds".to_string(), v)]))
+                .unwrap()
+        };
+        assert_eq!(call(tera::Value::Null), "Unknown");
+        assert_eq!(call(tera::to_value(-1).unwrap()), "Unknown");
+        assert_eq!(call(tera::to_value(59).unwrap()), "59s");
+        assert_eq!(call(tera::to_value(3661).unwrap()), "1h 1m 1s");
+    }
+
     #[test]
     fn test_parse_uint() {
         assert_eq!(parse_uint("0").unwrap(), 0);
@@ -153,6 +229,21 @@ mod tests {
         assert!(parse_uint("-32").is_err());
     }
 
+    #[test]
+    fn test_parse_pool_profile_window() {
+        for window in POOL_PROFILE_WINDOWS_DAYS {
+            assert_eq!(
+                parse_pool_profile_window(&window.to_string()).unwrap(),
+                window
+            );
+        }
+
+        assert!(parse_pool_profile_window("").is_err());
+        assert!(parse_pool_profile_window("0").is_err());
+        assert!(parse_pool_profile_window("31").is_err());
+        assert!(parse_pool_profile_window("-7").is_err());
+    }
+
     #[test]
     fn test_parse_block_hash_str() {
         assert_eq!(